@@ -122,6 +122,8 @@ fn key_list(
         for (alias, (stored_keypair, pkh)) in known_keys {
             let encrypted = if stored_keypair.is_encrypted() {
                 "encrypted"
+            } else if stored_keypair.is_watch_only() {
+                "watch-only"
             } else {
                 "not encrypted"
             };
@@ -129,6 +131,10 @@ fn key_list(
             if let Some(pkh) = pkh {
                 writeln!(w, "    Public key hash: {}", pkh).unwrap();
             }
+            if let Some(instance_id) = stored_keypair.instance_id() {
+                writeln!(w, "    Instance ID: {}", hex::encode(instance_id))
+                    .unwrap();
+            }
             match stored_keypair.get(decrypt, None) {
                 Ok(keypair) => {
                     writeln!(w, "    Public key: {}", keypair.ref_to())
@@ -140,6 +146,11 @@ fn key_list(
                 Err(DecryptionError::NotDecrypting) if !decrypt => {
                     continue;
                 }
+                Err(DecryptionError::WatchOnly) => {
+                    if let Some(pk) = stored_keypair.public_key() {
+                        writeln!(w, "    Public key: {}", pk).unwrap();
+                    }
+                }
                 Err(err) => {
                     writeln!(w, "    Couldn't decrypt the keypair: {}", err)
                         .unwrap();
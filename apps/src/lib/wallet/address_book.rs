@@ -0,0 +1,86 @@
+//! A contacts store of labelled public keys.
+
+use std::collections::BTreeMap;
+
+use anoma::types::key::*;
+use borsh::{BorshDeserialize, BorshSerialize};
+
+/// Public keys of contacts by their label. It only holds public data. Its
+/// Borsh encoding is the number of entries followed by the entries sorted by
+/// label, each a label string and a public key, so equal address books always
+/// have the same encoding.
+#[derive(
+    Debug, Clone, Default, PartialEq, BorshSerialize, BorshDeserialize,
+)]
+pub struct AddressBook {
+    entries: BTreeMap<String, common::PublicKey>,
+}
+
+impl AddressBook {
+    /// Create an empty address book
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a public key with the given label. Returns the public key that was
+    /// previously labelled so, if any.
+    pub fn add(
+        &mut self,
+        label: impl Into<String>,
+        pk: common::PublicKey,
+    ) -> Option<common::PublicKey> {
+        self.entries.insert(label.into(), pk)
+    }
+
+    /// Get the public key with the given label
+    pub fn resolve(&self, label: &str) -> Option<common::PublicKey> {
+        self.entries.get(label).cloned()
+    }
+
+    /// Get the label of the given public key. If the key has several labels,
+    /// the first one in lexicographic order is returned.
+    pub fn reverse_lookup(&self, pk: &common::PublicKey) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(_, entry)| *entry == pk)
+            .map(|(label, _)| label.as_str())
+    }
+
+    /// The number of entries
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns `true` if the address book has no entries
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anoma::types::key::testing::{keypair_1, keypair_2};
+
+    use super::*;
+
+    #[test]
+    fn test_address_book_roundtrip() {
+        let mut book = AddressBook::new();
+        assert!(book.add("bob", keypair_2().ref_to()).is_none());
+        assert!(book.add("alice", keypair_1().ref_to()).is_none());
+        assert_eq!(book.resolve("alice"), Some(keypair_1().ref_to()));
+        assert_eq!(book.resolve("carol"), None);
+        assert_eq!(book.reverse_lookup(&keypair_2().ref_to()), Some("bob"));
+
+        let bytes = book.try_to_vec().unwrap();
+        let decoded = AddressBook::try_from_slice(&bytes).unwrap();
+        assert_eq!(decoded, book);
+        assert_eq!(decoded.try_to_vec().unwrap(), bytes);
+
+        // The encoding doesn't depend on the insertion order
+        let mut reordered = AddressBook::new();
+        reordered.add("alice", keypair_1().ref_to());
+        reordered.add("bob", keypair_2().ref_to());
+        assert_eq!(reordered.try_to_vec().unwrap(), bytes);
+    }
+}
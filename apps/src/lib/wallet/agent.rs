@@ -0,0 +1,490 @@
+//! A local signing agent, like `ssh-agent`, that holds unlocked keypairs and
+//! signs messages on request over a Unix domain socket, so that the processes
+//! using the keys never have access to their secret keys.
+//!
+//! Every request and response is a frame of its Borsh encoding prefixed with
+//! its length in bytes as a little-endian `u32`. A connection may carry any
+//! number of requests, each answered by a single response in order.
+//!
+//! The socket is only accessible to the user running the agent: its parent
+//! directory is created with permissions `0o700` if it doesn't exist, and an
+//! existing one must be owned by the user and not writable by others. The
+//! socket is created with permissions `0o600` in a fresh private directory
+//! and only then moved into place, so that it's never accessible to others.
+//! The agent also checks that every client runs as the same user before
+//! answering its requests.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{
+    DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt,
+};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+
+use anoma::types::key::*;
+use borsh::{BorshDeserialize, BorshSerialize};
+use thiserror::Error;
+
+use super::{FindKeyError, Wallet};
+
+/// The maximum length of a frame, to not allocate an arbitrary amount of
+/// memory for a malicious length prefix
+const MAX_FRAME_LEN: u32 = 1 << 20;
+/// The permissions of the socket, only accessible to its owner
+const SOCKET_MODE: u32 = 0o600;
+/// The permissions of the socket's directory, if the agent creates it
+const SOCKET_DIR_MODE: u32 = 0o700;
+
+/// A request to a [`SigningAgent`]
+#[derive(Debug, Clone, PartialEq, BorshSerialize, BorshDeserialize)]
+pub enum AgentRequest {
+    /// Sign the message with the keypair of the given ID
+    Sign {
+        /// The ID of the keypair, i.e. its alias in the wallet
+        key_id: String,
+        /// The message to sign
+        msg: Vec<u8>,
+    },
+    /// List the IDs and public keys of the keypairs held by the agent
+    ListPublicKeys,
+}
+
+/// A response of a [`SigningAgent`]
+#[derive(Debug, Clone, PartialEq, BorshSerialize, BorshDeserialize)]
+pub enum AgentResponse {
+    /// The signature of the message of a [`AgentRequest::Sign`]
+    Signature(common::Signature),
+    /// The IDs and public keys of the keypairs held by the agent, ordered by
+    /// ID
+    PublicKeys(Vec<(String, common::PublicKey)>),
+    /// The agent doesn't hold a keypair with the requested ID
+    KeyNotFound(String),
+}
+
+#[allow(missing_docs)]
+#[derive(Error, Debug)]
+pub enum AgentError {
+    #[error("Signing agent connection error: {0}")]
+    Io(io::Error),
+    #[error("Invalid signing agent frame: {0}")]
+    InvalidFrame(String),
+    #[error("The signing agent doesn't hold the key {0}")]
+    KeyNotFound(String),
+    #[error("Unexpected signing agent response {0:?}")]
+    UnexpectedResponse(AgentResponse),
+}
+
+/// Holds unlocked keypairs and signs with them on request
+#[derive(Debug, Default)]
+pub struct SigningAgent {
+    keys: BTreeMap<String, common::SecretKey>,
+}
+
+impl SigningAgent {
+    /// An agent without any keypair
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an unlocked keypair with the given ID, replacing the keypair that
+    /// had this ID, if any
+    pub fn add_key(&mut self, key_id: String, keypair: common::SecretKey) {
+        self.keys.insert(key_id, keypair);
+    }
+
+    /// Unlock the wallet's keypair with the given ID as its alias with the
+    /// password, if it's encrypted, as by [`Wallet::unlock_key`], and add it
+    /// with this ID
+    pub fn unlock(
+        &mut self,
+        wallet: &mut Wallet,
+        key_id: String,
+        password: Option<String>,
+    ) -> Result<(), FindKeyError> {
+        let keypair = wallet.unlock_key(&key_id, password)?;
+        self.add_key(key_id, (*keypair).clone());
+        Ok(())
+    }
+
+    /// Remove the keypair with the given ID, returning whether the agent held
+    /// it
+    pub fn remove_key(&mut self, key_id: &str) -> bool {
+        self.keys.remove(key_id).is_some()
+    }
+
+    /// Answer a single request
+    pub fn handle(&self, request: &AgentRequest) -> AgentResponse {
+        match request {
+            AgentRequest::Sign { key_id, msg } => match self.keys.get(key_id) {
+                Some(keypair) => AgentResponse::Signature(
+                    common::SigScheme::sign(keypair, msg),
+                ),
+                None => AgentResponse::KeyNotFound(key_id.clone()),
+            },
+            AgentRequest::ListPublicKeys => AgentResponse::PublicKeys(
+                self.keys
+                    .iter()
+                    .map(|(key_id, keypair)| (key_id.clone(), keypair.ref_to()))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Listen on a socket at the given path, only accessible to the current
+    /// user. A stale socket left at the path is replaced, but any other kind
+    /// of file is left there and fails the binding, and so does a parent
+    /// directory owned by another user or writable by others.
+    pub fn bind(path: &Path) -> io::Result<UnixListener> {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        if !dir.exists() {
+            fs::DirBuilder::new()
+                .recursive(true)
+                .mode(SOCKET_DIR_MODE)
+                .create(dir)?;
+        }
+        check_socket_dir(dir)?;
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => {
+                fs::remove_file(path)?
+            }
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!(
+                        "{} exists and is not a socket, refusing to replace it",
+                        path.display()
+                    ),
+                ));
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+
+        // Bind in a fresh directory only accessible to the current user, to
+        // restrict the permissions of the socket before anyone can connect
+        let private_dir = dir.join(format!(".agent-{:016x}", {
+            use rand::RngCore;
+            rand::rngs::OsRng.next_u64()
+        }));
+        fs::DirBuilder::new()
+            .mode(SOCKET_DIR_MODE)
+            .create(&private_dir)?;
+        let private_path = private_dir.join("agent.sock");
+        let result = UnixListener::bind(&private_path).and_then(|listener| {
+            fs::set_permissions(
+                &private_path,
+                fs::Permissions::from_mode(SOCKET_MODE),
+            )?;
+            fs::rename(&private_path, path)?;
+            Ok(listener)
+        });
+        let _ = fs::remove_file(&private_path);
+        fs::remove_dir(&private_dir)?;
+        result
+    }
+
+    /// Serve the connections of the listener one after the other, until
+    /// accepting a connection fails. An error on a connection only closes
+    /// that connection.
+    pub fn serve(&self, listener: &UnixListener) -> io::Result<()> {
+        loop {
+            let (stream, _) = listener.accept()?;
+            if let Err(err) = self.serve_connection(stream) {
+                tracing::warn!("Signing agent connection error: {}", err);
+            }
+        }
+    }
+
+    /// Answer the requests of a connection until it's closed by the client.
+    /// The connection is refused if the client doesn't run as the current
+    /// user.
+    pub fn serve_connection(&self, mut stream: UnixStream) -> io::Result<()> {
+        let uid = peer_uid(&stream)?;
+        if uid != current_uid() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("Refusing a connection from the user {}", uid),
+            ));
+        }
+        loop {
+            let request: AgentRequest = match read_frame(&mut stream) {
+                Ok(request) => request,
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Ok(());
+                }
+                Err(err) => return Err(err),
+            };
+            write_frame(&mut stream, &self.handle(&request))?;
+        }
+    }
+}
+
+/// A connection to a [`SigningAgent`]
+#[derive(Debug)]
+pub struct AgentClient {
+    stream: UnixStream,
+}
+
+impl AgentClient {
+    /// Connect to the agent listening on the socket at the given path
+    pub fn connect(path: &Path) -> Result<Self, AgentError> {
+        let stream = UnixStream::connect(path).map_err(AgentError::Io)?;
+        Ok(Self { stream })
+    }
+
+    /// Sign the message with the keypair of the given ID held by the agent
+    pub fn sign(
+        &mut self,
+        key_id: &str,
+        msg: &[u8],
+    ) -> Result<common::Signature, AgentError> {
+        let request = AgentRequest::Sign {
+            key_id: key_id.to_owned(),
+            msg: msg.to_vec(),
+        };
+        match self.request(&request)? {
+            AgentResponse::Signature(signature) => Ok(signature),
+            AgentResponse::KeyNotFound(key_id) => {
+                Err(AgentError::KeyNotFound(key_id))
+            }
+            response => Err(AgentError::UnexpectedResponse(response)),
+        }
+    }
+
+    /// The IDs and public keys of the keypairs held by the agent
+    pub fn list_public_keys(
+        &mut self,
+    ) -> Result<Vec<(String, common::PublicKey)>, AgentError> {
+        match self.request(&AgentRequest::ListPublicKeys)? {
+            AgentResponse::PublicKeys(keys) => Ok(keys),
+            response => Err(AgentError::UnexpectedResponse(response)),
+        }
+    }
+
+    fn request(
+        &mut self,
+        request: &AgentRequest,
+    ) -> Result<AgentResponse, AgentError> {
+        write_frame(&mut self.stream, request).map_err(AgentError::Io)?;
+        read_frame(&mut self.stream).map_err(|err| {
+            if err.kind() == io::ErrorKind::InvalidData {
+                AgentError::InvalidFrame(err.to_string())
+            } else {
+                AgentError::Io(err)
+            }
+        })
+    }
+}
+
+/// Write the value as a frame of its length-prefixed Borsh encoding
+fn write_frame(
+    writer: &mut impl Write,
+    value: &impl BorshSerialize,
+) -> io::Result<()> {
+    let bytes = value.try_to_vec()?;
+    let len = u32::try_from(bytes.len())
+        .ok()
+        .filter(|len| *len <= MAX_FRAME_LEN)
+        .ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "The frame is too long")
+        })?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(&bytes)?;
+    writer.flush()
+}
+
+/// Check that the directory of the socket is owned by the current user and
+/// not writable by anyone else, who could otherwise replace the socket
+fn check_socket_dir(dir: &Path) -> io::Result<()> {
+    let metadata = fs::metadata(dir)?;
+    if metadata.uid() != current_uid() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "The socket directory {} is owned by another user",
+                dir.display()
+            ),
+        ));
+    }
+    if metadata.mode() & 0o022 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "The socket directory {} is writable by other users",
+                dir.display()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// The effective user ID of this process
+fn current_uid() -> u32 {
+    // SAFETY: `geteuid` has no preconditions and always succeeds
+    unsafe { libc::geteuid() }
+}
+
+/// The effective user ID of the process at the other end of the connection
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: the buffer is a `ucred` of the given length, which is what
+    // `SO_PEERCRED` writes
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred.uid)
+}
+
+/// The effective user ID of the process at the other end of the connection
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let mut uid = 0;
+    let mut gid = 0;
+    // SAFETY: both pointers are to valid IDs, which `getpeereid` writes
+    let ret =
+        unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(uid)
+}
+
+/// Read a frame written by [`write_frame`] and decode its value
+fn read_frame<T: BorshDeserialize>(reader: &mut impl Read) -> io::Result<T> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len);
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("The frame length {} is too long", len),
+        ));
+    }
+    let mut bytes = vec![0; len as usize];
+    reader.read_exact(&mut bytes)?;
+    T::try_from_slice(&bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use anoma::types::key::testing::{keypair_1, keypair_2};
+
+    use super::*;
+    use crate::wallet::StoredKeypair;
+
+    #[test]
+    fn test_agent_signs_over_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("agent").join("agent.sock");
+        let mut agent = SigningAgent::new();
+        agent.add_key("alice".to_owned(), keypair_1());
+        let mut wallet = Wallet::load_or_new(dir.path());
+        let (stored, _) =
+            StoredKeypair::new(keypair_2(), Some("password".to_owned()));
+        let pkh = PublicKeyHash::from(&keypair_2().ref_to());
+        wallet.insert_keypair("bob".to_owned(), stored, pkh);
+        agent
+            .unlock(&mut wallet, "bob".to_owned(), Some("password".to_owned()))
+            .unwrap();
+
+        let listener = SigningAgent::bind(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, SOCKET_MODE);
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            agent.serve_connection(stream).unwrap();
+        });
+
+        let mut client = AgentClient::connect(&path).unwrap();
+        assert_eq!(
+            client.list_public_keys().unwrap(),
+            vec![
+                ("alice".to_owned(), keypair_1().ref_to()),
+                ("bob".to_owned(), keypair_2().ref_to()),
+            ]
+        );
+        let signature = client.sign("bob", b"message").unwrap();
+        common::SigScheme::verify_signature_raw(
+            &keypair_2().ref_to(),
+            b"message",
+            &signature,
+        )
+        .unwrap();
+        assert!(matches!(
+            client.sign("carol", b"message"),
+            Err(AgentError::KeyNotFound(key_id)) if key_id == "carol"
+        ));
+
+        drop(client);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_oversized_frame_is_rejected() {
+        let mut frame = (MAX_FRAME_LEN + 1).to_le_bytes().to_vec();
+        frame.extend_from_slice(&[0; 8]);
+        let err =
+            read_frame::<AgentRequest>(&mut frame.as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_bind_replaces_only_a_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("agent.sock");
+        drop(SigningAgent::bind(&path).unwrap());
+        // A stale socket is replaced
+        let listener = SigningAgent::bind(&path).unwrap();
+        assert!(UnixStream::connect(&path).is_ok());
+        drop(listener);
+
+        let file = dir.path().join("file");
+        fs::write(&file, "data").unwrap();
+        let err = SigningAgent::bind(&file).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_to_string(&file).unwrap(), "data");
+        // No private directory is left behind
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_bind_refuses_a_shared_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = dir.path().join("shared");
+        fs::create_dir(&shared).unwrap();
+        fs::set_permissions(&shared, fs::Permissions::from_mode(0o777))
+            .unwrap();
+        let err = SigningAgent::bind(&shared.join("agent.sock")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(fs::read_dir(&shared).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_peer_uid() {
+        let (client, _server) = UnixStream::pair().unwrap();
+        assert_eq!(peer_uid(&client).unwrap(), current_uid());
+    }
+}
@@ -9,8 +9,8 @@ use serde::{Deserialize, Serialize};
 
 /// Aliases created from raw strings are kept in-memory as given, but their
 /// `Serialize` and `Display` instance converts them to lowercase. Their
-/// `PartialEq` instance is case-insensitive.
-#[derive(Clone, Debug, Default, Deserialize, PartialOrd, Ord, Eq)]
+/// `PartialEq` and `Ord` instances are case-insensitive.
+#[derive(Clone, Debug, Default, Deserialize, Eq)]
 #[serde(transparent)]
 pub struct Alias(String);
 
@@ -46,6 +46,18 @@ impl PartialEq for Alias {
     }
 }
 
+impl PartialOrd for Alias {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Alias {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.normalize().cmp(&other.normalize())
+    }
+}
+
 impl Hash for Alias {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         self.normalize().hash(state);
@@ -87,22 +99,74 @@ impl FromStr for Alias {
     }
 }
 
+/// The role of a key, derived from its alias following the default validator
+/// key aliases. Keys with any other alias are account keys.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum KeyRole {
+    /// A regular account key
+    Account,
+    /// A validator's account key
+    ValidatorAccount,
+    /// A validator's consensus key
+    Consensus,
+    /// A validator's staking rewards key
+    Rewards,
+    /// A validator's Tendermint node key
+    TendermintNode,
+}
+
+const VALIDATOR_KEY_SUFFIX: &str = "-validator-key";
+const CONSENSUS_KEY_SUFFIX: &str = "-consensus-key";
+const REWARDS_KEY_SUFFIX: &str = "-rewards-key";
+const TENDERMINT_NODE_KEY_SUFFIX: &str = "-tendermint-node-key";
+
+impl KeyRole {
+    /// Get the role of a key from its alias
+    pub fn of_alias(alias: impl AsRef<str>) -> Self {
+        let alias = alias.as_ref().to_lowercase();
+        if alias.ends_with(TENDERMINT_NODE_KEY_SUFFIX) {
+            Self::TendermintNode
+        } else if alias.ends_with(VALIDATOR_KEY_SUFFIX) {
+            Self::ValidatorAccount
+        } else if alias.ends_with(CONSENSUS_KEY_SUFFIX) {
+            Self::Consensus
+        } else if alias.ends_with(REWARDS_KEY_SUFFIX) {
+            Self::Rewards
+        } else {
+            Self::Account
+        }
+    }
+}
+
+impl Display for KeyRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let role = match self {
+            KeyRole::Account => "account",
+            KeyRole::ValidatorAccount => "validator-account",
+            KeyRole::Consensus => "consensus",
+            KeyRole::Rewards => "rewards",
+            KeyRole::TendermintNode => "tendermint-node",
+        };
+        write!(f, "{}", role)
+    }
+}
+
 /// Default alias of a validator's account key
 pub fn validator_key(validator_alias: &Alias) -> Alias {
-    format!("{validator_alias}-validator-key").into()
+    format!("{validator_alias}{VALIDATOR_KEY_SUFFIX}").into()
 }
 
 /// Default alias of a validator's consensus key
 pub fn validator_consensus_key(validator_alias: &Alias) -> Alias {
-    format!("{validator_alias}-consensus-key").into()
+    format!("{validator_alias}{CONSENSUS_KEY_SUFFIX}").into()
 }
 
 /// Default alias of a validator's staking rewards key
 pub fn validator_rewards_key(validator_alias: &Alias) -> Alias {
-    format!("{validator_alias}-rewards-key").into()
+    format!("{validator_alias}{REWARDS_KEY_SUFFIX}").into()
 }
 
 /// Default alias of a validator's Tendermint node key
 pub fn validator_tendermint_node_key(validator_alias: &Alias) -> Alias {
-    format!("{validator_alias}-tendermint-node-key").into()
+    format!("{validator_alias}{TENDERMINT_NODE_KEY_SUFFIX}").into()
 }
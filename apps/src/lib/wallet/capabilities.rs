@@ -0,0 +1,141 @@
+//! Introspection of the cryptographic capabilities of this build of the
+//! wallet, e.g. for clients of a wallet RPC.
+
+use serde::Serialize;
+
+use super::keys::KEYSTORE_CONTENT_TYPE;
+
+/// Every feature of the crate but `default`, and whether it's enabled in this
+/// build
+const FEATURES: [(&str, bool); 9] = [
+    ("dev", cfg!(feature = "dev")),
+    ("std", cfg!(feature = "std")),
+    ("testing", cfg!(feature = "testing")),
+    ("ABCI", cfg!(feature = "ABCI")),
+    ("ABCI-plus-plus", cfg!(feature = "ABCI-plus-plus")),
+    ("pq", cfg!(feature = "pq")),
+    ("selftest", cfg!(feature = "selftest")),
+    ("sqlite", cfg!(feature = "sqlite")),
+    ("sqlcipher", cfg!(feature = "sqlcipher")),
+];
+
+/// The schemes, ciphers, KDFs and formats supported by this build
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Capabilities {
+    /// The version of the crate
+    pub version: &'static str,
+    /// The signature schemes of the keys
+    pub signature_schemes: Vec<&'static str>,
+    /// The signing modes available for the keys
+    pub signing_modes: Vec<&'static str>,
+    /// The AEAD ciphers used to encrypt the keys
+    pub ciphers: Vec<&'static str>,
+    /// The KDFs used to derive encryption keys from passwords
+    pub kdfs: Vec<&'static str>,
+    /// The ways the data key of an encrypted keypair can be wrapped
+    pub key_wrappings: Vec<&'static str>,
+    /// The formats of the stored keypairs
+    pub storage_formats: Vec<&'static str>,
+    /// The formats of secret keys that can be imported
+    pub import_formats: Vec<&'static str>,
+    /// The formats of secret keys that can be exported
+    pub export_formats: Vec<&'static str>,
+    /// The enabled optional features of the build
+    pub features: Vec<&'static str>,
+}
+
+/// Get the capabilities of this build
+pub fn capabilities() -> Capabilities {
+    let features = FEATURES
+        .into_iter()
+        .filter_map(|(feature, enabled)| enabled.then(|| feature))
+        .collect();
+    Capabilities {
+        version: env!("CARGO_PKG_VERSION"),
+        signature_schemes: vec![
+            "ed25519",
+            #[cfg(feature = "pq")]
+            "ed25519-dilithium3",
+        ],
+        signing_modes: vec![
+            "ed25519",
+            "ed25519ph",
+            "musig2",
+            "chain-bound",
+            "ownership-proof",
+        ],
+        ciphers: vec!["xchacha20-poly1305"],
+        kdfs: vec!["argon2i"],
+        key_wrappings: vec![
+            "password",
+            "kms",
+            "recovery-code",
+            "x25519-escrow",
+            "machine-binding",
+            "totp",
+            "dual-control",
+        ],
+        storage_formats: vec![
+            "unencrypted",
+            "encrypted",
+            "encrypted_v1",
+            KEYSTORE_CONTENT_TYPE,
+        ],
+        import_formats: vec![
+            "pkcs8-pem",
+            "pkcs8-encrypted",
+            "json-array",
+            "hex",
+            "wif",
+            "base58",
+            "base64",
+            "raw",
+            "mnemonic",
+            "jwks",
+            "gpg",
+        ],
+        export_formats: vec!["wif", "mnemonic"],
+        features,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capabilities_json() {
+        let json = serde_json::to_value(capabilities()).unwrap();
+        assert_eq!(json["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(json["ciphers"], serde_json::json!(["xchacha20-poly1305"]));
+        let features = json["features"].as_array().unwrap();
+        assert_eq!(features.contains(&"ABCI".into()), cfg!(feature = "ABCI"));
+    }
+
+    /// Every feature declared in the manifest must be reported, so that the
+    /// list can't miss a new one
+    #[test]
+    fn test_features_match_the_manifest() {
+        let manifest: toml::Value =
+            toml::from_str(include_str!("../../../Cargo.toml")).unwrap();
+        let mut declared: Vec<&str> = manifest["features"]
+            .as_table()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .filter(|feature| *feature != "default")
+            .collect();
+        declared.sort_unstable();
+        let mut known: Vec<&str> =
+            FEATURES.iter().map(|(feature, _)| *feature).collect();
+        known.sort_unstable();
+        assert_eq!(known, declared);
+
+        let enabled: Vec<&str> = FEATURES
+            .iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(feature, _)| *feature)
+            .collect();
+        assert_eq!(capabilities().features, enabled);
+    }
+}
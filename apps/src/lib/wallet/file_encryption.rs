@@ -0,0 +1,230 @@
+//! Encryption of arbitrary files at rest with keys derived from a wallet
+//! keypair, so that the files are only readable with the keypair.
+//!
+//! Every context, e.g. the name of an application or of a backup, gets its
+//! own data key, derived from the keypair's seed with
+//! [`derive_data_key`]. A file is encrypted as a stream of chunks with
+//! XChaCha20-Poly1305, as libsodium's `crypto_secretstream`, so that large
+//! files never need to be held in memory. The encrypted file is the random
+//! 24 bytes nonce of the stream followed by the encrypted chunks, each of
+//! [`FILE_CHUNK_SIZE`] bytes of plaintext and [`ABYTES`] bytes of overhead,
+//! except for the last one, which is tagged as final and may be shorter. A
+//! truncated, reordered or extended stream fails to decrypt.
+
+use std::io::{self, Read, Write};
+
+use anoma::types::key::*;
+use orion::hazardous::aead::streaming::{
+    Nonce, SecretStreamXChaCha20Poly1305, StreamTag, ABYTES,
+};
+use orion::hazardous::stream::chacha20;
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+/// The HKDF salt of the data keys derived from a keypair
+const DATA_KEY_DOMAIN: &[u8] = b"anoma-wallet:data-key:v1";
+/// The length of the nonce at the start of an encrypted file
+const NONCE_LEN: usize = 24;
+/// The size of the plaintext of every chunk of an encrypted file, except
+/// for the last one
+pub const FILE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Derive a 32 bytes data key for the given context from the keypair with
+/// HKDF-SHA256 over its seed, with a fixed domain separator as the salt and
+/// the context as the info. Different contexts give unrelated keys, which
+/// reveal nothing about the keypair.
+pub fn derive_data_key(
+    keypair: &common::SecretKey,
+    context: &[u8],
+) -> Zeroizing<[u8; 32]> {
+    use hkdf::Hkdf;
+
+    let common::SecretKey::Ed25519(sk) = keypair;
+    let ikm = Zeroizing::new(sk.0.to_bytes());
+    let hkdf = Hkdf::<Sha256>::new(Some(DATA_KEY_DOMAIN), &ikm[..]);
+    let mut key = Zeroizing::new([0; 32]);
+    hkdf.expand(context, &mut key[..])
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    key
+}
+
+/// Encrypt the data read from the reader with the data key of the keypair
+/// for the given context and write the encrypted file to the writer. The
+/// data is read and encrypted in chunks of [`FILE_CHUNK_SIZE`] bytes.
+pub fn encrypt_file(
+    keypair: &common::SecretKey,
+    mut reader: impl Read,
+    mut writer: impl Write,
+    context: &[u8],
+) -> io::Result<()> {
+    let nonce = Nonce::generate();
+    let mut stream = SecretStreamXChaCha20Poly1305::new(
+        &stream_key(keypair, context),
+        &nonce,
+    );
+    writer.write_all(nonce.as_ref())?;
+
+    let mut chunk = Zeroizing::new(vec![0; FILE_CHUNK_SIZE]);
+    let mut sealed = vec![0; FILE_CHUNK_SIZE + ABYTES];
+    loop {
+        let len = read_full(&mut reader, &mut chunk)?;
+        // A full chunk may be followed by more data, so the stream is only
+        // finished by a shorter one, which is empty if the data is a multiple
+        // of the chunk size
+        let tag = if len < FILE_CHUNK_SIZE {
+            StreamTag::Finish
+        } else {
+            StreamTag::Message
+        };
+        stream
+            .seal_chunk(&chunk[..len], None, &mut sealed[..len + ABYTES], &tag)
+            .map_err(|_| invalid_data("Unable to encrypt the file"))?;
+        writer.write_all(&sealed[..len + ABYTES])?;
+        if len < FILE_CHUNK_SIZE {
+            return writer.flush();
+        }
+    }
+}
+
+/// Decrypt an encrypted file written by [`encrypt_file`] with the same
+/// keypair and context, read from the reader, and write the decrypted data
+/// to the writer. Fails with [`io::ErrorKind::InvalidData`] if the keypair
+/// or the context are wrong or if the file was modified or truncated. The
+/// chunks before the failing one have then already been written.
+pub fn decrypt_file(
+    keypair: &common::SecretKey,
+    mut reader: impl Read,
+    mut writer: impl Write,
+    context: &[u8],
+) -> io::Result<()> {
+    let mut nonce = [0; NONCE_LEN];
+    if read_full(&mut reader, &mut nonce)? < NONCE_LEN {
+        return Err(invalid_data("The encrypted file is truncated"));
+    }
+    let nonce = Nonce::from_slice(&nonce)
+        .map_err(|_| invalid_data("Invalid encrypted file nonce"))?;
+    let mut stream = SecretStreamXChaCha20Poly1305::new(
+        &stream_key(keypair, context),
+        &nonce,
+    );
+
+    let mut sealed = vec![0; FILE_CHUNK_SIZE + ABYTES];
+    let mut chunk = Zeroizing::new(vec![0; FILE_CHUNK_SIZE]);
+    loop {
+        let len = read_full(&mut reader, &mut sealed)?;
+        if len < ABYTES {
+            return Err(invalid_data("The encrypted file is truncated"));
+        }
+        let tag = stream
+            .open_chunk(&sealed[..len], None, &mut chunk[..len - ABYTES])
+            .map_err(|_| {
+                invalid_data(
+                    "Unable to decrypt the file. Are the key and the context \
+                     correct?",
+                )
+            })?;
+        writer.write_all(&chunk[..len - ABYTES])?;
+        match tag {
+            StreamTag::Finish => {
+                if read_full(&mut reader, &mut [0])? != 0 {
+                    return Err(invalid_data(
+                        "Unexpected data after the end of the encrypted file",
+                    ));
+                }
+                return writer.flush();
+            }
+            _ if len < sealed.len() => {
+                return Err(invalid_data("The encrypted file is truncated"));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The key of the encryption stream of a file
+fn stream_key(
+    keypair: &common::SecretKey,
+    context: &[u8],
+) -> chacha20::SecretKey {
+    chacha20::SecretKey::from_slice(&derive_data_key(keypair, context)[..])
+        .expect("32 bytes should be a valid ChaCha20 key")
+}
+
+/// Fill the buffer from the reader, unless it ends first. Returns the number
+/// of bytes read.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(len) => filled += len,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(filled)
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use anoma::types::key::testing::{keypair_1, keypair_2};
+
+    use super::*;
+
+    fn encrypt(data: &[u8], context: &[u8]) -> Vec<u8> {
+        let mut encrypted = Vec::new();
+        encrypt_file(&keypair_1(), data, &mut encrypted, context).unwrap();
+        encrypted
+    }
+
+    fn decrypt(
+        keypair: &common::SecretKey,
+        encrypted: &[u8],
+        context: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        let mut decrypted = Vec::new();
+        decrypt_file(keypair, encrypted, &mut decrypted, context)
+            .map(|()| decrypted)
+    }
+
+    #[test]
+    fn test_file_roundtrip() {
+        for len in [0, 1, FILE_CHUNK_SIZE, 2 * FILE_CHUNK_SIZE + 100] {
+            let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let encrypted = encrypt(&data, b"backup");
+            assert_eq!(
+                encrypted.len(),
+                NONCE_LEN + len + (len / FILE_CHUNK_SIZE + 1) * ABYTES
+            );
+            assert_eq!(
+                decrypt(&keypair_1(), &encrypted, b"backup").unwrap(),
+                data
+            );
+        }
+    }
+
+    #[test]
+    fn test_file_tampering_is_detected() {
+        let data = vec![7; FILE_CHUNK_SIZE + 10];
+        let encrypted = encrypt(&data, b"backup");
+        let is_invalid = |result: io::Result<Vec<u8>>| {
+            result.unwrap_err().kind() == io::ErrorKind::InvalidData
+        };
+
+        assert!(is_invalid(decrypt(&keypair_2(), &encrypted, b"backup")));
+        assert!(is_invalid(decrypt(&keypair_1(), &encrypted, b"photos")));
+        // Dropping the final chunk
+        let truncated = &encrypted[..NONCE_LEN + FILE_CHUNK_SIZE + ABYTES];
+        assert!(is_invalid(decrypt(&keypair_1(), truncated, b"backup")));
+        let mut extended = encrypted.clone();
+        extended.push(0);
+        assert!(is_invalid(decrypt(&keypair_1(), &extended, b"backup")));
+        let mut modified = encrypted;
+        modified[NONCE_LEN + 1] ^= 1;
+        assert!(is_invalid(decrypt(&keypair_1(), &modified, b"backup")));
+    }
+}
@@ -0,0 +1,269 @@
+//! Hybrid keypairs of an Ed25519 key and a post-quantum Dilithium3 key.
+//!
+//! A hybrid signature consists of a signature by each of the two keys over
+//! the same message and is only valid if both are, so it stays secure as long
+//! as either scheme is unbroken. The hybrid keypairs are a wallet-side
+//! addition: the ledger still only verifies Ed25519 signatures. They're
+//! stored in the wallet as a [`super::StoredKeypair::Encrypted`] keypair,
+//! encrypted with [`super::EncryptedKeypair::new_hybrid`], which is used as
+//! its Ed25519 key for transactions.
+
+use anoma::types::key::*;
+use borsh::{BorshDeserialize, BorshSerialize};
+use pqcrypto_dilithium::dilithium3;
+use pqcrypto_traits::sign::{
+    DetachedSignature as _, PublicKey as _, SecretKey as _,
+};
+use zeroize::{Zeroize, Zeroizing};
+
+pub use super::keys::HybridScheme;
+use super::keys::PostQuantumKey;
+use super::store::gen_sk;
+use super::DecryptionError;
+
+/// A hybrid secret key. Both secret keys are zeroized on drop.
+pub struct HybridSecretKey {
+    /// The Ed25519 secret key
+    pub ed25519: common::SecretKey,
+    /// The Dilithium3 secret key
+    pub dilithium: dilithium3::SecretKey,
+    /// The Dilithium3 public key, which can't be derived from its secret key
+    dilithium_public: dilithium3::PublicKey,
+}
+
+/// A hybrid public key
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct HybridPublicKey {
+    /// The Ed25519 public key
+    pub ed25519: common::PublicKey,
+    /// The encoded Dilithium3 public key
+    pub dilithium: Vec<u8>,
+}
+
+/// A hybrid signature
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct HybridSignature {
+    /// The Ed25519 signature
+    pub ed25519: common::Signature,
+    /// The encoded Dilithium3 detached signature
+    pub dilithium: Vec<u8>,
+}
+
+impl HybridSecretKey {
+    /// Generate a new hybrid secret key with the operating system's random
+    /// number generator
+    pub fn generate() -> Self {
+        let (dilithium_public, dilithium) = dilithium3::keypair();
+        Self {
+            ed25519: gen_sk(),
+            dilithium,
+            dilithium_public,
+        }
+    }
+
+    /// The public key of this secret key
+    pub fn public_key(&self) -> HybridPublicKey {
+        HybridPublicKey {
+            ed25519: self.ed25519.ref_to(),
+            dilithium: self.dilithium_public.as_bytes().to_vec(),
+        }
+    }
+
+    /// The post-quantum key recorded in the header of the keystore of this
+    /// secret key
+    pub(super) fn post_quantum_key(&self) -> PostQuantumKey {
+        PostQuantumKey {
+            scheme: HybridScheme::Ed25519Dilithium3,
+            public_key: self.dilithium_public.as_bytes().to_vec(),
+        }
+    }
+
+    /// The plaintext of the keystore of this secret key, the Borsh encoding
+    /// of the Ed25519 secret key followed by the one of the encoded Dilithium3
+    /// secret key
+    pub(super) fn to_plaintext(&self) -> Zeroizing<Vec<u8>> {
+        let dilithium = Zeroizing::new(self.dilithium.as_bytes().to_vec());
+        let mut plaintext = Zeroizing::new(Vec::new());
+        self.ed25519
+            .serialize(&mut *plaintext)
+            .expect("Serializing keypair shouldn't fail");
+        dilithium
+            .serialize(&mut *plaintext)
+            .expect("Serializing keypair shouldn't fail");
+        plaintext
+    }
+
+    /// Rebuild a secret key from its decrypted Ed25519 secret key, its encoded
+    /// Dilithium3 secret key and the post-quantum key of its keystore
+    pub(super) fn from_parts(
+        ed25519: common::SecretKey,
+        dilithium: &[u8],
+        post_quantum: &PostQuantumKey,
+    ) -> Result<Self, DecryptionError> {
+        let dilithium = dilithium3::SecretKey::from_bytes(dilithium)
+            .map_err(|_| DecryptionError::DeserializingError)?;
+        let dilithium_public =
+            dilithium3::PublicKey::from_bytes(&post_quantum.public_key)
+                .map_err(|_| DecryptionError::DeserializingError)?;
+        Ok(Self {
+            ed25519,
+            dilithium,
+            dilithium_public,
+        })
+    }
+}
+
+impl Drop for HybridSecretKey {
+    fn drop(&mut self) {
+        let common::SecretKey::Ed25519(ed25519::SecretKey(sk)) =
+            &mut self.ed25519;
+        sk.zeroize();
+        // The Dilithium3 secret key is a bare byte array without any mutable
+        // access to it, so it's overwritten as a whole with a zeroed key
+        let zeroed = dilithium3::SecretKey::from_bytes(
+            &[0; dilithium3::secret_key_bytes()],
+        )
+        .expect("A zeroed Dilithium3 secret key has the right length");
+        // Safety: the pointer is to a valid and aligned owned value
+        unsafe { std::ptr::write_volatile(&mut self.dilithium, zeroed) };
+        std::sync::atomic::compiler_fence(std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+/// Sign a message with both keys of a hybrid secret key
+pub fn sign(keypair: &HybridSecretKey, msg: &[u8]) -> HybridSignature {
+    HybridSignature {
+        ed25519: common::SigScheme::sign(&keypair.ed25519, msg),
+        dilithium: dilithium3::detached_sign(msg, &keypair.dilithium)
+            .as_bytes()
+            .to_vec(),
+    }
+}
+
+/// Verify a hybrid signature, which is only valid if both of its signatures
+/// are valid
+pub fn verify(pk: &HybridPublicKey, msg: &[u8], sig: &HybridSignature) -> bool {
+    let ed25519_valid =
+        common::SigScheme::verify_signature_raw(&pk.ed25519, msg, &sig.ed25519)
+            .is_ok();
+    let dilithium_valid = match (
+        dilithium3::PublicKey::from_bytes(&pk.dilithium),
+        dilithium3::DetachedSignature::from_bytes(&sig.dilithium),
+    ) {
+        (Ok(dilithium_pk), Ok(dilithium_sig)) => {
+            dilithium3::verify_detached_signature(
+                &dilithium_sig,
+                msg,
+                &dilithium_pk,
+            )
+            .is_ok()
+        }
+        _ => false,
+    };
+    ed25519_valid && dilithium_valid
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wallet::{
+        EncryptedKeypair, FieldProtection, KdfParams, StoredKeypair,
+        KEYSTORE_FORMAT_VERSION,
+    };
+
+    #[test]
+    fn test_hybrid_signature_requires_both() {
+        let keypair = HybridSecretKey::generate();
+        let pk = keypair.public_key();
+        let sig = sign(&keypair, b"message");
+        assert!(verify(&pk, b"message", &sig));
+        assert!(!verify(&pk, b"other message", &sig));
+
+        let other = sign(&HybridSecretKey::generate(), b"message");
+        let mut forged = sig.clone();
+        forged.ed25519 = other.ed25519.clone();
+        assert!(!verify(&pk, b"message", &forged));
+        let mut forged = sig;
+        forged.dilithium = other.dilithium;
+        assert!(!verify(&pk, b"message", &forged));
+    }
+
+    #[test]
+    fn test_encrypted_hybrid_keypair_roundtrip() {
+        let keypair = HybridSecretKey::generate();
+        let params = KdfParams {
+            iterations: 3,
+            memory: 8,
+        };
+        let encrypted = EncryptedKeypair::new_hybrid(
+            &keypair,
+            "password".to_owned(),
+            &params,
+        );
+        let parsed: EncryptedKeypair = encrypted.to_string().parse().unwrap();
+        assert!(parsed.is_hybrid());
+        assert_eq!(
+            parsed.header().post_quantum,
+            Some(keypair.post_quantum_key())
+        );
+        let decrypted = parsed.decrypt_hybrid("password".to_owned()).unwrap();
+        assert_eq!(decrypted.public_key(), keypair.public_key());
+        let sig = sign(&decrypted, b"message");
+        assert!(verify(&keypair.public_key(), b"message", &sig));
+        assert!(matches!(
+            parsed.decrypt_hybrid("wrong".to_owned()),
+            Err(DecryptionError::DecryptionError)
+        ));
+
+        // Both keys survive a password change
+        let changed = parsed
+            .change_password("password".to_owned(), "new".to_owned())
+            .unwrap();
+        assert!(changed.is_hybrid());
+        let decrypted = changed.decrypt_hybrid("new".to_owned()).unwrap();
+        assert_eq!(decrypted.public_key(), keypair.public_key());
+
+        let plain = EncryptedKeypair::new(&keypair.ed25519, "password".into());
+        assert!(matches!(
+            plain.decrypt_hybrid("password".to_owned()),
+            Err(DecryptionError::NotHybrid)
+        ));
+    }
+
+    #[test]
+    fn test_stored_hybrid_keypair() {
+        let keypair = HybridSecretKey::generate();
+        let params = KdfParams {
+            iterations: 3,
+            memory: 8,
+        };
+        let stored = StoredKeypair::Encrypted(EncryptedKeypair::new_hybrid(
+            &keypair,
+            "password".to_owned(),
+            &params,
+        ));
+        let encoded = stored.to_string();
+        assert!(encoded.starts_with("encrypted_v1:"));
+        let parsed: StoredKeypair = encoded.parse().unwrap();
+        assert!(parsed.is_encrypted());
+        assert_eq!(parsed.public_key(), Some(keypair.ed25519.ref_to()));
+        assert_eq!(parsed.format_version(), Some(KEYSTORE_FORMAT_VERSION));
+        assert_eq!(
+            parsed.metadata_protection().post_quantum,
+            FieldProtection::Authenticated
+        );
+
+        // It's used as its Ed25519 key, and both keys can be read back
+        let ed25519 = parsed.get(true, Some("password".to_owned())).unwrap();
+        assert_eq!(ed25519.ref_to(), keypair.ed25519.ref_to());
+        let decrypted = parsed.get_hybrid(Some("password".to_owned())).unwrap();
+        assert_eq!(decrypted.public_key(), keypair.public_key());
+        assert!(parsed.verify_roundtrip("password", &keypair.ed25519));
+
+        let (raw, _) = StoredKeypair::new(keypair.ed25519.clone(), None);
+        assert!(matches!(
+            raw.get_hybrid(None),
+            Err(DecryptionError::NotHybrid)
+        ));
+    }
+}
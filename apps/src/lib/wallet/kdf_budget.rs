@@ -0,0 +1,184 @@
+//! A memory budget shared by the concurrent key derivations of the wallet.
+//!
+//! Every Argon2 key derivation allocates its whole memory cost up front, so
+//! unlocking many keys in parallel could run out of memory. Each derivation
+//! must first acquire its memory cost from the budget, which blocks until
+//! enough of it has been released by the derivations in progress.
+
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+use once_cell::sync::Lazy;
+use sysinfo::{RefreshKind, System, SystemExt};
+
+/// The default budget is the total memory of the system divided by this
+const DEFAULT_BUDGET_DIVISOR: u64 = 4;
+/// The default budget in kibibytes if the memory of the system is unknown
+const FALLBACK_BUDGET_KIB: u64 = 1 << 20;
+
+/// The budget used by the key derivations of the wallet
+static KDF_MEMORY_BUDGET: Lazy<KdfMemoryBudget> =
+    Lazy::new(KdfMemoryBudget::default);
+
+/// The memory budget shared by the key derivations of the wallet. Its
+/// default limit is a quarter of the total memory of the system, which can be
+/// changed with [`KdfMemoryBudget::set_limit`].
+pub fn kdf_memory_budget() -> &'static KdfMemoryBudget {
+    &KDF_MEMORY_BUDGET
+}
+
+/// A limit of the memory that may be used by key derivations at the same
+/// time
+#[derive(Debug)]
+pub struct KdfMemoryBudget {
+    state: Mutex<BudgetState>,
+    released: Condvar,
+}
+
+#[derive(Debug)]
+struct BudgetState {
+    /// The limit in kibibytes
+    limit: u64,
+    /// The memory in kibibytes acquired by the derivations in progress
+    in_use: u64,
+}
+
+impl BudgetState {
+    /// A derivation needing more memory than the whole limit may only run
+    /// alone, as it would otherwise never be allowed to
+    fn can_acquire(&self, memory: u64) -> bool {
+        self.in_use == 0 || self.in_use + memory <= self.limit
+    }
+}
+
+/// Memory acquired from a [`KdfMemoryBudget`], released when dropped
+#[derive(Debug)]
+pub struct KdfMemoryPermit<'budget> {
+    budget: &'budget KdfMemoryBudget,
+    memory: u64,
+}
+
+impl Drop for KdfMemoryPermit<'_> {
+    fn drop(&mut self) {
+        self.budget.lock().in_use -= self.memory;
+        self.budget.released.notify_all();
+    }
+}
+
+impl Default for KdfMemoryBudget {
+    fn default() -> Self {
+        let sys = System::new_with_specifics(RefreshKind::new().with_memory());
+        let limit = match sys.total_memory() {
+            0 => FALLBACK_BUDGET_KIB,
+            total => total / DEFAULT_BUDGET_DIVISOR,
+        };
+        Self::new(limit)
+    }
+}
+
+impl KdfMemoryBudget {
+    /// A budget with the given limit in kibibytes
+    pub fn new(limit: u64) -> Self {
+        Self {
+            state: Mutex::new(BudgetState { limit, in_use: 0 }),
+            released: Condvar::new(),
+        }
+    }
+
+    /// The limit in kibibytes
+    pub fn limit(&self) -> u64 {
+        self.lock().limit
+    }
+
+    /// Change the limit in kibibytes. The derivations in progress are not
+    /// affected, but new ones wait until they fit in the new limit.
+    pub fn set_limit(&self, limit: u64) {
+        self.lock().limit = limit;
+        self.released.notify_all();
+    }
+
+    /// The memory in kibibytes acquired by the derivations in progress
+    pub fn in_use(&self) -> u64 {
+        self.lock().in_use
+    }
+
+    /// Acquire the given memory in kibibytes, blocking until it fits in the
+    /// limit. A derivation needing more than the whole limit waits until no
+    /// other derivation is in progress.
+    pub fn acquire(&self, memory: u32) -> KdfMemoryPermit<'_> {
+        let memory = memory as u64;
+        let mut state = self.lock();
+        while !state.can_acquire(memory) {
+            state = self
+                .released
+                .wait(state)
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+        state.in_use += memory;
+        KdfMemoryPermit {
+            budget: self,
+            memory,
+        }
+    }
+
+    /// Acquire the given memory in kibibytes if it fits in the limit now
+    pub fn try_acquire(&self, memory: u32) -> Option<KdfMemoryPermit<'_>> {
+        let memory = memory as u64;
+        let mut state = self.lock();
+        if !state.can_acquire(memory) {
+            return None;
+        }
+        state.in_use += memory;
+        Some(KdfMemoryPermit {
+            budget: self,
+            memory,
+        })
+    }
+
+    /// The state is always consistent, so it's still usable if a thread
+    /// panicked while holding the lock
+    fn lock(&self) -> MutexGuard<'_, BudgetState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_budget_limits_concurrent_memory() {
+        let budget = KdfMemoryBudget::new(100);
+        let first = budget.acquire(60);
+        assert!(budget.try_acquire(60).is_none());
+        let second = budget.try_acquire(40).unwrap();
+        assert_eq!(budget.in_use(), 100);
+        drop(first);
+        drop(second);
+        assert_eq!(budget.in_use(), 0);
+
+        // More than the limit is only granted alone
+        let oversized = budget.try_acquire(200).unwrap();
+        assert!(budget.try_acquire(1).is_none());
+        drop(oversized);
+    }
+
+    #[test]
+    fn test_acquire_waits_for_release() {
+        let budget = Arc::new(KdfMemoryBudget::new(100));
+        let permit = budget.acquire(80);
+        let waiting = {
+            let budget = budget.clone();
+            thread::spawn(move || {
+                let _permit = budget.acquire(80);
+            })
+        };
+        drop(permit);
+        waiting.join().unwrap();
+        assert_eq!(budget.in_use(), 0);
+    }
+}
@@ -0,0 +1,166 @@
+//! X25519 key agreement between wallet identities, to derive the keys of an
+//! encrypted channel between them from their Ed25519 keypairs.
+//!
+//! The Ed25519 keys are converted to X25519 keys like libsodium's
+//! `crypto_sign_ed25519_sk_to_curve25519` and
+//! `crypto_sign_ed25519_pk_to_curve25519`: the X25519 secret key is the first
+//! half of the SHA-512 hash of the Ed25519 seed, i.e. the Ed25519 secret
+//! scalar, and the X25519 public key is the Montgomery form of the Ed25519
+//! public key.
+
+use anoma::types::key::*;
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use sha2::{Digest, Sha256, Sha512};
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+use super::import::{validate_public_key, KeyValidationError};
+
+/// The HKDF salt of the keys derived from a shared secret
+const SHARED_KEY_DOMAIN: &[u8] = b"anoma-wallet:key-agreement:v1";
+
+#[allow(missing_docs)]
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum KeyAgreementError {
+    #[error("Invalid public key of the other party: {0}")]
+    InvalidPublicKey(KeyValidationError),
+    #[error("The shared secret is zero, the other party's key is unsafe")]
+    NonContributory,
+}
+
+/// A Diffie-Hellman shared secret, to derive symmetric keys from with
+/// [`SharedSecret::derive_key`]
+pub struct SharedSecret(Zeroizing<[u8; 32]>);
+
+impl std::fmt::Debug for SharedSecret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SharedSecret(..)")
+    }
+}
+
+impl SharedSecret {
+    /// The raw X25519 shared secret. It's not uniformly random, so it
+    /// should not be used as a key directly.
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// Derive a 32 bytes symmetric key for the given context with
+    /// HKDF-SHA256 from the shared secret, with a fixed domain separator as
+    /// the salt and the context as the info. Different contexts give
+    /// unrelated keys.
+    pub fn derive_key(&self, context: &[u8]) -> [u8; 32] {
+        use hkdf::Hkdf;
+
+        let hkdf = Hkdf::<Sha256>::new(Some(SHARED_KEY_DOMAIN), &self.0[..]);
+        let mut key = [0; 32];
+        hkdf.expand(context, &mut key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        key
+    }
+}
+
+/// Convert an Ed25519 secret key to an X25519 secret key
+pub fn to_x25519_secret(
+    keypair: &common::SecretKey,
+) -> x25519_dalek::StaticSecret {
+    let common::SecretKey::Ed25519(sk) = keypair;
+    let seed = Zeroizing::new(sk.0.to_bytes());
+    let hash = Zeroizing::new(Sha512::digest(&seed[..]));
+    let mut scalar = Zeroizing::new([0; 32]);
+    scalar.copy_from_slice(&hash[..32]);
+    x25519_dalek::StaticSecret::from(*scalar)
+}
+
+/// Convert an Ed25519 public key to an X25519 public key. The public key
+/// must pass [`validate_public_key`].
+pub fn to_x25519_public(
+    pk: &common::PublicKey,
+) -> Result<x25519_dalek::PublicKey, KeyValidationError> {
+    validate_public_key(pk)?;
+    let common::PublicKey::Ed25519(pk) = pk;
+    let point = CompressedEdwardsY(pk.0.to_bytes())
+        .decompress()
+        .ok_or(KeyValidationError::InvalidPoint)?;
+    Ok(x25519_dalek::PublicKey::from(
+        point.to_montgomery().to_bytes(),
+    ))
+}
+
+/// Compute the X25519 shared secret of a keypair and the public key of
+/// another party, both converted from Ed25519. Both parties get the same
+/// shared secret.
+pub fn key_agreement(
+    keypair: &common::SecretKey,
+    their_public: &common::PublicKey,
+) -> Result<SharedSecret, KeyAgreementError> {
+    let their_public = to_x25519_public(their_public)
+        .map_err(KeyAgreementError::InvalidPublicKey)?;
+    let shared = to_x25519_secret(keypair).diffie_hellman(&their_public);
+    let shared = Zeroizing::new(*shared.as_bytes());
+    if *shared == [0; 32] {
+        return Err(KeyAgreementError::NonContributory);
+    }
+    Ok(SharedSecret(shared))
+}
+
+#[cfg(test)]
+mod tests {
+    use anoma::types::key::testing::{keypair_1, keypair_2};
+    use borsh::BorshDeserialize;
+
+    use super::*;
+
+    /// The secret key of an RFC 8032 Ed25519 test vector
+    fn rfc8032_keypair(seed: &str) -> common::SecretKey {
+        ed25519::SecretKey::try_from_slice(&hex::decode(seed).unwrap())
+            .unwrap()
+            .try_to_sk()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_x25519_conversion_vectors() {
+        // The RFC 8032 test vectors 1 and 2, with their X25519 keys as
+        // converted by libsodium and their shared secret as computed by
+        // OpenSSL's X25519
+        let alice = rfc8032_keypair(
+            "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+        );
+        let bob = rfc8032_keypair(
+            "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+        );
+        assert_eq!(
+            hex::encode(to_x25519_secret(&alice).to_bytes()),
+            "307c83864f2833cb427a2ef1c00a013cfdff2768d980c0a3a520f006904de94f"
+        );
+        assert_eq!(
+            hex::encode(to_x25519_public(&alice.ref_to()).unwrap().as_bytes()),
+            "d85e07ec22b0ad881537c2f44d662d1a143cf830c57aca4305d85c7a90f6b62e"
+        );
+        assert_eq!(
+            hex::encode(to_x25519_public(&bob.ref_to()).unwrap().as_bytes()),
+            "25c704c594b88afc00a76b69d1ed2b984d7e22550f3ed0802d04fbcd07d38d47"
+        );
+        // The converted secret key matches the converted public key
+        assert_eq!(
+            x25519_dalek::PublicKey::from(&to_x25519_secret(&bob)).as_bytes(),
+            to_x25519_public(&bob.ref_to()).unwrap().as_bytes()
+        );
+
+        let shared = key_agreement(&alice, &bob.ref_to()).unwrap();
+        assert_eq!(
+            hex::encode(shared.as_bytes()),
+            "5166f24a6918368e2af831a4affadd97af0ac326bdf143596c045967cc00230e"
+        );
+    }
+
+    #[test]
+    fn test_key_agreement_is_symmetric() {
+        let ours = key_agreement(&keypair_1(), &keypair_2().ref_to()).unwrap();
+        let theirs =
+            key_agreement(&keypair_2(), &keypair_1().ref_to()).unwrap();
+        assert_eq!(ours.derive_key(b"chat"), theirs.derive_key(b"chat"));
+        assert_ne!(ours.derive_key(b"chat"), ours.derive_key(b"files"));
+    }
+}
@@ -1,72 +1,114 @@
 //! Cryptographic keys for digital signatures support for the wallet.
 
+use std::collections::BTreeMap;
 use std::fmt::Display;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use anoma::types::address::{Address, ImplicitAddress};
 use anoma::types::key::*;
 use borsh::{BorshDeserialize, BorshSerialize};
+use orion::hazardous::aead::xchacha20poly1305;
+use orion::hazardous::stream::chacha20;
 use orion::{aead, kdf};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
+use zeroize::Zeroizing;
 
+use super::alias::KeyRole;
+#[cfg(feature = "pq")]
+use super::hybrid::HybridSecretKey;
+use super::kdf_budget::kdf_memory_budget;
 use super::read_password;
 
+/// Prefix of encrypted keypairs written before the [`KeystoreHeader`] was
+/// introduced. These are only read, never written.
 const ENCRYPTED_KEY_PREFIX: &str = "encrypted:";
+/// Prefix of encrypted keypairs with a [`KeystoreHeader`]. The versions of the
+/// wallet that predate it only know the [`ENCRYPTED_KEY_PREFIX`] and the
+/// [`UNENCRYPTED_KEY_PREFIX`], neither of which is a prefix of this one, so
+/// they fail to load a wallet with such a keypair with a missing prefix error
+/// instead of misreading it.
+const ENCRYPTED_KEY_V1_PREFIX: &str = "encrypted_v1:";
 const UNENCRYPTED_KEY_PREFIX: &str = "unencrypted:";
+const WATCH_ONLY_KEY_PREFIX: &str = "watch_only:";
+/// The prefixes of the string encodings of a [`StoredKeypair`]
+pub(super) const STORED_KEYPAIR_PREFIXES: &[&str] = &[
+    ENCRYPTED_KEY_V1_PREFIX,
+    UNENCRYPTED_KEY_PREFIX,
+    WATCH_ONLY_KEY_PREFIX,
+    ENCRYPTED_KEY_PREFIX,
+];
+/// File extension of keypairs saved in their own file
+const KEY_FILE_EXTENSION: &str = "key";
+/// Prefix of the line after the keypair in a key file, with the hex encoded
+/// SHA-256 checksum of the keypair's line
+const KEY_FILE_CHECKSUM_PREFIX: &str = "checksum:";
+/// The content type of the hex encoded [`EncryptedKeypair`] format
+pub(super) const KEYSTORE_CONTENT_TYPE: &str =
+    "application/vnd.anoma.keystore.v1+hex";
+/// The version of the format of the encrypted keypairs written by this
+/// version of the wallet, with a [`KeystoreHeader`], as in the
+/// [`KEYSTORE_CONTENT_TYPE`]
+pub const KEYSTORE_FORMAT_VERSION: u8 = 1;
+/// The version of the format of the encrypted keypairs written before the
+/// [`KeystoreHeader`] was introduced, with the [`ENCRYPTED_KEY_PREFIX`]
+pub const LEGACY_KEYSTORE_FORMAT_VERSION: u8 = 0;
+/// The version of the encoding of the [`KeystoreHeader`], which starts it
+pub const KEYSTORE_HEADER_VERSION: u8 = 1;
+/// The length of the nonce at the start of the data encrypted by
+/// [`aead::seal`]
+const AEAD_NONCE_LEN: usize = 24;
+/// The length of the nonce and the authentication tag added by
+/// [`aead::seal`] to the encrypted data
+const AEAD_OVERHEAD: usize = AEAD_NONCE_LEN + 16;
+/// The maximum length of a decompressed keypair, to bound the memory used by
+/// a maliciously crafted keystore
+const MAX_DECOMPRESSED_LEN: usize = 1 << 20;
 
 /// A keypair stored in a wallet
 #[derive(Debug)]
 pub enum StoredKeypair {
-    /// An encrypted keypair
+    /// An encrypted keypair, possibly a hybrid keypair of an Ed25519 key and
+    /// a post-quantum key. The ledger only verifies Ed25519 signatures, so a
+    /// hybrid keypair is used as its Ed25519 key everywhere but in
+    /// [`StoredKeypair::get_hybrid`].
     Encrypted(EncryptedKeypair),
     /// An raw (unencrypted) keypair
     Raw(
         // Wrapped in `Rc` to avoid reference lifetimes when we borrow the key
         Rc<common::SecretKey>,
     ),
+    /// Only the public key of a keypair whose secret key is held elsewhere,
+    /// to watch its account
+    WatchOnly(common::PublicKey),
 }
 
-impl Serialize for StoredKeypair {
-    fn serialize<S>(
-        &self,
-        serializer: S,
-    ) -> std::result::Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
+impl Display for StoredKeypair {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         // String encoded, because toml doesn't support enums
         match self {
             StoredKeypair::Encrypted(encrypted) => {
-                let keypair_string =
-                    format!("{}{}", ENCRYPTED_KEY_PREFIX, encrypted);
-                serde::Serialize::serialize(&keypair_string, serializer)
+                write!(f, "{}{}", ENCRYPTED_KEY_V1_PREFIX, encrypted)
             }
             StoredKeypair::Raw(raw) => {
-                let keypair_string =
-                    format!("{}{}", UNENCRYPTED_KEY_PREFIX, raw);
-                serde::Serialize::serialize(&keypair_string, serializer)
+                write!(f, "{}{}", UNENCRYPTED_KEY_PREFIX, raw)
+            }
+            StoredKeypair::WatchOnly(pk) => {
+                write!(f, "{}{}", WATCH_ONLY_KEY_PREFIX, pk)
             }
         }
     }
 }
 
-impl<'de> Deserialize<'de> for StoredKeypair {
-    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        use serde::de::Error;
+impl FromStr for StoredKeypair {
+    type Err = DeserializeStoredKeypairError;
 
-        let keypair_string: String =
-            serde::Deserialize::deserialize(deserializer)
-                .map_err(|err| {
-                    DeserializeStoredKeypairError::InvalidStoredKeypairString(
-                        err.to_string(),
-                    )
-                })
-                .map_err(D::Error::custom)?;
-        if let Some(raw) = keypair_string.strip_prefix(UNENCRYPTED_KEY_PREFIX) {
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(raw) = s.strip_prefix(UNENCRYPTED_KEY_PREFIX) {
             FromStr::from_str(raw)
                 .map(|keypair| Self::Raw(Rc::new(keypair)))
                 .map_err(|err| {
@@ -74,25 +116,68 @@ impl<'de> Deserialize<'de> for StoredKeypair {
                         err.to_string(),
                     )
                 })
-                .map_err(D::Error::custom)
-        } else if let Some(encrypted) =
-            keypair_string.strip_prefix(ENCRYPTED_KEY_PREFIX)
+        } else if let Some(encrypted) = s.strip_prefix(ENCRYPTED_KEY_V1_PREFIX)
         {
-            FromStr::from_str(encrypted)
+            FromStr::from_str(encrypted).map(Self::Encrypted).map_err(
+                |err: ParseEncryptedKeypairError| {
+                    DeserializeStoredKeypairError::InvalidStoredKeypairString(
+                        err.to_string(),
+                    )
+                },
+            )
+        } else if let Some(pk) = s.strip_prefix(WATCH_ONLY_KEY_PREFIX) {
+            FromStr::from_str(pk).map(Self::WatchOnly).map_err(
+                |err: ParsePublicKeyError| {
+                    DeserializeStoredKeypairError::InvalidStoredKeypairString(
+                        err.to_string(),
+                    )
+                },
+            )
+        } else if let Some(encrypted) = s.strip_prefix(ENCRYPTED_KEY_PREFIX) {
+            EncryptedKeypair::from_legacy_str(encrypted)
                 .map(Self::Encrypted)
                 .map_err(|err| {
                     DeserializeStoredKeypairError::InvalidStoredKeypairString(
                         err.to_string(),
                     )
                 })
-                .map_err(D::Error::custom)
         } else {
             Err(DeserializeStoredKeypairError::MissingPrefix)
-                .map_err(D::Error::custom)
         }
     }
 }
 
+impl Serialize for StoredKeypair {
+    fn serialize<S>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serde::Serialize::serialize(&self.to_string(), serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for StoredKeypair {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        let keypair_string: String =
+            serde::Deserialize::deserialize(deserializer)
+                .map_err(|err| {
+                    DeserializeStoredKeypairError::InvalidStoredKeypairString(
+                        err.to_string(),
+                    )
+                })
+                .map_err(D::Error::custom)?;
+        Self::from_str(&keypair_string).map_err(D::Error::custom)
+    }
+}
+
 #[allow(missing_docs)]
 #[derive(Error, Debug)]
 pub enum DeserializeStoredKeypairError {
@@ -102,24 +187,835 @@ pub enum DeserializeStoredKeypairError {
     MissingPrefix,
 }
 
+#[allow(missing_docs)]
+#[derive(Error, Debug)]
+pub enum LoadKeypairError {
+    #[error("Failed to read the keypair file: {0}")]
+    Read(std::io::Error),
+    #[error("{0}")]
+    Deserialize(DeserializeStoredKeypairError),
+    #[error(
+        "The keypair file doesn't match its stored commitment. It may have \
+         been tampered with or substituted."
+    )]
+    CommitmentMismatch,
+    #[error(
+        "The keypair file doesn't match its checksum, it's likely corrupted"
+    )]
+    ChecksumMismatch,
+}
+
 /// An encrypted keypair stored in a wallet
-#[derive(Debug)]
-pub struct EncryptedKeypair(Vec<u8>);
+#[derive(Debug, BorshSerialize, BorshDeserialize)]
+pub struct EncryptedKeypair {
+    /// Cleartext metadata about the keystore, authenticated by the encryption
+    /// of the keypair
+    header: KeystoreHeader,
+    /// The encryption salt followed by the encrypted keypair
+    data: Vec<u8>,
+}
+
+/// Cleartext metadata stored together with an encrypted keypair.
+///
+/// The header is encoded as the [`KEYSTORE_HEADER_VERSION`] byte followed by
+/// the Borsh encoding of a list of its fields, each a tag and the
+/// length-prefixed Borsh encoding of its value, in increasing order of their
+/// tags. Absent fields are left out. A new field gets a new tag: the versions
+/// of the wallet that don't know it skip it and keep it as it is. The version
+/// is only bumped for changes that older versions must not ignore, which then
+/// refuse to read the header.
+///
+/// The encoded header, without the public key, is the associated data of the
+/// encryption of the keypair, so that tampering with any of its fields, known
+/// or not, or swapping it for another header makes the decryption fail. The
+/// public key is instead checked against the decrypted keypair, so that a
+/// corrupted one can be fixed with [`EncryptedKeypair::repair_public`]. Only
+/// the headers of legacy keystores, read from the format without a header,
+/// are not authenticated.
+#[derive(Debug, Clone, Default)]
+pub struct KeystoreHeader {
+    /// The version of the software that created the keystore. This is `None`
+    /// for keystores written before it was being recorded.
+    pub created_by_version: Option<String>,
+    /// The public key of the encrypted keypair, readable without decryption.
+    /// This is `None` for keystores written before it was being recorded.
+    pub public_key: Option<common::PublicKey>,
+    /// If set, the keypair is encrypted with a data key wrapped by a KMS
+    /// instead of a key derived from a password.
+    pub kms: Option<KmsWrapping>,
+    /// The parameters used to derive the encryption key from the password.
+    /// This is `None` for keystores written before they were being recorded,
+    /// which used the [`KdfParams::default`].
+    pub kdf_params: Option<KdfParams>,
+    /// If set, the keypair is encrypted with a random data key, which is in
+    /// turn encrypted with the key derived from the password, so that the
+    /// data key can also be wrapped for a [`RecoveryCode`] or an auditor.
+    pub password_wrapped_data_key: Option<Vec<u8>>,
+    /// If set, the data key is also wrapped by a [`RecoveryCode`].
+    pub recovery: Option<RecoveryWrapping>,
+    /// If set, the data key is also wrapped to the public key of an auditor,
+    /// who can decrypt the keypair without the password.
+    pub escrow: Option<EscrowWrapping>,
+    /// If set, the encryption key is also derived from a fingerprint of the
+    /// machine the keypair was encrypted on and it only decrypts there.
+    pub machine_binding: Option<MachineBinding>,
+    /// If set, the encryption key is also derived from a TOTP secret and a
+    /// valid TOTP code is required to decrypt the keypair.
+    pub totp: Option<TotpWrapping>,
+    /// A random nonce drawn when the keystore was created, to tell apart
+    /// keystores of the same keypair, e.g. derived from the same seed on
+    /// several devices. It doesn't affect the key nor its decryption. This is
+    /// `None` for keystores written before it was being recorded.
+    pub instance_id: Option<[u8; 16]>,
+    /// If set, the serialized keypair is compressed with this algorithm
+    /// before it's encrypted. This is `None` for keystores written before it
+    /// was being recorded and when compression doesn't make it smaller.
+    pub compression: Option<Compression>,
+    /// The length in bytes of the salt at the start of the encrypted data.
+    /// This is `None` for keystores written before it was being recorded,
+    /// whose salt is assumed to be of the current length, unless detected
+    /// otherwise with [`EncryptedKeypair::detect_salt_length`]. It's unused
+    /// for keypairs wrapped with a KMS, which have no salt.
+    pub salt_len: Option<u32>,
+    /// The time the keypair was encrypted, in seconds since the Unix epoch.
+    /// Changing the password counts as encrypting the keypair again. This is
+    /// `None` for keystores written before it was being recorded.
+    pub created_at: Option<u64>,
+    /// If set, the data key is split into two shares, each wrapped with a
+    /// key derived from the password of a different authorizer, and the
+    /// keypair can only be decrypted with both passwords.
+    pub dual_control: Option<DualControlWrapping>,
+    /// If set, the keypair is a hybrid keypair of an Ed25519 key and this
+    /// post-quantum key, and the plaintext is the Ed25519 secret key followed
+    /// by the post-quantum secret key. The versions of the wallet that don't
+    /// know this field fail to deserialize the plaintext instead of
+    /// misreading it.
+    pub post_quantum: Option<PostQuantumKey>,
+    /// The fields unknown to this version of the wallet by their tag, kept to
+    /// encode the header unchanged
+    unknown_fields: BTreeMap<u8, Vec<u8>>,
+    /// Whether the keystore was read from the format without a header, whose
+    /// keypair is encrypted without associated data
+    legacy: bool,
+}
+
+/// The tags of the fields of an encoded [`KeystoreHeader`]
+mod header_tag {
+    pub const CREATED_BY_VERSION: u8 = 1;
+    pub const PUBLIC_KEY: u8 = 2;
+    pub const KMS: u8 = 3;
+    pub const KDF_PARAMS: u8 = 4;
+    pub const PASSWORD_WRAPPED_DATA_KEY: u8 = 5;
+    pub const RECOVERY: u8 = 6;
+    pub const ESCROW: u8 = 7;
+    pub const MACHINE_BINDING: u8 = 8;
+    pub const TOTP: u8 = 9;
+    pub const INSTANCE_ID: u8 = 10;
+    pub const COMPRESSION: u8 = 11;
+    pub const SALT_LEN: u8 = 12;
+    pub const CREATED_AT: u8 = 13;
+    pub const DUAL_CONTROL: u8 = 14;
+    /// A field without value, present only in legacy headers
+    pub const LEGACY: u8 = 15;
+    pub const POST_QUANTUM: u8 = 16;
+}
+
+impl KeystoreHeader {
+    /// The header of a keystore created now by this version of the wallet,
+    /// without any of the fields describing its encryption
+    fn new() -> Self {
+        Self {
+            created_by_version: Some(env!("CARGO_PKG_VERSION").to_owned()),
+            instance_id: Some(new_instance_id()),
+            created_at: Some(now_secs()),
+            ..Self::default()
+        }
+    }
+
+    /// The header of a keystore read from the format without a header
+    fn legacy() -> Self {
+        Self {
+            legacy: true,
+            ..Self::default()
+        }
+    }
+
+    /// The encoded fields of the header, by their tag
+    fn fields(&self) -> BTreeMap<u8, Vec<u8>> {
+        fn insert(
+            fields: &mut BTreeMap<u8, Vec<u8>>,
+            tag: u8,
+            value: Option<&impl BorshSerialize>,
+        ) {
+            if let Some(value) = value {
+                let value = value
+                    .try_to_vec()
+                    .expect("Serializing keystore header shouldn't fail");
+                fields.insert(tag, value);
+            }
+        }
+
+        let mut fields = self.unknown_fields.clone();
+        insert(
+            &mut fields,
+            header_tag::CREATED_BY_VERSION,
+            self.created_by_version.as_ref(),
+        );
+        insert(
+            &mut fields,
+            header_tag::PUBLIC_KEY,
+            self.public_key.as_ref(),
+        );
+        insert(&mut fields, header_tag::KMS, self.kms.as_ref());
+        insert(
+            &mut fields,
+            header_tag::KDF_PARAMS,
+            self.kdf_params.as_ref(),
+        );
+        insert(
+            &mut fields,
+            header_tag::PASSWORD_WRAPPED_DATA_KEY,
+            self.password_wrapped_data_key.as_ref(),
+        );
+        insert(&mut fields, header_tag::RECOVERY, self.recovery.as_ref());
+        insert(&mut fields, header_tag::ESCROW, self.escrow.as_ref());
+        insert(
+            &mut fields,
+            header_tag::MACHINE_BINDING,
+            self.machine_binding.as_ref(),
+        );
+        insert(&mut fields, header_tag::TOTP, self.totp.as_ref());
+        insert(
+            &mut fields,
+            header_tag::INSTANCE_ID,
+            self.instance_id.as_ref(),
+        );
+        insert(
+            &mut fields,
+            header_tag::COMPRESSION,
+            self.compression.as_ref(),
+        );
+        insert(&mut fields, header_tag::SALT_LEN, self.salt_len.as_ref());
+        insert(
+            &mut fields,
+            header_tag::CREATED_AT,
+            self.created_at.as_ref(),
+        );
+        insert(
+            &mut fields,
+            header_tag::DUAL_CONTROL,
+            self.dual_control.as_ref(),
+        );
+        if self.legacy {
+            fields.insert(header_tag::LEGACY, Vec::new());
+        }
+        insert(
+            &mut fields,
+            header_tag::POST_QUANTUM,
+            self.post_quantum.as_ref(),
+        );
+        fields
+    }
+
+    /// Encode the header from its fields
+    fn encode(fields: BTreeMap<u8, Vec<u8>>) -> Vec<u8> {
+        let fields: Vec<(u8, Vec<u8>)> = fields.into_iter().collect();
+        (KEYSTORE_HEADER_VERSION, fields)
+            .try_to_vec()
+            .expect("Serializing keystore header shouldn't fail")
+    }
+
+    /// The associated data of the encryption of the keypair: the encoded
+    /// header without the public key, or nothing for a legacy header
+    fn associated_data(&self) -> Vec<u8> {
+        if self.legacy {
+            return Vec::new();
+        }
+        let mut fields = self.fields();
+        fields.remove(&header_tag::PUBLIC_KEY);
+        Self::encode(fields)
+    }
+}
+
+impl BorshSerialize for KeystoreHeader {
+    fn serialize<W: std::io::Write>(
+        &self,
+        writer: &mut W,
+    ) -> std::io::Result<()> {
+        writer.write_all(&Self::encode(self.fields()))
+    }
+}
+
+impl BorshDeserialize for KeystoreHeader {
+    fn deserialize(buf: &mut &[u8]) -> std::io::Result<Self> {
+        fn decode<T: BorshDeserialize>(value: &[u8]) -> std::io::Result<T> {
+            T::try_from_slice(value)
+        }
+        let invalid = |msg: String| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+        };
+
+        let version = u8::deserialize(buf)?;
+        if version != KEYSTORE_HEADER_VERSION {
+            return Err(invalid(format!(
+                "Unsupported keystore header version {}",
+                version
+            )));
+        }
+        let fields: Vec<(u8, Vec<u8>)> = BorshDeserialize::deserialize(buf)?;
+        let mut header = Self::default();
+        let mut last_tag = None;
+        for (tag, value) in fields {
+            // A canonical encoding, for the associated data to be the same
+            // when the header is encoded again
+            if last_tag >= Some(tag) {
+                return Err(invalid(format!(
+                    "The keystore header field {} is duplicated or out of \
+                     order",
+                    tag
+                )));
+            }
+            last_tag = Some(tag);
+            match tag {
+                header_tag::CREATED_BY_VERSION => {
+                    header.created_by_version = Some(decode(&value)?)
+                }
+                header_tag::PUBLIC_KEY => {
+                    header.public_key = Some(decode(&value)?)
+                }
+                header_tag::KMS => header.kms = Some(decode(&value)?),
+                header_tag::KDF_PARAMS => {
+                    header.kdf_params = Some(decode(&value)?)
+                }
+                header_tag::PASSWORD_WRAPPED_DATA_KEY => {
+                    header.password_wrapped_data_key = Some(decode(&value)?)
+                }
+                header_tag::RECOVERY => header.recovery = Some(decode(&value)?),
+                header_tag::ESCROW => header.escrow = Some(decode(&value)?),
+                header_tag::MACHINE_BINDING => {
+                    header.machine_binding = Some(decode(&value)?)
+                }
+                header_tag::TOTP => header.totp = Some(decode(&value)?),
+                header_tag::INSTANCE_ID => {
+                    header.instance_id = Some(decode(&value)?)
+                }
+                header_tag::COMPRESSION => {
+                    header.compression = Some(decode(&value)?)
+                }
+                header_tag::SALT_LEN => header.salt_len = Some(decode(&value)?),
+                header_tag::CREATED_AT => {
+                    header.created_at = Some(decode(&value)?)
+                }
+                header_tag::DUAL_CONTROL => {
+                    header.dual_control = Some(decode(&value)?)
+                }
+                header_tag::LEGACY => {
+                    if !value.is_empty() {
+                        return Err(invalid(
+                            "The legacy keystore header field has a value"
+                                .to_owned(),
+                        ));
+                    }
+                    header.legacy = true
+                }
+                header_tag::POST_QUANTUM => {
+                    header.post_quantum = Some(decode(&value)?)
+                }
+                _ => {
+                    header.unknown_fields.insert(tag, value);
+                }
+            }
+        }
+        Ok(header)
+    }
+}
+
+/// The salt lengths tried by [`EncryptedKeypair::detect_salt_length`], in
+/// this order: the 16 bytes of the default [`kdf::Salt`], which every
+/// version of the wallet has used so far, then 32 bytes, the other salt
+/// length commonly used with Argon2, and 8 bytes, the shortest salt accepted
+/// by the key derivation.
+const KNOWN_SALT_LENGTHS: [usize; 3] = [16, 32, 8];
+
+/// A compression algorithm of the plaintext of an encrypted keypair
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, BorshSerialize, BorshDeserialize,
+)]
+pub enum Compression {
+    /// Raw DEFLATE (RFC 1951)
+    Deflate,
+}
+
+impl Compression {
+    /// Compress the data
+    fn compress(&self, data: &[u8]) -> Zeroizing<Vec<u8>> {
+        use std::io::Write;
+
+        use flate2::write::DeflateEncoder;
+        match self {
+            Compression::Deflate => {
+                let mut encoder = DeflateEncoder::new(
+                    Vec::new(),
+                    flate2::Compression::best(),
+                );
+                encoder
+                    .write_all(data)
+                    .expect("Compressing in memory shouldn't fail");
+                encoder
+                    .finish()
+                    .map(Zeroizing::new)
+                    .expect("Compressing in memory shouldn't fail")
+            }
+        }
+    }
+
+    /// Decompress the data, failing if it doesn't decompress to at most
+    /// [`MAX_DECOMPRESSED_LEN`] bytes
+    fn decompress(
+        &self,
+        data: &[u8],
+    ) -> Result<Zeroizing<Vec<u8>>, DecryptionError> {
+        use std::io::Read;
+
+        use flate2::read::DeflateDecoder;
+        match self {
+            Compression::Deflate => {
+                let mut decompressed = Zeroizing::new(Vec::new());
+                DeflateDecoder::new(data)
+                    .take(MAX_DECOMPRESSED_LEN as u64 + 1)
+                    .read_to_end(&mut decompressed)
+                    .map_err(|_| DecryptionError::DeserializingError)?;
+                if decompressed.len() > MAX_DECOMPRESSED_LEN {
+                    return Err(DecryptionError::DeserializingError);
+                }
+                Ok(decompressed)
+            }
+        }
+    }
+}
+
+/// Parameters of the Argon2i key derivation of the encryption key from a
+/// password
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    BorshSerialize,
+    BorshDeserialize,
+    Serialize,
+    Deserialize,
+)]
+pub struct KdfParams {
+    /// The number of iterations, at least 3
+    pub iterations: u32,
+    /// The memory cost in kibibytes, at least 8
+    pub memory: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            iterations: 3,
+            memory: 1 << 16,
+        }
+    }
+}
+
+/// The KMS wrapping of the data key of an encrypted keypair
+#[derive(Debug, Clone, BorshSerialize, BorshDeserialize)]
+pub struct KmsWrapping {
+    /// The identifier of the KMS key that wrapped the data key
+    pub key_id: String,
+    /// The region of the KMS key, if applicable
+    pub region: Option<String>,
+    /// The data key, as wrapped by the KMS
+    pub wrapped_data_key: Vec<u8>,
+}
+
+/// The wrapping of the data key of an encrypted keypair by a
+/// [`RecoveryCode`]
+#[derive(Debug, Clone, BorshSerialize, BorshDeserialize)]
+pub struct RecoveryWrapping {
+    /// The salt of the key derived from the recovery code
+    pub recovery_salt: Vec<u8>,
+    /// The data key, encrypted with the key derived from the recovery code
+    pub recovery_wrapped_data_key: Vec<u8>,
+}
+
+/// The wrapping of the data key of an encrypted keypair to the X25519 public
+/// key of an auditor, in the manner of a sealed box
+#[derive(Debug, Clone, BorshSerialize, BorshDeserialize)]
+pub struct EscrowWrapping {
+    /// The X25519 public key of the auditor
+    pub auditor_public_key: [u8; 32],
+    /// The X25519 public key of the ephemeral secret that was used to derive
+    /// the wrapping key together with the auditor's public key
+    pub ephemeral_public_key: [u8; 32],
+    /// The data key, encrypted with the wrapping key
+    pub wrapped_data_key: Vec<u8>,
+}
+
+/// The wrapping of the data key of an encrypted keypair under dual control:
+/// the data key is the XOR of two random shares, each wrapped with a key
+/// derived from the password of one of the two authorizers. A single share
+/// reveals nothing about the data key, so neither password alone can decrypt
+/// the keypair.
+#[derive(Debug, Clone, BorshSerialize, BorshDeserialize)]
+pub struct DualControlWrapping {
+    /// The share of the first authorizer
+    pub first: AuthorizerShare,
+    /// The share of the second authorizer
+    pub second: AuthorizerShare,
+}
+
+/// A share of the data key of a keypair under dual control, wrapped with a
+/// key derived from the password of an authorizer
+#[derive(Debug, Clone, BorshSerialize, BorshDeserialize)]
+pub struct AuthorizerShare {
+    /// The salt of the key derived from the authorizer's password
+    pub salt: Vec<u8>,
+    /// The share, encrypted with the key derived from the password
+    pub wrapped_share: Vec<u8>,
+}
+
+/// The scheme of a hybrid keypair
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, BorshSerialize, BorshDeserialize,
+)]
+pub enum HybridScheme {
+    /// Ed25519 together with Dilithium3
+    Ed25519Dilithium3,
+}
+
+/// The post-quantum key of a hybrid keypair, stored next to its Ed25519 key
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct PostQuantumKey {
+    /// The scheme of the hybrid keypair
+    pub scheme: HybridScheme,
+    /// The encoded post-quantum public key, which can't be derived from its
+    /// secret key
+    pub public_key: Vec<u8>,
+}
+
+/// The binding of an encrypted keypair to a machine
+#[derive(Debug, Clone, BorshSerialize, BorshDeserialize)]
+pub struct MachineBinding {
+    /// The hash of the machine fingerprint, to tell a wrong machine apart
+    /// from a wrong password
+    pub fingerprint_hash: [u8; 32],
+}
+
+/// A source of a stable fingerprint of the machine, for
+/// [`EncryptedKeypair::new_bound_to_machine`]
+pub trait MachineFingerprint {
+    /// Get the fingerprint of the current machine
+    fn fingerprint(&self) -> std::io::Result<Vec<u8>>;
+}
+
+/// A machine fingerprint read from a file, by default the `/etc/machine-id`
+/// of systemd, which is generated on installation of the OS
+#[derive(Debug, Clone)]
+pub struct MachineIdFile(pub PathBuf);
+
+impl Default for MachineIdFile {
+    fn default() -> Self {
+        Self(PathBuf::from("/etc/machine-id"))
+    }
+}
+
+impl MachineFingerprint for MachineIdFile {
+    fn fingerprint(&self) -> std::io::Result<Vec<u8>> {
+        let content = fs::read_to_string(&self.0)?;
+        let id = content.trim();
+        if id.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("The machine ID file {} is empty", self.0.display()),
+            ));
+        }
+        Ok(id.as_bytes().to_vec())
+    }
+}
+
+impl MachineBinding {
+    fn new(fingerprint: &[u8]) -> Self {
+        Self {
+            fingerprint_hash: machine_hash(b"check", fingerprint),
+        }
+    }
+
+    fn matches(&self, fingerprint: &[u8]) -> bool {
+        machine_hash(b"check", fingerprint) == self.fingerprint_hash
+    }
+
+    /// Mix the machine fingerprint into a password
+    fn bind_password(password: String, fingerprint: &[u8]) -> String {
+        let password = Zeroizing::new(password);
+        format!(
+            "{}{}",
+            *password,
+            hex::encode(machine_hash(b"key", fingerprint))
+        )
+    }
+}
+
+/// The encrypted TOTP secret of a keypair that requires a TOTP code to be
+/// decrypted.
+///
+/// The TOTP secret is encrypted with the key derived from the password, so
+/// that the secret itself isn't needed to decrypt the keypair, only a code it
+/// generates. This makes a TOTP code a second factor for unlocking the
+/// keypair in the wallet, but it doesn't protect the keypair from someone
+/// who has both the keystore and the password, as they can decrypt the TOTP
+/// secret and generate codes.
+#[derive(Debug, Clone, BorshSerialize, BorshDeserialize)]
+pub struct TotpWrapping {
+    /// The parameters of the TOTP codes
+    pub params: TotpParams,
+    /// The TOTP secret, encrypted with the key derived from the password
+    pub encrypted_secret: Vec<u8>,
+}
+
+/// Parameters of the generation of TOTP codes (RFC 6238) with HMAC-SHA1, as
+/// supported by the common authenticator apps
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, BorshSerialize, BorshDeserialize,
+)]
+pub struct TotpParams {
+    /// The duration of a time step in seconds
+    pub step: u64,
+    /// The number of digits of a code
+    pub digits: u32,
+}
+
+impl Default for TotpParams {
+    fn default() -> Self {
+        Self {
+            step: 30,
+            digits: 6,
+        }
+    }
+}
+
+impl TotpParams {
+    /// Generate the TOTP code of the secret at the given time in seconds
+    /// since the Unix epoch
+    pub fn code(&self, secret: &[u8], time: u64) -> String {
+        self.code_at_counter(secret, time / self.step)
+    }
+
+    /// Check that the code is the TOTP code of the secret at the given time,
+    /// or at most `window` time steps before or after it, to tolerate clock
+    /// skew
+    pub fn verify(
+        &self,
+        secret: &[u8],
+        code: &str,
+        time: u64,
+        window: u64,
+    ) -> bool {
+        let counter = time / self.step;
+        let first = counter.saturating_sub(window);
+        let last = counter.saturating_add(window);
+        (first..=last).any(|counter| {
+            let expected =
+                Zeroizing::new(self.code_at_counter(secret, counter));
+            orion::util::secure_cmp(expected.as_bytes(), code.as_bytes())
+                .is_ok()
+        })
+    }
+
+    /// The HOTP code (RFC 4226) of the secret for the given counter
+    fn code_at_counter(&self, secret: &[u8], counter: u64) -> String {
+        use hmac::{Hmac, Mac, NewMac};
+
+        let mut mac = Hmac::<sha1::Sha1>::new_from_slice(secret)
+            .expect("HMAC accepts keys of any length");
+        mac.update(&counter.to_be_bytes());
+        let hash = mac.finalize().into_bytes();
+        let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+        let mut truncated = [0; 4];
+        truncated.copy_from_slice(&hash[offset..offset + 4]);
+        let truncated = u32::from_be_bytes(truncated) & 0x7fff_ffff;
+        let code = u64::from(truncated) % 10_u64.pow(self.digits);
+        format!("{:0width$}", code, width = self.digits as usize)
+    }
+}
+
+/// Domain separator of the derivation of the encryption key from the TOTP
+/// secret
+const TOTP_KEY_DOMAIN: &[u8] = b"anoma-wallet:totp:v1";
+
+/// Domain separator of the hashes of machine fingerprints
+const MACHINE_BINDING_DOMAIN: &[u8] = b"anoma-wallet:machine-binding:v1";
+
+/// Domain separator of the derivation of the escrow wrapping key
+const ESCROW_KEY_DOMAIN: &[u8] = b"anoma-wallet:escrow:v1";
+/// The HKDF salt of application keys derivation
+const APP_KEY_DOMAIN: &[u8] = b"anoma-wallet:app-key:v1";
+
+/// The length of the secret of a [`RecoveryCode`]
+const RECOVERY_SECRET_LEN: usize = 20;
+/// The length of the checksum of a [`RecoveryCode`]
+const RECOVERY_CHECKSUM_LEN: usize = 2;
+/// The number of characters in each dash-separated group of a formatted
+/// [`RecoveryCode`]
+const RECOVERY_CODE_GROUP_LEN: usize = 4;
+/// The recovery secret has a high entropy, so the key derived from it doesn't
+/// need the cost of a password KDF.
+const RECOVERY_KDF_PARAMS: KdfParams = KdfParams {
+    iterations: 3,
+    memory: 8,
+};
+
+/// A high-entropy secret that can decrypt a keypair encrypted with
+/// [`EncryptedKeypair::new_with_recovery`] in place of its password. It is
+/// formatted for printing as groups of uppercase hex digits ending with a
+/// checksum, e.g. `1A2B-3C4D-...`. A keypair whose password and recovery
+/// code are both lost cannot be decrypted.
+pub struct RecoveryCode {
+    secret: Zeroizing<Vec<u8>>,
+}
+
+impl RecoveryCode {
+    /// Generate a new random recovery code
+    fn generate() -> Self {
+        use rand::rngs::OsRng;
+        use rand::RngCore;
+        let mut secret = Zeroizing::new(vec![0; RECOVERY_SECRET_LEN]);
+        OsRng.fill_bytes(&mut secret);
+        Self { secret }
+    }
+
+    /// The checksum of the secret
+    fn checksum(secret: &[u8]) -> [u8; RECOVERY_CHECKSUM_LEN] {
+        use sha2::{Digest, Sha256};
+        let hash = Sha256::digest(secret);
+        let mut checksum = [0; RECOVERY_CHECKSUM_LEN];
+        checksum.copy_from_slice(&hash[..RECOVERY_CHECKSUM_LEN]);
+        checksum
+    }
+
+    /// The encryption key derived from this recovery code
+    fn encryption_key(
+        &self,
+        salt: &kdf::Salt,
+    ) -> Result<kdf::SecretKey, orion::errors::UnknownCryptoError> {
+        encryption_key(salt, hex::encode(&*self.secret), &RECOVERY_KDF_PARAMS)
+    }
+}
+
+impl Display for RecoveryCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let bytes = Zeroizing::new(
+            [&self.secret[..], &Self::checksum(&self.secret)[..]].concat(),
+        );
+        let digits = Zeroizing::new(hex::encode_upper(&*bytes));
+        let groups: Vec<&str> = digits
+            .as_bytes()
+            .chunks(RECOVERY_CODE_GROUP_LEN)
+            .map(|group| std::str::from_utf8(group).unwrap())
+            .collect();
+        write!(f, "{}", groups.join("-"))
+    }
+}
+
+impl std::fmt::Debug for RecoveryCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("RecoveryCode(<redacted>)")
+    }
+}
+
+impl FromStr for RecoveryCode {
+    type Err = ParseRecoveryCodeError;
+
+    /// Parse a recovery code, ignoring the case, dashes and whitespace
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digits: Zeroizing<String> = Zeroizing::new(
+            s.chars()
+                .filter(|c| *c != '-' && !c.is_whitespace())
+                .collect(),
+        );
+        let bytes = hex::decode(&*digits)
+            .map(Zeroizing::new)
+            .map_err(ParseRecoveryCodeError::InvalidHex)?;
+        if bytes.len() != RECOVERY_SECRET_LEN + RECOVERY_CHECKSUM_LEN {
+            return Err(ParseRecoveryCodeError::InvalidLength);
+        }
+        let (secret, checksum) = bytes.split_at(RECOVERY_SECRET_LEN);
+        if checksum != &Self::checksum(secret)[..] {
+            return Err(ParseRecoveryCodeError::ChecksumMismatch);
+        }
+        Ok(Self {
+            secret: Zeroizing::new(secret.to_vec()),
+        })
+    }
+}
+
+#[allow(missing_docs)]
+#[derive(Error, Debug)]
+pub enum ParseRecoveryCodeError {
+    #[error("Invalid recovery code hex: {0}")]
+    InvalidHex(hex::FromHexError),
+    #[error("The recovery code has an invalid length")]
+    InvalidLength,
+    #[error("The recovery code checksum doesn't match. Is there a typo?")]
+    ChecksumMismatch,
+}
+
+/// A key management service, e.g. AWS or GCP KMS, that can wrap and unwrap
+/// the data keys of encrypted keypairs
+pub trait KmsBackend {
+    /// The identifier of the KMS key used for wrapping
+    fn key_id(&self) -> &str;
+    /// The region of the KMS key, if applicable
+    fn region(&self) -> Option<&str>;
+    /// Wrap (encrypt) the given plaintext
+    fn wrap(&self, plaintext: &[u8]) -> Result<Vec<u8>, KmsError>;
+    /// Unwrap (decrypt) the given ciphertext
+    fn unwrap(&self, ciphertext: &[u8]) -> Result<Vec<u8>, KmsError>;
+}
+
+/// An error reported by a [`KmsBackend`]
+#[derive(Error, Debug)]
+#[error("KMS error: {0}")]
+pub struct KmsError(pub String);
 
 impl Display for EncryptedKeypair {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", hex::encode(&self.0))
+        let bytes = self
+            .try_to_vec()
+            .expect("Serializing encrypted keypair shouldn't fail");
+        write!(f, "{}", hex::encode(bytes))
     }
 }
 
 impl FromStr for EncryptedKeypair {
-    type Err = hex::FromHexError;
+    type Err = ParseEncryptedKeypairError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        hex::decode(s).map(Self)
+        let bytes =
+            hex::decode(s).map_err(ParseEncryptedKeypairError::InvalidHex)?;
+        Self::try_from_slice(&bytes)
+            .map_err(ParseEncryptedKeypairError::InvalidEncoding)
     }
 }
 
+#[allow(missing_docs)]
+#[derive(Error, Debug)]
+pub enum ParseEncryptedKeypairError {
+    #[error("Invalid encrypted keypair hex: {0}")]
+    InvalidHex(hex::FromHexError),
+    #[error("Invalid encrypted keypair encoding: {0}")]
+    InvalidEncoding(std::io::Error),
+    #[error("Missing or invalid field \"{0}\" in the encrypted keypair JSON")]
+    InvalidJson(&'static str),
+    #[error("Unsupported encrypted keypair content type: {0}")]
+    ContentTypeMismatch(String),
+}
+
 #[allow(missing_docs)]
 #[derive(Debug, Error)]
 pub enum DecryptionError {
@@ -129,8 +1025,72 @@ pub enum DecryptionError {
     DecryptionError,
     #[error("Unable to deserialize the keypair")]
     DeserializingError,
+    #[error("The decrypted keypair doesn't match the stored public key")]
+    PublicKeyMismatch,
     #[error("Asked not to decrypt")]
     NotDecrypting,
+    #[error("The keypair must be decrypted with the KMS key {0}")]
+    KmsRequired(String),
+    #[error("The keypair is not wrapped with a KMS key")]
+    NotKmsWrapped,
+    #[error("The keypair is wrapped with a different KMS key {0}")]
+    KmsKeyMismatch(String),
+    #[error("{0}")]
+    Kms(KmsError),
+    #[error("Invalid key derivation parameters {0:?}")]
+    InvalidKdfParams(KdfParams),
+    #[error("The keypair cannot be recovered with a recovery code")]
+    NotRecoverable,
+    #[error("The keypair is not escrowed")]
+    NotEscrowed,
+    #[error("The keypair is escrowed with a different auditor key")]
+    EscrowKeyMismatch,
+    #[error(
+        "The keypair is bound to another machine and can't be decrypted on \
+         this one"
+    )]
+    MachineBindingMismatch,
+    #[error("Unable to get the machine fingerprint: {0}")]
+    MachineFingerprint(std::io::Error),
+    #[error("The key is watch-only, its secret key is not in the wallet")]
+    WatchOnly,
+    #[error("The keypair requires a TOTP code to be decrypted")]
+    TotpRequired,
+    #[error(
+        "The keypair is under dual control and requires the passwords of both \
+         authorizers to be decrypted"
+    )]
+    InsufficientAuthorizers,
+    #[error("The keypair is not under dual control")]
+    NotDualControl,
+    #[cfg(feature = "pq")]
+    #[error("The keypair is not a hybrid keypair")]
+    NotHybrid,
+    #[error("The keypair is not protected with a TOTP")]
+    NotTotpProtected,
+    #[error(
+        "The TOTP code is wrong or expired. Is the clock of this machine \
+         correct?"
+    )]
+    InvalidTotpCode,
+}
+
+#[allow(missing_docs)]
+#[derive(Error, Debug)]
+pub enum KeyMismatchError {
+    #[error(
+        "The keypair has no public key readable without decryption to check \
+         against the pinned key"
+    )]
+    MissingPublicKey,
+    #[error(
+        "The public key {found} doesn't match the pinned public key {pinned}. \
+         The keypair may have been substituted."
+    )]
+    Mismatch {
+        pinned: common::PublicKey,
+        found: common::PublicKey,
+    },
 }
 
 impl StoredKeypair {
@@ -140,12 +1100,24 @@ impl StoredKeypair {
     pub fn new(
         keypair: common::SecretKey,
         password: Option<String>,
+    ) -> (Self, Rc<common::SecretKey>) {
+        Self::new_with_kdf_params(keypair, password, &KdfParams::default())
+    }
+
+    /// Construct a keypair for storage like [`StoredKeypair::new`], but derive
+    /// the encryption key from the password with the given parameters.
+    pub fn new_with_kdf_params(
+        keypair: common::SecretKey,
+        password: Option<String>,
+        kdf_params: &KdfParams,
     ) -> (Self, Rc<common::SecretKey>) {
         match password {
             Some(password) => {
                 let keypair = Rc::new(keypair);
                 (
-                    Self::Encrypted(EncryptedKeypair::new(&keypair, password)),
+                    Self::Encrypted(EncryptedKeypair::new_with_kdf_params(
+                        &keypair, password, kdf_params,
+                    )),
                     keypair,
                 )
             }
@@ -158,7 +1130,8 @@ impl StoredKeypair {
 
     /// Get a raw keypair from a stored keypair. If the keypair is encrypted and
     /// no password is provided in the argument, a password will be prompted
-    /// from stdin.
+    /// from stdin. The wallet's duress password isn't checked, the keys of a
+    /// wallet should be decrypted with [`super::Wallet::unlock_key`] instead.
     pub fn get(
         &self,
         decrypt: bool,
@@ -177,33 +1150,1063 @@ impl StoredKeypair {
                 }
             }
             StoredKeypair::Raw(keypair) => Ok(keypair.clone()),
+            StoredKeypair::WatchOnly(_) => Err(DecryptionError::WatchOnly),
+        }
+    }
+
+    /// Get both secret keys of a stored hybrid keypair. If no password is
+    /// provided in the argument, a password will be prompted from stdin.
+    #[cfg(feature = "pq")]
+    pub fn get_hybrid(
+        &self,
+        password: Option<String>,
+    ) -> Result<HybridSecretKey, DecryptionError> {
+        match self {
+            StoredKeypair::Encrypted(encrypted) if encrypted.is_hybrid() => {
+                let password = password.unwrap_or_else(|| {
+                    read_password("Enter decryption password: ")
+                });
+                encrypted.decrypt_hybrid(password)
+            }
+            _ => Err(DecryptionError::NotHybrid),
         }
     }
 
     pub fn is_encrypted(&self) -> bool {
         match self {
             StoredKeypair::Encrypted(_) => true,
-            StoredKeypair::Raw(_) => false,
+            StoredKeypair::Raw(_) | StoredKeypair::WatchOnly(_) => false,
         }
     }
-}
 
-impl EncryptedKeypair {
-    /// Encrypt a keypair and store it with its salt.
-    pub fn new(keypair: &common::SecretKey, password: String) -> Self {
-        let salt = encryption_salt();
-        let encryption_key = encryption_key(&salt, password);
-
-        let data = keypair
-            .try_to_vec()
-            .expect("Serializing keypair shouldn't fail");
+    /// Returns `true` if only the public key is stored
+    pub fn is_watch_only(&self) -> bool {
+        matches!(self, StoredKeypair::WatchOnly(_))
+    }
 
-        let encrypted_keypair = aead::seal(&encryption_key, &data)
-            .expect("Encryption of data shouldn't fail");
+    /// The version of the format of this keystore, as in
+    /// [`EncryptedKeypair::format_version`]. Raw and watch-only keypairs have
+    /// no keystore format, so this is `None` for them.
+    pub fn format_version(&self) -> Option<u8> {
+        match self {
+            StoredKeypair::Encrypted(encrypted) => {
+                Some(encrypted.format_version())
+            }
+            _ => None,
+        }
+    }
+
+    /// The version of the software that created this keystore, if known. Raw
+    /// keypairs are stored without this metadata, so this is always `None`
+    /// for them.
+    pub fn created_by_version(&self) -> Option<&str> {
+        match self {
+            StoredKeypair::Encrypted(encrypted) => {
+                encrypted.created_by_version()
+            }
+            _ => None,
+        }
+    }
+
+    /// Report which metadata fields of this keypair are protected against
+    /// tampering. Raw keypairs have no metadata and the public key of a
+    /// watch-only keypair can't be checked against anything.
+    pub fn metadata_protection(&self) -> MetadataProtection {
+        match self {
+            StoredKeypair::Encrypted(encrypted) => {
+                encrypted.metadata_protection()
+            }
+            StoredKeypair::Raw(_) => MetadataProtection::absent(),
+            StoredKeypair::WatchOnly(_) => MetadataProtection {
+                public_key: FieldProtection::Unauthenticated,
+                ..MetadataProtection::absent()
+            },
+        }
+    }
+
+    /// The random nonce drawn when this keypair was encrypted, to tell apart
+    /// the keystores of the same keypair. Raw and watch-only keypairs are
+    /// stored without this metadata, so this is always `None` for them.
+    pub fn instance_id(&self) -> Option<[u8; 16]> {
+        match self {
+            StoredKeypair::Encrypted(encrypted) => encrypted.instance_id(),
+            _ => None,
+        }
+    }
+
+    /// Check that this keypair was encrypted more than `max_age` before
+    /// `now`, in seconds since the Unix epoch, e.g. to have it re-encrypted
+    /// or rotated. An encrypted keypair whose time of encryption is unknown
+    /// predates its recording and is always too old. Raw and watch-only
+    /// keypairs are not encrypted, so they're never too old.
+    pub fn is_older_than(&self, max_age: Duration, now: u64) -> bool {
+        match self {
+            StoredKeypair::Encrypted(encrypted) => match encrypted.created_at()
+            {
+                Some(created_at) => {
+                    now.saturating_sub(created_at) > max_age.as_secs()
+                }
+                None => true,
+            },
+            _ => false,
+        }
+    }
+
+    /// The public key of this keypair, if it can be obtained without
+    /// decryption.
+    pub fn public_key(&self) -> Option<common::PublicKey> {
+        match self {
+            StoredKeypair::Encrypted(encrypted) => encrypted.public_key(),
+            StoredKeypair::Raw(raw) => Some(raw.ref_to()),
+            StoredKeypair::WatchOnly(pk) => Some(pk.clone()),
+        }
+    }
+
+    /// Check that the public key of this keypair is the pinned public key, to
+    /// detect the substitution of the keypair by another one. Only the public
+    /// key readable without decryption is used, so an encrypted keypair
+    /// written before its public key was being recorded can't be checked.
+    pub fn verify_against_pinned(
+        &self,
+        pinned: &common::PublicKey,
+    ) -> Result<(), KeyMismatchError> {
+        match self.public_key() {
+            Some(found) if &found == pinned => Ok(()),
+            Some(found) => Err(KeyMismatchError::Mismatch {
+                pinned: pinned.clone(),
+                found,
+            }),
+            None => Err(KeyMismatchError::MissingPublicKey),
+        }
+    }
+
+    /// Check that this keypair, once written out and parsed back, decrypts
+    /// with the password to the original keypair, to catch an undecryptable
+    /// keystore before it's saved in place of the only copy of the keypair.
+    /// A watch-only keypair never round-trips, as it has no secret key.
+    pub fn verify_roundtrip(
+        &self,
+        password: &str,
+        original: &common::SecretKey,
+    ) -> bool {
+        let parsed = match Self::from_str(&Zeroizing::new(self.to_string())) {
+            Ok(parsed) => parsed,
+            Err(_) => return false,
+        };
+        let decrypted = match parsed {
+            StoredKeypair::Encrypted(encrypted) => {
+                encrypted.decrypt(password.to_owned()).map(Rc::new)
+            }
+            StoredKeypair::Raw(keypair) => Ok(keypair),
+            StoredKeypair::WatchOnly(_) => return false,
+        };
+        matches!(decrypted, Ok(keypair) if keypair.ref_to() == original.ref_to())
+    }
+
+    /// A full-width identifier of this keypair to sort keys in the same order
+    /// on every device, the [`SortablePublicKey::stable_id`] of its public key,
+    /// if the public key is available without decryption.
+    pub fn stable_id(&self) -> Option<[u8; 32]> {
+        self.public_key()
+            .map(|pk| SortablePublicKey(pk).stable_id())
+    }
+
+    /// A filesystem-safe name for this keypair derived from the implicit
+    /// address of its public key, if the public key is available without
+    /// decryption.
+    pub fn canonical_filename(&self) -> Option<String> {
+        self.public_key().map(|pk| {
+            let pkh = PublicKeyHash::from(&pk);
+            Address::Implicit(ImplicitAddress(pkh)).encode()
+        })
+    }
+
+    /// The SHA-256 hash of the serialized form of this keypair, as written to a
+    /// file, to be kept in a trusted location and checked on load by
+    /// [`StoredKeypair::load_from_file`]. No decryption is needed.
+    pub fn commitment(&self) -> [u8; 32] {
+        use sha2::{Digest, Sha256};
+
+        let serialized = Zeroizing::new(self.to_string());
+        Sha256::digest(serialized.as_bytes()).into()
+    }
+
+    /// Read a keypair from a file, as written by
+    /// [`StoredKeypair::save_to_dir`]. The keypair must match the checksum in
+    /// the file, if any, to detect its corruption. If a commitment is given,
+    /// the keypair must match it too, to detect the tampering or substitution
+    /// of the file.
+    pub fn load_from_file(
+        path: &Path,
+        commitment: Option<&[u8; 32]>,
+    ) -> Result<Self, LoadKeypairError> {
+        let content = Zeroizing::new(
+            fs::read_to_string(path).map_err(LoadKeypairError::Read)?,
+        );
+        let (serialized, checksum) = split_key_file(&content);
+        if checksum.map_or(false, |checksum| {
+            !key_file_checksum_matches(serialized, checksum)
+        }) {
+            return Err(LoadKeypairError::ChecksumMismatch);
+        }
+        let keypair = Self::from_str(serialized)
+            .map_err(LoadKeypairError::Deserialize)?;
+        match commitment {
+            Some(commitment) if &keypair.commitment() != commitment => {
+                Err(LoadKeypairError::CommitmentMismatch)
+            }
+            _ => Ok(keypair),
+        }
+    }
+
+    /// Write this keypair into the given directory to a file named
+    /// `<canonical_filename>.key`, followed by a line with its checksum.
+    /// Returns the path of the written file.
+    pub fn save_to_dir(&self, dir: &Path) -> std::io::Result<PathBuf> {
+        let file_name = self.canonical_filename().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "The keypair has no public key to derive a file name from",
+            )
+        })?;
+        fs::create_dir_all(dir)?;
+        let path = dir.join(format!("{}.{}", file_name, KEY_FILE_EXTENSION));
+        let serialized = Zeroizing::new(self.to_string());
+        let content = Zeroizing::new(format!(
+            "{}\n{}{}\n",
+            *serialized,
+            KEY_FILE_CHECKSUM_PREFIX,
+            key_file_checksum(&serialized)
+        ));
+        fs::write(&path, content.as_bytes())?;
+        Ok(path)
+    }
+}
+
+impl EncryptedKeypair {
+    /// Encrypt a keypair and store it with its salt.
+    pub fn new(keypair: &common::SecretKey, password: String) -> Self {
+        Self::new_with_kdf_params(keypair, password, &KdfParams::default())
+    }
+
+    /// Encrypt a keypair with a key derived from the password with the given
+    /// parameters and store it with its salt and the parameters.
+    ///
+    /// Panics if the parameters are invalid.
+    pub fn new_with_kdf_params(
+        keypair: &common::SecretKey,
+        password: String,
+        kdf_params: &KdfParams,
+    ) -> Self {
+        Self::new_with_sealer(
+            keypair,
+            password,
+            encryption_salt(),
+            kdf_params,
+            KeystoreHeader::new(),
+            seal_with_ad,
+        )
+    }
+
+    /// Encrypt a keypair like [`EncryptedKeypair::new_with_kdf_params`], but
+    /// with the given salt and AEAD nonce instead of random ones and without
+    /// an instance ID, a time of encryption or the version that created it,
+    /// so that the encrypted keypair is deterministic for golden tests of the
+    /// format.
+    ///
+    /// A fixed salt and nonce must never be used in production: the same
+    /// password then always derives the same key, which can be precomputed,
+    /// and reusing a nonce with a key breaks the encryption.
+    ///
+    /// Panics if the parameters are invalid.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn new_with_salt(
+        keypair: &common::SecretKey,
+        password: String,
+        salt: [u8; 16],
+        nonce: [u8; 24],
+        kdf_params: &KdfParams,
+    ) -> Self {
+        let salt = kdf::Salt::from_slice(&salt)
+            .expect("A 16 bytes salt should be valid");
+        let nonce = xchacha20poly1305::Nonce::from_slice(&nonce)
+            .expect("A 24 bytes nonce should be valid");
+        Self::new_with_sealer(
+            keypair,
+            password,
+            salt,
+            kdf_params,
+            KeystoreHeader::default(),
+            |key, data, ad| seal_with_nonce(key, &nonce, data, ad),
+        )
+    }
+
+    /// Encrypt a keypair with a key derived from the password and the given
+    /// salt, using the given function to seal the serialized keypair with the
+    /// associated data of the header. The fields of the header about the
+    /// keypair and the key derivation are set, the others are the given ones.
+    fn new_with_sealer(
+        keypair: &common::SecretKey,
+        password: String,
+        salt: kdf::Salt,
+        kdf_params: &KdfParams,
+        header: KeystoreHeader,
+        seal: impl FnOnce(&aead::SecretKey, &[u8], &[u8]) -> Vec<u8>,
+    ) -> Self {
+        let plaintext = keypair
+            .try_to_vec()
+            .map(Zeroizing::new)
+            .expect("Serializing keypair shouldn't fail");
+        Self::new_with_plaintext(
+            keypair.ref_to(),
+            plaintext,
+            password,
+            salt,
+            kdf_params,
+            header,
+            seal,
+        )
+    }
+
+    /// Encrypt the plaintext of a keypair with the given public key like
+    /// [`EncryptedKeypair::new_with_sealer`]
+    fn new_with_plaintext(
+        public_key: common::PublicKey,
+        plaintext: Zeroizing<Vec<u8>>,
+        password: String,
+        salt: kdf::Salt,
+        kdf_params: &KdfParams,
+        header: KeystoreHeader,
+        seal: impl FnOnce(&aead::SecretKey, &[u8], &[u8]) -> Vec<u8>,
+    ) -> Self {
+        let encryption_key = encryption_key(&salt, password, kdf_params)
+            .expect("Generation of encryption secret key shouldn't fail");
+
+        let (data, compression) = maybe_compress(plaintext);
+
+        let header = KeystoreHeader {
+            public_key: Some(public_key),
+            kdf_params: Some(*kdf_params),
+            compression,
+            salt_len: Some(salt.len() as u32),
+            ..header
+        };
+
+        let encrypted_keypair =
+            seal(&encryption_key, &data, &header.associated_data());
 
         let encrypted_data = [salt.as_ref(), &encrypted_keypair].concat();
 
-        Self(encrypted_data)
+        Self {
+            header,
+            data: encrypted_data,
+        }
+    }
+
+    /// Encrypt a keypair like [`EncryptedKeypair::new_with_kdf_params`], but
+    /// also derive the encryption key from the fingerprint of this machine, so
+    /// that the keypair can only be decrypted on it. Moving the keystore to
+    /// new hardware requires re-provisioning the key on it, e.g. from a
+    /// backup of the secret key.
+    pub fn new_bound_to_machine(
+        keypair: &common::SecretKey,
+        password: String,
+        kdf_params: &KdfParams,
+        machine: &impl MachineFingerprint,
+    ) -> std::io::Result<Self> {
+        let fingerprint = Zeroizing::new(machine.fingerprint()?);
+        let password = MachineBinding::bind_password(password, &fingerprint);
+        let header = KeystoreHeader {
+            machine_binding: Some(MachineBinding::new(&fingerprint)),
+            ..KeystoreHeader::new()
+        };
+        Ok(Self::new_with_sealer(
+            keypair,
+            password,
+            encryption_salt(),
+            kdf_params,
+            header,
+            seal_with_ad,
+        ))
+    }
+
+    /// Encrypt a hybrid keypair like [`EncryptedKeypair::new_with_kdf_params`].
+    /// The public key of the keystore is its Ed25519 public key and its
+    /// post-quantum public key is recorded in the header too, so that both
+    /// are readable without decryption. Both secret keys are encrypted
+    /// together with a single key derivation.
+    ///
+    /// Panics if the parameters are invalid.
+    #[cfg(feature = "pq")]
+    pub fn new_hybrid(
+        keypair: &HybridSecretKey,
+        password: String,
+        kdf_params: &KdfParams,
+    ) -> Self {
+        let header = KeystoreHeader {
+            post_quantum: Some(keypair.post_quantum_key()),
+            ..KeystoreHeader::new()
+        };
+        Self::new_with_plaintext(
+            keypair.ed25519.ref_to(),
+            keypair.to_plaintext(),
+            password,
+            encryption_salt(),
+            kdf_params,
+            header,
+            seal_with_ad,
+        )
+    }
+
+    /// Decrypt both secret keys of a hybrid keypair encrypted by
+    /// [`EncryptedKeypair::new_hybrid`], whereas
+    /// [`EncryptedKeypair::decrypt`] only returns its Ed25519 key
+    #[cfg(feature = "pq")]
+    pub fn decrypt_hybrid(
+        &self,
+        password: String,
+    ) -> Result<HybridSecretKey, DecryptionError> {
+        let post_quantum = self
+            .header
+            .post_quantum
+            .as_ref()
+            .ok_or(DecryptionError::NotHybrid)?;
+        let password =
+            self.bind_password(password, &MachineIdFile::default())?;
+        let keys = self.decrypt_keys(password, self.salt_len())?;
+        let post_quantum_secret = keys
+            .post_quantum
+            .as_ref()
+            .ok_or(DecryptionError::DeserializingError)?;
+        HybridSecretKey::from_parts(
+            keys.ed25519,
+            post_quantum_secret,
+            post_quantum,
+        )
+    }
+
+    /// Returns `true` if this is a hybrid keypair of an Ed25519 key and a
+    /// post-quantum key
+    pub fn is_hybrid(&self) -> bool {
+        self.header.post_quantum.is_some()
+    }
+
+    /// Encrypt a keypair with a key derived from both the password and the
+    /// given TOTP secret, so that decrypting it with
+    /// [`EncryptedKeypair::decrypt_with_totp`] requires the password and a
+    /// current TOTP code. See [`TotpWrapping`] for what it protects against.
+    ///
+    /// Panics if the KDF parameters are invalid.
+    pub fn new_with_totp(
+        keypair: &common::SecretKey,
+        password: String,
+        totp_secret: &[u8],
+        totp_params: TotpParams,
+        kdf_params: &KdfParams,
+    ) -> Self {
+        let salt = encryption_salt();
+        let password_key = encryption_key(&salt, password, kdf_params)
+            .expect("Generation of encryption secret key shouldn't fail");
+        let encrypted_secret = aead::seal(&password_key, totp_secret)
+            .expect("Encryption of data shouldn't fail");
+
+        let (data, compression) = serialize_keypair(keypair);
+        let header = KeystoreHeader {
+            public_key: Some(keypair.ref_to()),
+            kdf_params: Some(*kdf_params),
+            totp: Some(TotpWrapping {
+                params: totp_params,
+                encrypted_secret,
+            }),
+            compression,
+            salt_len: Some(salt.len() as u32),
+            ..KeystoreHeader::new()
+        };
+        let encrypted_keypair = seal_with_ad(
+            &totp_key(&password_key, totp_secret),
+            &data,
+            &header.associated_data(),
+        );
+        Self {
+            header,
+            data: [salt.as_ref(), &encrypted_keypair].concat(),
+        }
+    }
+
+    /// Decrypt a keypair encrypted by [`EncryptedKeypair::new_with_totp`] with
+    /// the password and a TOTP code valid at the given time in seconds since
+    /// the Unix epoch, or at most `window` time steps before or after it.
+    pub fn decrypt_with_totp(
+        &self,
+        password: String,
+        code: &str,
+        now: u64,
+        window: u64,
+    ) -> Result<common::SecretKey, DecryptionError> {
+        let totp = self
+            .header
+            .totp
+            .as_ref()
+            .ok_or(DecryptionError::NotTotpProtected)?;
+        let salt_len = self.salt_len();
+        if self.data.len() < salt_len {
+            return Err(DecryptionError::BadSalt);
+        }
+        let (raw_salt, cipher) = self.data.split_at(salt_len);
+        let salt = kdf::Salt::from_slice(raw_salt)
+            .map_err(|_| DecryptionError::BadSalt)?;
+        let kdf_params = self.header.kdf_params.unwrap_or_default();
+        let password_key = encryption_key(&salt, password, &kdf_params)
+            .map_err(|_| DecryptionError::InvalidKdfParams(kdf_params))?;
+
+        // A wrong password fails to decrypt the TOTP secret
+        let totp_secret = aead::open(&password_key, &totp.encrypted_secret)
+            .map(Zeroizing::new)
+            .map_err(|_| DecryptionError::DecryptionError)?;
+        if !totp.params.verify(&totp_secret, code, now, window) {
+            return Err(DecryptionError::InvalidTotpCode);
+        }
+        self.open(&totp_key(&password_key, &totp_secret), cipher)
+    }
+
+    /// Encrypt a keypair with the given data key and wrap the data key with a
+    /// key derived from the password with the given parameters. The data key
+    /// may be wrapped some other way too in the given header, which is
+    /// authenticated with the keypair.
+    fn new_with_data_key(
+        keypair: &common::SecretKey,
+        password: String,
+        data_key: &aead::SecretKey,
+        kdf_params: &KdfParams,
+        header: KeystoreHeader,
+    ) -> Self {
+        let salt = encryption_salt();
+        let password_key = encryption_key(&salt, password, kdf_params)
+            .expect("Generation of encryption secret key shouldn't fail");
+        let password_wrapped_data_key =
+            aead::seal(&password_key, data_key.unprotected_as_bytes())
+                .expect("Encryption of data shouldn't fail");
+
+        let (data, compression) = serialize_keypair(keypair);
+
+        let header = KeystoreHeader {
+            public_key: Some(keypair.ref_to()),
+            kdf_params: Some(*kdf_params),
+            password_wrapped_data_key: Some(password_wrapped_data_key),
+            compression,
+            salt_len: Some(salt.len() as u32),
+            ..header
+        };
+        let encrypted_keypair =
+            seal_with_ad(data_key, &data, &header.associated_data());
+
+        Self {
+            header,
+            data: [salt.as_ref(), &encrypted_keypair].concat(),
+        }
+    }
+
+    /// Encrypt a keypair with a random data key and wrap the data key both
+    /// with a key derived from the password and with a key derived from a new
+    /// recovery code. The keypair can then be decrypted with either of them.
+    /// The password key is derived with the given parameters.
+    ///
+    /// Panics if the parameters are invalid.
+    pub fn new_with_recovery(
+        keypair: &common::SecretKey,
+        password: String,
+        kdf_params: &KdfParams,
+    ) -> (Self, RecoveryCode) {
+        let data_key = aead::SecretKey::default();
+        let recovery_code = RecoveryCode::generate();
+
+        let recovery_salt = encryption_salt();
+        let recovery_key = recovery_code
+            .encryption_key(&recovery_salt)
+            .expect("Generation of encryption secret key shouldn't fail");
+        let recovery_wrapped_data_key =
+            aead::seal(&recovery_key, data_key.unprotected_as_bytes())
+                .expect("Encryption of data shouldn't fail");
+
+        let header = KeystoreHeader {
+            recovery: Some(RecoveryWrapping {
+                recovery_salt: recovery_salt.as_ref().to_vec(),
+                recovery_wrapped_data_key,
+            }),
+            ..KeystoreHeader::new()
+        };
+        let keypair = Self::new_with_data_key(
+            keypair, password, &data_key, kdf_params, header,
+        );
+        (keypair, recovery_code)
+    }
+
+    /// Decrypt a keypair encrypted with
+    /// [`EncryptedKeypair::new_with_recovery`] using its recovery code instead
+    /// of the password
+    pub fn decrypt_with_recovery(
+        &self,
+        code: &RecoveryCode,
+    ) -> Result<common::SecretKey, DecryptionError> {
+        let recovery = self
+            .header
+            .recovery
+            .as_ref()
+            .ok_or(DecryptionError::NotRecoverable)?;
+        let salt = kdf::Salt::from_slice(&recovery.recovery_salt)
+            .map_err(|_| DecryptionError::BadSalt)?;
+        let recovery_key = code
+            .encryption_key(&salt)
+            .map_err(|_| DecryptionError::DecryptionError)?;
+        let data_key = unwrap_data_key(
+            &recovery_key,
+            &recovery.recovery_wrapped_data_key,
+        )?;
+        let salt_len = self.salt_len();
+        if self.data.len() < salt_len {
+            return Err(DecryptionError::BadSalt);
+        }
+        self.open(&data_key, &self.data[salt_len..])
+    }
+
+    /// Encrypt a keypair in escrow: the random data key is wrapped both with a
+    /// key derived from the password and to the X25519 public key of an
+    /// auditor, who can then decrypt the keypair with
+    /// [`EncryptedKeypair::decrypt_as_auditor`] without the password. The
+    /// auditor's public key is recorded in the cleartext header, so that the
+    /// escrow is visible to the key's owner. The password key is derived with
+    /// the given parameters.
+    ///
+    /// Panics if the parameters are invalid.
+    pub fn new_with_escrow(
+        keypair: &common::SecretKey,
+        password: String,
+        auditor_public_key: &x25519_dalek::PublicKey,
+        kdf_params: &KdfParams,
+    ) -> Self {
+        use rand::rngs::OsRng;
+        use rand::RngCore;
+
+        let data_key = aead::SecretKey::default();
+
+        let mut ephemeral_secret = Zeroizing::new([0; 32]);
+        OsRng.fill_bytes(&mut *ephemeral_secret);
+        let ephemeral_secret =
+            x25519_dalek::StaticSecret::from(*ephemeral_secret);
+        let ephemeral_public_key =
+            x25519_dalek::PublicKey::from(&ephemeral_secret);
+        let escrow_key = escrow_key(
+            &ephemeral_secret.diffie_hellman(auditor_public_key),
+            &ephemeral_public_key,
+            auditor_public_key,
+        );
+        let wrapped_data_key =
+            aead::seal(&escrow_key, data_key.unprotected_as_bytes())
+                .expect("Encryption of data shouldn't fail");
+
+        let header = KeystoreHeader {
+            escrow: Some(EscrowWrapping {
+                auditor_public_key: *auditor_public_key.as_bytes(),
+                ephemeral_public_key: *ephemeral_public_key.as_bytes(),
+                wrapped_data_key,
+            }),
+            ..KeystoreHeader::new()
+        };
+        Self::new_with_data_key(
+            keypair, password, &data_key, kdf_params, header,
+        )
+    }
+
+    /// Decrypt an escrowed keypair with the auditor's X25519 secret key
+    pub fn decrypt_as_auditor(
+        &self,
+        auditor_secret: &x25519_dalek::StaticSecret,
+    ) -> Result<common::SecretKey, DecryptionError> {
+        let escrow = self
+            .header
+            .escrow
+            .as_ref()
+            .ok_or(DecryptionError::NotEscrowed)?;
+        let auditor_public_key = x25519_dalek::PublicKey::from(auditor_secret);
+        if auditor_public_key.as_bytes() != &escrow.auditor_public_key {
+            return Err(DecryptionError::EscrowKeyMismatch);
+        }
+        let ephemeral_public_key =
+            x25519_dalek::PublicKey::from(escrow.ephemeral_public_key);
+        let escrow_key = escrow_key(
+            &auditor_secret.diffie_hellman(&ephemeral_public_key),
+            &ephemeral_public_key,
+            &auditor_public_key,
+        );
+        let data_key = unwrap_data_key(&escrow_key, &escrow.wrapped_data_key)?;
+        let salt_len = self.salt_len();
+        if self.data.len() < salt_len {
+            return Err(DecryptionError::BadSalt);
+        }
+        self.open(&data_key, &self.data[salt_len..])
+    }
+
+    /// The X25519 public key of the auditor of an escrowed keypair
+    pub fn escrow_auditor(&self) -> Option<&[u8; 32]> {
+        self.header
+            .escrow
+            .as_ref()
+            .map(|escrow| &escrow.auditor_public_key)
+    }
+
+    /// Encrypt a keypair under dual control: the random data key is split
+    /// into two shares, wrapped with keys derived from the passwords of two
+    /// different authorizers, and
+    /// [`EncryptedKeypair::decrypt_with_authorizers`] requires both passwords
+    /// to recover the data key. The passwords should be chosen independently
+    /// by each authorizer, as a single person knowing both defeats the dual
+    /// control.
+    ///
+    /// Panics if the KDF parameters are invalid.
+    pub fn new_with_dual_control(
+        keypair: &common::SecretKey,
+        first_password: String,
+        second_password: String,
+        kdf_params: &KdfParams,
+    ) -> Self {
+        let data_key = aead::SecretKey::default();
+        let data_key_bytes = data_key.unprotected_as_bytes();
+        let first_share = aead::SecretKey::default();
+        let second_share: Zeroizing<Vec<u8>> = Zeroizing::new(
+            data_key_bytes
+                .iter()
+                .zip(first_share.unprotected_as_bytes())
+                .map(|(key, share)| key ^ share)
+                .collect(),
+        );
+        let wrap_share = |password: String, share: &[u8]| {
+            let salt = encryption_salt();
+            let key = encryption_key(&salt, password, kdf_params)
+                .expect("Generation of encryption secret key shouldn't fail");
+            AuthorizerShare {
+                salt: salt.as_ref().to_vec(),
+                wrapped_share: aead::seal(&key, share)
+                    .expect("Encryption of data shouldn't fail"),
+            }
+        };
+        let dual_control = DualControlWrapping {
+            first: wrap_share(
+                first_password,
+                first_share.unprotected_as_bytes(),
+            ),
+            second: wrap_share(second_password, &second_share),
+        };
+
+        let (data, compression) = serialize_keypair(keypair);
+        let header = KeystoreHeader {
+            public_key: Some(keypair.ref_to()),
+            kdf_params: Some(*kdf_params),
+            compression,
+            dual_control: Some(dual_control),
+            ..KeystoreHeader::new()
+        };
+        let encrypted_keypair =
+            seal_with_ad(&data_key, &data, &header.associated_data());
+        Self {
+            header,
+            data: encrypted_keypair,
+        }
+    }
+
+    /// Decrypt a keypair encrypted by
+    /// [`EncryptedKeypair::new_with_dual_control`] with the passwords of both
+    /// authorizers, in the order they were given at encryption. Fails with
+    /// [`DecryptionError::InsufficientAuthorizers`] if either password is
+    /// missing, before any key derivation.
+    pub fn decrypt_with_authorizers(
+        &self,
+        first_password: Option<String>,
+        second_password: Option<String>,
+    ) -> Result<common::SecretKey, DecryptionError> {
+        let dual_control = self
+            .header
+            .dual_control
+            .as_ref()
+            .ok_or(DecryptionError::NotDualControl)?;
+        let (first_password, second_password) =
+            match (first_password, second_password) {
+                (Some(first), Some(second)) => (first, second),
+                _ => return Err(DecryptionError::InsufficientAuthorizers),
+            };
+        let kdf_params = self.header.kdf_params.unwrap_or_default();
+        let unwrap_share = |share: &AuthorizerShare, password: String| {
+            let salt = kdf::Salt::from_slice(&share.salt)
+                .map_err(|_| DecryptionError::BadSalt)?;
+            let key = encryption_key(&salt, password, &kdf_params)
+                .map_err(|_| DecryptionError::InvalidKdfParams(kdf_params))?;
+            aead::open(&key, &share.wrapped_share)
+                .map(Zeroizing::new)
+                .map_err(|_| DecryptionError::DecryptionError)
+        };
+        let first_share = unwrap_share(&dual_control.first, first_password)?;
+        let second_share = unwrap_share(&dual_control.second, second_password)?;
+        if first_share.len() != second_share.len() {
+            return Err(DecryptionError::DecryptionError);
+        }
+        let data_key: Zeroizing<Vec<u8>> = Zeroizing::new(
+            first_share
+                .iter()
+                .zip(second_share.iter())
+                .map(|(first, second)| first ^ second)
+                .collect(),
+        );
+        let data_key = aead::SecretKey::from_slice(&data_key)
+            .map_err(|_| DecryptionError::DecryptionError)?;
+        self.open(&data_key, &self.data)
+    }
+
+    /// Encrypt a keypair with a random data key and wrap the data key with
+    /// the given KMS, instead of deriving the encryption key from a password.
+    pub fn new_kms(
+        keypair: &common::SecretKey,
+        kms: &impl KmsBackend,
+    ) -> Result<Self, KmsError> {
+        let data_key = aead::SecretKey::default();
+        let wrapped_data_key = kms.wrap(data_key.unprotected_as_bytes())?;
+
+        let (data, compression) = serialize_keypair(keypair);
+        let header = KeystoreHeader {
+            public_key: Some(keypair.ref_to()),
+            kms: Some(KmsWrapping {
+                key_id: kms.key_id().to_owned(),
+                region: kms.region().map(ToOwned::to_owned),
+                wrapped_data_key,
+            }),
+            compression,
+            ..KeystoreHeader::new()
+        };
+        let encrypted_keypair =
+            seal_with_ad(&data_key, &data, &header.associated_data());
+
+        Ok(Self {
+            header,
+            data: encrypted_keypair,
+        })
+    }
+
+    /// Decrypt a keypair whose data key is wrapped with the given KMS
+    pub fn decrypt_kms(
+        &self,
+        kms: &impl KmsBackend,
+    ) -> Result<common::SecretKey, DecryptionError> {
+        let wrapping = self
+            .header
+            .kms
+            .as_ref()
+            .ok_or(DecryptionError::NotKmsWrapped)?;
+        if wrapping.key_id != kms.key_id() {
+            return Err(DecryptionError::KmsKeyMismatch(
+                wrapping.key_id.clone(),
+            ));
+        }
+        let data_key = kms
+            .unwrap(&wrapping.wrapped_data_key)
+            .map(Zeroizing::new)
+            .map_err(DecryptionError::Kms)?;
+        let data_key = aead::SecretKey::from_slice(&data_key)
+            .map_err(|_| DecryptionError::DecryptionError)?;
+        self.open(&data_key, &self.data)
+    }
+
+    /// Parse an encrypted keypair in the format used before the
+    /// [`KeystoreHeader`] was introduced, which is just the hex encoded salt
+    /// followed by the encrypted keypair.
+    fn from_legacy_str(s: &str) -> Result<Self, hex::FromHexError> {
+        hex::decode(s).map(|data| Self {
+            header: KeystoreHeader::legacy(),
+            data,
+        })
+    }
+
+    /// The content type of this keystore as encoded by its `Display`
+    /// instance, which identifies the format version and the encoding
+    pub fn content_type(&self) -> &'static str {
+        KEYSTORE_CONTENT_TYPE
+    }
+
+    /// The version of the format needed to read this keystore: the
+    /// [`LEGACY_KEYSTORE_FORMAT_VERSION`] if it was read from the encoding
+    /// without a header and has no metadata, which older versions of the
+    /// wallet can still read until it's written again, and the
+    /// [`KEYSTORE_FORMAT_VERSION`] otherwise. Nothing is decrypted.
+    pub fn format_version(&self) -> u8 {
+        let header = self
+            .header
+            .try_to_vec()
+            .expect("Serializing keystore header shouldn't fail");
+        let legacy_header = KeystoreHeader::legacy()
+            .try_to_vec()
+            .expect("Serializing keystore header shouldn't fail");
+        if header == legacy_header {
+            LEGACY_KEYSTORE_FORMAT_VERSION
+        } else {
+            KEYSTORE_FORMAT_VERSION
+        }
+    }
+
+    /// The SHA-256 hash of the Borsh encoding of this keystore, i.e. of the
+    /// header and the encrypted data, to address it in a content-addressed
+    /// store. As the salt and the nonce are random, encrypting the same
+    /// keypair twice gives different hashes.
+    pub fn content_hash(&self) -> [u8; 32] {
+        use sha2::{Digest, Sha256};
+
+        let bytes = self
+            .try_to_vec()
+            .expect("Serializing encrypted keypair shouldn't fail");
+        Sha256::digest(&bytes).into()
+    }
+
+    /// Encode this keystore as a JSON object tagged with its content type
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "content_type": self.content_type(),
+            "data": self.to_string(),
+        })
+    }
+
+    /// Decode a keystore from a JSON object written by
+    /// [`EncryptedKeypair::to_json`]. The content type is checked before the
+    /// data is decoded.
+    pub fn from_json(
+        json: &serde_json::Value,
+    ) -> Result<Self, ParseEncryptedKeypairError> {
+        let content_type = json["content_type"]
+            .as_str()
+            .ok_or(ParseEncryptedKeypairError::InvalidJson("content_type"))?;
+        if content_type != KEYSTORE_CONTENT_TYPE {
+            return Err(ParseEncryptedKeypairError::ContentTypeMismatch(
+                content_type.to_owned(),
+            ));
+        }
+        let data = json["data"]
+            .as_str()
+            .ok_or(ParseEncryptedKeypairError::InvalidJson("data"))?;
+        Self::from_str(data)
+    }
+
+    /// The cleartext metadata of this keystore
+    pub fn header(&self) -> &KeystoreHeader {
+        &self.header
+    }
+
+    /// The version of the software that created this keystore, if known
+    pub fn created_by_version(&self) -> Option<&str> {
+        self.header.created_by_version.as_deref()
+    }
+
+    /// The random nonce drawn when this keystore was created, if known
+    pub fn instance_id(&self) -> Option<[u8; 16]> {
+        self.header.instance_id
+    }
+
+    /// The time the keypair was encrypted in seconds since the Unix epoch,
+    /// if known
+    pub fn created_at(&self) -> Option<u64> {
+        self.header.created_at
+    }
+
+    /// Report which fields of the header are protected against tampering.
+    /// See [`StoredKeypair::metadata_protection`]. All the fields of a
+    /// header are authenticated, except in a legacy keystore, where only the
+    /// ones that the decryption depends on or checks are.
+    pub fn metadata_protection(&self) -> MetadataProtection {
+        use FieldProtection::{Authenticated, Unauthenticated};
+
+        let header = &self.header;
+        let protection = |present: bool, protection| {
+            if !present {
+                FieldProtection::Absent
+            } else if header.legacy {
+                protection
+            } else {
+                Authenticated
+            }
+        };
+        MetadataProtection {
+            created_by_version: protection(
+                header.created_by_version.is_some(),
+                Unauthenticated,
+            ),
+            public_key: protection(header.public_key.is_some(), Authenticated),
+            kdf_params: protection(header.kdf_params.is_some(), Authenticated),
+            kms: protection(header.kms.is_some(), Authenticated),
+            password_wrapped_data_key: protection(
+                header.password_wrapped_data_key.is_some(),
+                Authenticated,
+            ),
+            recovery: protection(header.recovery.is_some(), Unauthenticated),
+            escrow: protection(header.escrow.is_some(), Unauthenticated),
+            machine_binding: protection(
+                header.machine_binding.is_some(),
+                Authenticated,
+            ),
+            totp: protection(header.totp.is_some(), Unauthenticated),
+            instance_id: protection(
+                header.instance_id.is_some(),
+                Unauthenticated,
+            ),
+            compression: protection(
+                header.compression.is_some(),
+                Authenticated,
+            ),
+            salt_len: protection(header.salt_len.is_some(), Authenticated),
+            created_at: protection(
+                header.created_at.is_some(),
+                Unauthenticated,
+            ),
+            dual_control: protection(
+                header.dual_control.is_some(),
+                Authenticated,
+            ),
+            post_quantum: protection(
+                header.post_quantum.is_some(),
+                Authenticated,
+            ),
+        }
+    }
+
+    /// Check, without decryption, that the encrypted data is long enough to
+    /// contain the salt (unless wrapped with a KMS or under dual control), the
+    /// AEAD nonce and tag and some ciphertext.
+    pub fn is_well_formed(&self) -> bool {
+        let salt_len = if self.header.kms.is_some()
+            || self.header.dual_control.is_some()
+        {
+            0
+        } else {
+            self.salt_len()
+        };
+        self.data.len() > salt_len + AEAD_OVERHEAD
+    }
+
+    /// Check if the keypair was encrypted with a constant salt instead of a
+    /// random one, i.e. a salt of a single repeated byte such as a zeroed
+    /// default. The [`kdf::Salt::default`] used by [`encryption_salt`] is
+    /// drawn from the OS random generator, so there is no default value to
+    /// compare the salt against, but a broken random generator or a fixed
+    /// test salt typically yields such a salt. The same password then derives
+    /// the same key for every keystore with that salt, so the keypair should
+    /// be re-encrypted. Keypairs without a salt, i.e. wrapped by a KMS or
+    /// under dual control, are never flagged.
+    pub fn uses_default_salt(&self) -> bool {
+        if self.header.kms.is_some() || self.header.dual_control.is_some() {
+            return false;
+        }
+        match self.data.get(..self.salt_len()) {
+            Some([first, rest @ ..]) => rest.iter().all(|byte| byte == first),
+            _ => false,
+        }
+    }
+
+    /// The public key stored in cleartext with this keypair, if any
+    pub fn public_key(&self) -> Option<common::PublicKey> {
+        self.header.public_key.clone()
     }
 
     /// Decrypt an encrypted keypair
@@ -211,30 +2214,2513 @@ impl EncryptedKeypair {
         &self,
         password: String,
     ) -> Result<common::SecretKey, DecryptionError> {
-        let salt_len = encryption_salt().len();
-        let (raw_salt, cipher) = self.0.split_at(salt_len);
+        self.decrypt_on_machine(password, &MachineIdFile::default())
+    }
+
+    /// Decrypt an encrypted keypair, getting the fingerprint of this machine
+    /// from the given source if the keypair is bound to a machine.
+    pub fn decrypt_on_machine(
+        &self,
+        password: String,
+        machine: &impl MachineFingerprint,
+    ) -> Result<common::SecretKey, DecryptionError> {
+        let password = self.bind_password(password, machine)?;
+        self.decrypt_bound(password)
+    }
+
+    /// Mix the machine fingerprint into the password, if this keypair is bound
+    /// to a machine
+    fn bind_password(
+        &self,
+        password: String,
+        machine: &impl MachineFingerprint,
+    ) -> Result<String, DecryptionError> {
+        match &self.header.machine_binding {
+            Some(binding) => {
+                let fingerprint = Zeroizing::new(
+                    machine
+                        .fingerprint()
+                        .map_err(DecryptionError::MachineFingerprint)?,
+                );
+                if !binding.matches(&fingerprint) {
+                    return Err(DecryptionError::MachineBindingMismatch);
+                }
+                Ok(MachineBinding::bind_password(password, &fingerprint))
+            }
+            None => Ok(password),
+        }
+    }
+
+    /// Decrypt with a password into which the machine fingerprint has already
+    /// been mixed, if needed
+    fn decrypt_bound(
+        &self,
+        password: String,
+    ) -> Result<common::SecretKey, DecryptionError> {
+        self.decrypt_bound_with_salt_len(password, self.salt_len())
+    }
+
+    /// Check that the password alone decrypts this keypair
+    fn check_password_only(&self) -> Result<(), DecryptionError> {
+        if self.header.totp.is_some() {
+            return Err(DecryptionError::TotpRequired);
+        }
+        if self.header.dual_control.is_some() {
+            return Err(DecryptionError::InsufficientAuthorizers);
+        }
+        if let Some(wrapping) = &self.header.kms {
+            return Err(DecryptionError::KmsRequired(wrapping.key_id.clone()));
+        }
+        Ok(())
+    }
+
+    /// Decrypt like [`EncryptedKeypair::decrypt_bound`], with a salt of the
+    /// given length at the start of the encrypted data
+    fn decrypt_bound_with_salt_len(
+        &self,
+        password: String,
+        salt_len: usize,
+    ) -> Result<common::SecretKey, DecryptionError> {
+        self.decrypt_keys(password, salt_len)
+            .map(|keys| keys.ed25519)
+    }
+
+    /// Decrypt the secret keys like
+    /// [`EncryptedKeypair::decrypt_bound_with_salt_len`], including the
+    /// post-quantum secret key of a hybrid keypair
+    fn decrypt_keys(
+        &self,
+        password: String,
+        salt_len: usize,
+    ) -> Result<DecryptedKeys, DecryptionError> {
+        self.check_password_only()?;
+        if self.data.len() < salt_len {
+            return Err(DecryptionError::BadSalt);
+        }
+        let (raw_salt, cipher) = self.data.split_at(salt_len);
 
         let salt = kdf::Salt::from_slice(raw_salt)
             .map_err(|_| DecryptionError::BadSalt)?;
 
-        let encryption_key = encryption_key(&salt, password);
+        let kdf_params = self.header.kdf_params.unwrap_or_default();
+        let encryption_key = encryption_key(&salt, password, &kdf_params)
+            .map_err(|_| DecryptionError::InvalidKdfParams(kdf_params))?;
 
-        let decrypted_data = aead::open(&encryption_key, cipher)
-            .map_err(|_| DecryptionError::DecryptionError)?;
+        let key = match &self.header.password_wrapped_data_key {
+            Some(wrapped_data_key) => {
+                unwrap_data_key(&encryption_key, wrapped_data_key)?
+            }
+            None => encryption_key,
+        };
+        self.open_sealed(&key, cipher).map(|(keys, _)| keys)
+    }
 
-        common::SecretKey::try_from_slice(&decrypted_data)
-            .map_err(|_| DecryptionError::DeserializingError)
+    /// Repair a keystore whose cleartext public key has been corrupted. The
+    /// keypair is decrypted with the password, without checking it against
+    /// the stored public key, and the public key derived from the decrypted
+    /// secret key replaces the stored one. The authenticated encryption
+    /// guarantees that the secret key itself is intact. On failure, the
+    /// keystore is left unchanged.
+    pub fn repair_public(
+        &mut self,
+        password: &str,
+    ) -> Result<(), DecryptionError> {
+        let stored = self.header.public_key.take();
+        match self.decrypt(password.to_owned()) {
+            Ok(keypair) => {
+                self.header.public_key = Some(keypair.ref_to());
+                Ok(())
+            }
+            Err(err) => {
+                self.header.public_key = stored;
+                Err(err)
+            }
+        }
     }
-}
 
-/// Keypair encryption salt
-fn encryption_salt() -> kdf::Salt {
-    kdf::Salt::default()
-}
+    /// Re-encrypt the keypair with a new password. The header is kept as it
+    /// is, including the fields unknown to this version of the wallet, but
+    /// for the salt of the key derivation and the time of encryption. If the
+    /// keypair is encrypted with a data key, the data key is kept and only
+    /// its password wrapping is replaced, so that its [`RecoveryCode`] or
+    /// escrow stay valid. The key derived from the old password to decrypt the
+    /// keypair is the only one derived from it. A legacy keystore gets an
+    /// authenticated header.
+    pub fn change_password(
+        &self,
+        old_password: String,
+        new_password: String,
+    ) -> Result<Self, DecryptionError> {
+        self.check_password_only()?;
+        let machine = MachineIdFile::default();
+        let old_password = self.bind_password(old_password, &machine)?;
+        let new_password = self.bind_password(new_password, &machine)?;
+        let kdf_params = self.header.kdf_params.unwrap_or_default();
+        let salt_len = self.salt_len();
+        if self.data.len() < salt_len {
+            return Err(DecryptionError::BadSalt);
+        }
+        let (raw_salt, cipher) = self.data.split_at(salt_len);
+        let salt = kdf::Salt::from_slice(raw_salt)
+            .map_err(|_| DecryptionError::BadSalt)?;
+        let old_key = encryption_key(&salt, old_password, &kdf_params)
+            .map_err(|_| DecryptionError::InvalidKdfParams(kdf_params))?;
+        let data_key = match &self.header.password_wrapped_data_key {
+            Some(wrapped_data_key) => {
+                Some(unwrap_data_key(&old_key, wrapped_data_key)?)
+            }
+            None => None,
+        };
+        let (keys, plaintext) =
+            self.open_sealed(data_key.as_ref().unwrap_or(&old_key), cipher)?;
+
+        let new_salt = encryption_salt();
+        let new_key = encryption_key(&new_salt, new_password, &kdf_params)
+            .map_err(|_| DecryptionError::InvalidKdfParams(kdf_params))?;
+
+        let mut header = self.header.clone();
+        header.legacy = false;
+        header.public_key = Some(keys.ed25519.ref_to());
+        header.kdf_params = Some(kdf_params);
+        header.salt_len = Some(new_salt.len() as u32);
+        header.created_at = Some(now_secs());
+        // The plaintext is sealed again as it is, with the data key if any,
+        // to authenticate the new header
+        let sealing_key = match &data_key {
+            Some(data_key) => {
+                header.password_wrapped_data_key = Some(
+                    aead::seal(&new_key, data_key.unprotected_as_bytes())
+                        .expect("Encryption of data shouldn't fail"),
+                );
+                data_key
+            }
+            None => &new_key,
+        };
+        let encrypted_keypair =
+            seal_with_ad(sealing_key, &plaintext, &header.associated_data());
+        Ok(Self {
+            header,
+            data: [new_salt.as_ref(), &encrypted_keypair].concat(),
+        })
+    }
 
-/// Make encryption secret key from a password.
-fn encryption_key(salt: &kdf::Salt, password: String) -> kdf::SecretKey {
-    kdf::Password::from_slice(password.as_bytes())
-        .and_then(|password| kdf::derive_key(&password, salt, 3, 1 << 16, 32))
-        .expect("Generation of encryption secret key shouldn't fail")
+    /// The length of the salt at the start of the encrypted data, which is
+    /// the current one for keystores that don't record it
+    fn salt_len(&self) -> usize {
+        self.header
+            .salt_len
+            .map(|salt_len| salt_len as usize)
+            .unwrap_or_else(|| encryption_salt().len())
+    }
+
+    /// Find the length of the salt of a keystore that doesn't record it, e.g.
+    /// written by a version of the wallet using another salt length, by
+    /// trying to decrypt it with the password and each of the
+    /// [`KNOWN_SALT_LENGTHS`] until the authenticated decryption succeeds.
+    /// Every attempt runs a whole key derivation. Returns `None` if the
+    /// keystore records its salt length, is wrapped with a KMS or a TOTP, is
+    /// under dual control or if no known length decrypts it.
+    pub fn detect_salt_length(&self, password: &str) -> Option<usize> {
+        if self.header.salt_len.is_some()
+            || self.header.kms.is_some()
+            || self.header.dual_control.is_some()
+        {
+            return None;
+        }
+        let password = self
+            .bind_password(password.to_owned(), &MachineIdFile::default())
+            .ok()?;
+        KNOWN_SALT_LENGTHS.into_iter().find(|&salt_len| {
+            self.data.len() > salt_len + AEAD_OVERHEAD
+                && self
+                    .decrypt_bound_with_salt_len(password.clone(), salt_len)
+                    .is_ok()
+        })
+    }
+
+    /// Record the salt length found by
+    /// [`EncryptedKeypair::detect_salt_length`] in the header of a keystore
+    /// that doesn't record it, so that it can be decrypted again. Returns the
+    /// detected length, or `None` if the keystore is left unchanged.
+    pub fn migrate_salt_length(&mut self, password: &str) -> Option<usize> {
+        let salt_len = self.detect_salt_length(password)?;
+        self.header.salt_len = Some(salt_len as u32);
+        Some(salt_len)
+    }
+
+    /// Open the ciphertext with the given key and the associated data of the
+    /// header and check that the decrypted keypair matches the stored public
+    /// key, if any.
+    fn open(
+        &self,
+        key: &aead::SecretKey,
+        cipher: &[u8],
+    ) -> Result<common::SecretKey, DecryptionError> {
+        self.open_sealed(key, cipher).map(|(keys, _)| keys.ed25519)
+    }
+
+    /// Open the ciphertext like [`EncryptedKeypair::open`], returning all the
+    /// secret keys and the plaintext as it was sealed, i.e. still compressed
+    /// if it is, to seal it again unchanged
+    fn open_sealed(
+        &self,
+        key: &aead::SecretKey,
+        cipher: &[u8],
+    ) -> Result<(DecryptedKeys, Zeroizing<Vec<u8>>), DecryptionError> {
+        let sealed = open_with_ad(key, cipher, &self.header.associated_data())?;
+        let hybrid = self.is_hybrid();
+        let keys = match self.header.compression {
+            Some(compression) => {
+                DecryptedKeys::decode(&compression.decompress(&sealed)?, hybrid)
+            }
+            None => DecryptedKeys::decode(&sealed, hybrid),
+        }?;
+
+        match &self.header.public_key {
+            Some(pk) if pk != &keys.ed25519.ref_to() => {
+                Err(DecryptionError::PublicKeyMismatch)
+            }
+            _ => Ok((keys, sealed)),
+        }
+    }
+}
+
+/// The secret keys in the plaintext of an [`EncryptedKeypair`]
+struct DecryptedKeys {
+    /// The Ed25519 secret key
+    ed25519: common::SecretKey,
+    /// The encoded post-quantum secret key of a hybrid keypair
+    post_quantum: Option<Zeroizing<Vec<u8>>>,
+}
+
+impl DecryptedKeys {
+    /// Decode the secret keys from the plaintext, which only has a
+    /// post-quantum secret key after the Ed25519 one for a hybrid keypair
+    fn decode(plaintext: &[u8], hybrid: bool) -> Result<Self, DecryptionError> {
+        let invalid = |_: std::io::Error| DecryptionError::DeserializingError;
+        if !hybrid {
+            let ed25519 = common::SecretKey::try_from_slice(plaintext)
+                .map_err(invalid)?;
+            return Ok(Self {
+                ed25519,
+                post_quantum: None,
+            });
+        }
+        let mut rest = plaintext;
+        let ed25519 =
+            common::SecretKey::deserialize(&mut rest).map_err(invalid)?;
+        let post_quantum = Vec::<u8>::try_from_slice(rest)
+            .map(Zeroizing::new)
+            .map_err(invalid)?;
+        Ok(Self {
+            ed25519,
+            post_quantum: Some(post_quantum),
+        })
+    }
+}
+
+/// A public key ordered by its [`SortablePublicKey::stable_id`], to list keys
+/// in a deterministic order independent of their insertion order
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SortablePublicKey(pub common::PublicKey);
+
+impl SortablePublicKey {
+    /// The SHA-256 hash of the Borsh encoding of the public key
+    pub fn stable_id(&self) -> [u8; 32] {
+        use sha2::{Digest, Sha256};
+
+        let pk = self
+            .0
+            .try_to_vec()
+            .expect("Encoding public key shouldn't fail");
+        Sha256::digest(&pk).into()
+    }
+}
+
+impl Ord for SortablePublicKey {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        // Ties of distinct keys, i.e. hash collisions, are broken by the key
+        // itself to stay consistent with `Eq`
+        self.stable_id()
+            .cmp(&other.stable_id())
+            .then_with(|| self.0.cmp(&other.0))
+    }
+}
+
+impl PartialOrd for SortablePublicKey {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Whether a metadata field of a keystore is protected against tampering
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldProtection {
+    /// Tampering with the field makes the decryption of the keypair fail
+    Authenticated,
+    /// The field is stored in plaintext and can be altered undetected
+    Unauthenticated,
+    /// The keystore doesn't have this field
+    Absent,
+}
+
+/// The protection of every metadata field of a keystore, as reported by
+/// [`StoredKeypair::metadata_protection`]. The associated data of the
+/// encryption of an `encrypted_v1:` keystore is its encoded header without
+/// the public key, so all of its other fields are authenticated and the public
+/// key is checked against the decrypted keypair instead. A legacy
+/// `encrypted:` keystore has no header and is encrypted with an empty
+/// associated data, so its fields are absent, and those set on it in memory
+/// are only authenticated if the decryption depends on them or checks them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetadataProtection {
+    /// The version of the software that created the keystore
+    pub created_by_version: FieldProtection,
+    /// The public key, which is checked against the decrypted keypair
+    pub public_key: FieldProtection,
+    /// The KDF parameters, which derive the encryption key
+    pub kdf_params: FieldProtection,
+    /// The KMS wrapping of the data key
+    pub kms: FieldProtection,
+    /// The password wrapping of the data key
+    pub password_wrapped_data_key: FieldProtection,
+    /// The recovery code wrapping of the data key
+    pub recovery: FieldProtection,
+    /// The auditor wrapping of the data key
+    pub escrow: FieldProtection,
+    /// The machine binding, which is mixed into the encryption key
+    pub machine_binding: FieldProtection,
+    /// The TOTP parameters and the wrapping of the TOTP secret
+    pub totp: FieldProtection,
+    /// The instance ID
+    pub instance_id: FieldProtection,
+    /// The compression of the plaintext
+    pub compression: FieldProtection,
+    /// The salt length, which delimits the salt of the key derivation
+    pub salt_len: FieldProtection,
+    /// The time of encryption
+    pub created_at: FieldProtection,
+    /// The dual control wrapping of the data key
+    pub dual_control: FieldProtection,
+    /// The post-quantum key of a hybrid keypair
+    pub post_quantum: FieldProtection,
+}
+
+impl MetadataProtection {
+    /// The protection of the metadata of a keystore without any
+    fn absent() -> Self {
+        Self {
+            created_by_version: FieldProtection::Absent,
+            public_key: FieldProtection::Absent,
+            kdf_params: FieldProtection::Absent,
+            kms: FieldProtection::Absent,
+            password_wrapped_data_key: FieldProtection::Absent,
+            recovery: FieldProtection::Absent,
+            escrow: FieldProtection::Absent,
+            machine_binding: FieldProtection::Absent,
+            totp: FieldProtection::Absent,
+            instance_id: FieldProtection::Absent,
+            compression: FieldProtection::Absent,
+            salt_len: FieldProtection::Absent,
+            created_at: FieldProtection::Absent,
+            dual_control: FieldProtection::Absent,
+            post_quantum: FieldProtection::Absent,
+        }
+    }
+
+    /// The names of the present fields that are not tamper-protected
+    pub fn unauthenticated_fields(&self) -> Vec<&'static str> {
+        [
+            ("created_by_version", self.created_by_version),
+            ("public_key", self.public_key),
+            ("kdf_params", self.kdf_params),
+            ("kms", self.kms),
+            ("password_wrapped_data_key", self.password_wrapped_data_key),
+            ("recovery", self.recovery),
+            ("escrow", self.escrow),
+            ("machine_binding", self.machine_binding),
+            ("totp", self.totp),
+            ("instance_id", self.instance_id),
+            ("compression", self.compression),
+            ("salt_len", self.salt_len),
+            ("created_at", self.created_at),
+            ("dual_control", self.dual_control),
+            ("post_quantum", self.post_quantum),
+        ]
+        .into_iter()
+        .filter(|(_, protection)| {
+            *protection == FieldProtection::Unauthenticated
+        })
+        .map(|(name, _)| name)
+        .collect()
+    }
+}
+
+/// The result of [`verify_wallet_dir`]
+#[derive(Debug, Default)]
+pub struct WalletIntegrityReport {
+    /// The status of every key file found in the directory
+    pub files: Vec<(PathBuf, KeyFileStatus)>,
+}
+
+/// The integrity status of a single key file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyFileStatus {
+    /// The file parses and its content is well-formed
+    Ok,
+    /// The file couldn't be read
+    Unreadable(String),
+    /// The file doesn't contain a valid stored keypair
+    Invalid(String),
+    /// The encrypted data is too short to be decrypted
+    Truncated,
+    /// The file doesn't match its checksum
+    ChecksumMismatch,
+    /// The file parses and its content is well-formed, but it was written
+    /// without a checksum, so its corruption can't be detected. It's given a
+    /// checksum when it's saved again.
+    NoChecksum,
+}
+
+impl WalletIntegrityReport {
+    /// Returns `true` if all the key files are intact
+    pub fn is_ok(&self) -> bool {
+        self.files
+            .iter()
+            .all(|(_, status)| *status == KeyFileStatus::Ok)
+    }
+}
+
+/// Check every `*.key` file in the given directory, as written by
+/// [`StoredKeypair::save_to_dir`], without decrypting any of them. Every file
+/// is checked against its checksum and reported on individually. Only a
+/// failure to list the directory is returned as an error.
+pub fn verify_wallet_dir(dir: &Path) -> std::io::Result<WalletIntegrityReport> {
+    let mut report = WalletIntegrityReport::default();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str())
+            != Some(KEY_FILE_EXTENSION)
+        {
+            continue;
+        }
+        let status = match fs::read_to_string(&path).map(Zeroizing::new) {
+            Err(err) => KeyFileStatus::Unreadable(err.to_string()),
+            Ok(content) => key_file_status(&content),
+        };
+        report.files.push((path, status));
+    }
+    report.files.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(report)
+}
+
+/// Check the content of a key file
+fn key_file_status(content: &str) -> KeyFileStatus {
+    let (serialized, checksum) = split_key_file(content);
+    if let Some(checksum) = checksum {
+        if !key_file_checksum_matches(serialized, checksum) {
+            return KeyFileStatus::ChecksumMismatch;
+        }
+    }
+    match StoredKeypair::from_str(serialized) {
+        Err(err) => KeyFileStatus::Invalid(err.to_string()),
+        Ok(StoredKeypair::Encrypted(encrypted))
+            if !encrypted.is_well_formed() =>
+        {
+            KeyFileStatus::Truncated
+        }
+        Ok(_) if checksum.is_none() => KeyFileStatus::NoChecksum,
+        Ok(_) => KeyFileStatus::Ok,
+    }
+}
+
+/// Split the content of a key file into the serialized keypair and the line
+/// of its checksum, if any, as key files written before the checksums only
+/// hold the keypair
+fn split_key_file(content: &str) -> (&str, Option<&str>) {
+    let content = content.trim();
+    match content.split_once('\n') {
+        Some((serialized, checksum)) => {
+            (serialized.trim(), Some(checksum.trim()))
+        }
+        None => (content, None),
+    }
+}
+
+/// The hex encoded SHA-256 checksum of a serialized keypair in a key file
+fn key_file_checksum(serialized: &str) -> String {
+    use sha2::{Digest, Sha256};
+
+    hex::encode(Sha256::digest(serialized.as_bytes()))
+}
+
+/// Check the line of the checksum of a key file against its keypair
+fn key_file_checksum_matches(serialized: &str, checksum: &str) -> bool {
+    checksum.strip_prefix(KEY_FILE_CHECKSUM_PREFIX)
+        == Some(key_file_checksum(serialized).as_str())
+}
+
+/// Check that two encrypted keypairs, e.g. before and after a migration, hold
+/// the same key. Both keypairs are decrypted with their respective passwords
+/// and their public keys are compared in constant time. The decrypted secret
+/// keys are dropped as soon as their public keys have been derived.
+pub fn same_key(
+    a: &EncryptedKeypair,
+    pwd_a: &str,
+    b: &EncryptedKeypair,
+    pwd_b: &str,
+) -> Result<bool, DecryptionError> {
+    let pk_a = a.decrypt(pwd_a.to_owned())?.ref_to();
+    let pk_b = b.decrypt(pwd_b.to_owned())?.ref_to();
+    let pk_a = pk_a
+        .try_to_vec()
+        .expect("Encoding public key shouldn't fail");
+    let pk_b = pk_b
+        .try_to_vec()
+        .expect("Encoding public key shouldn't fail");
+    Ok(orion::util::secure_cmp(&pk_a, &pk_b).is_ok())
+}
+
+#[allow(missing_docs)]
+#[derive(Error, Debug)]
+pub enum ChangePasswordError {
+    #[error("The new password cannot be empty")]
+    EmptyPassword,
+    #[error("Unable to re-encrypt the key at index {index}: {error}")]
+    Decryption {
+        index: usize,
+        error: DecryptionError,
+    },
+}
+
+/// Change the password of all the encrypted keypairs of a wallet at once.
+/// Every keypair is first re-encrypted with the new password and the keypairs
+/// are only replaced once all of them succeeded, so on error all the keypairs
+/// are left unchanged on the old password. Hybrid keypairs are re-encrypted
+/// with both of their secret keys. Raw keypairs are left as they are, and so
+/// are the keypairs that the password alone doesn't decrypt, i.e. wrapped with
+/// a KMS or a TOTP or under dual control.
+///
+/// Returns the indices in `keys` of the encrypted keypairs that were skipped,
+/// in increasing order, so that their passwords can be changed separately.
+pub fn change_wallet_password(
+    keys: &mut [StoredKeypair],
+    old: &str,
+    new: &str,
+) -> Result<Vec<usize>, ChangePasswordError> {
+    if new.is_empty() {
+        return Err(ChangePasswordError::EmptyPassword);
+    }
+    let mut reencrypted = Vec::new();
+    let mut skipped = Vec::new();
+    for (index, keypair) in keys.iter().enumerate() {
+        if let StoredKeypair::Encrypted(encrypted) = keypair {
+            if encrypted.check_password_only().is_err() {
+                skipped.push(index);
+                continue;
+            }
+            let changed = encrypted
+                .change_password(old.to_owned(), new.to_owned())
+                .map_err(|error| ChangePasswordError::Decryption {
+                    index,
+                    error,
+                })?;
+            reencrypted.push((index, changed));
+        }
+    }
+    for (index, changed) in reencrypted {
+        keys[index] = StoredKeypair::Encrypted(changed);
+    }
+    Ok(skipped)
+}
+
+/// Re-encrypt all the encrypted keypairs of a wallet with a fresh random salt
+/// for the key derived from the same password. Every keypair is decrypted
+/// once, against the public key of the original if it's recorded, and the
+/// public key of the re-encrypted keypair is checked against it. Like
+/// [`change_wallet_password`], the keypairs are only replaced once all of them
+/// succeeded, hybrid keypairs are rotated with both of their secret keys and
+/// raw keypairs are left as they are.
+///
+/// Returns the indices in `keys` of the encrypted keypairs that the password
+/// alone doesn't decrypt, i.e. wrapped with a KMS or a TOTP or under dual
+/// control, which are skipped and keep their salts.
+pub fn rotate_salts(
+    keys: &mut [StoredKeypair],
+    password: &str,
+) -> Result<Vec<usize>, DecryptionError> {
+    let mut rotated = Vec::new();
+    let mut skipped = Vec::new();
+    for (index, keypair) in keys.iter().enumerate() {
+        if let StoredKeypair::Encrypted(encrypted) = keypair {
+            if encrypted.check_password_only().is_err() {
+                skipped.push(index);
+                continue;
+            }
+            let reencrypted = encrypted
+                .change_password(password.to_owned(), password.to_owned())?;
+            if encrypted.public_key().is_some()
+                && reencrypted.public_key() != encrypted.public_key()
+            {
+                return Err(DecryptionError::PublicKeyMismatch);
+            }
+            rotated.push((index, reencrypted));
+        }
+    }
+    for (index, reencrypted) in rotated {
+        keys[index] = StoredKeypair::Encrypted(reencrypted);
+    }
+    Ok(skipped)
+}
+
+/// Estimate the time it takes to unlock all the given keys with a password,
+/// e.g. to size startup timeouts. Only a single key derivation with the
+/// given parameters is run to calibrate the cost of unlocking one key, which
+/// is then multiplied by the number of key derivations needed, i.e. one for
+/// every encrypted key that is not wrapped by a KMS and two for the keys under
+/// dual control. Every key is assumed to be unlocked independently of the
+/// others.
+///
+/// Panics if the parameters are invalid.
+pub fn estimate_unlock_time(
+    keys: &[StoredKeypair],
+    params: &KdfParams,
+) -> Duration {
+    let count: usize = keys
+        .iter()
+        .map(|keypair| match keypair {
+            StoredKeypair::Encrypted(encrypted) => {
+                if encrypted.header.kms.is_some() {
+                    0
+                } else if encrypted.header.dual_control.is_some() {
+                    2
+                } else {
+                    1
+                }
+            }
+            StoredKeypair::Raw(_) | StoredKeypair::WatchOnly(_) => 0,
+        })
+        .sum();
+    if count == 0 {
+        return Duration::ZERO;
+    }
+    let start = Instant::now();
+    encryption_key(&encryption_salt(), "calibration".to_owned(), params)
+        .expect("The KDF parameters should be valid");
+    start.elapsed() * count as u32
+}
+
+/// Get the raw keypairs of only the named keys with the given role, leaving
+/// the other keys locked. Raw keypairs are returned as they are, encrypted ones
+/// are decrypted with the given password and watch-only keys are skipped.
+pub fn decrypt_by_role(
+    keys: &[(String, StoredKeypair)],
+    role: KeyRole,
+    password: &str,
+) -> Result<Vec<(String, Rc<common::SecretKey>)>, DecryptionError> {
+    keys.iter()
+        .filter(|(name, keypair)| {
+            KeyRole::of_alias(name) == role && !keypair.is_watch_only()
+        })
+        .map(|(name, keypair)| {
+            let keypair = keypair.get(true, Some(password.to_owned()))?;
+            Ok((name.clone(), keypair))
+        })
+        .collect()
+}
+
+/// Get the indices of the given keys that were encrypted more than `max_age`
+/// before `now`, in seconds since the Unix epoch, as in
+/// [`StoredKeypair::is_older_than`]. No decryption is needed.
+pub fn keys_exceeding_age(
+    keys: &[StoredKeypair],
+    max_age: Duration,
+    now: u64,
+) -> Vec<usize> {
+    keys.iter()
+        .enumerate()
+        .filter(|(_, keypair)| keypair.is_older_than(max_age, now))
+        .map(|(index, _)| index)
+        .collect()
+}
+
+/// A keystore that can't be read by a version of the wallet, as found by
+/// [`check_format_compatibility`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IncompatibleKey {
+    /// The index of the key in the checked keys
+    pub index: usize,
+    /// The public key, if it's known without decryption
+    pub public_key: Option<common::PublicKey>,
+    /// The format version required to read the keystore
+    pub required_version: u8,
+}
+
+/// Find the keys whose keystore format version, as in
+/// [`StoredKeypair::format_version`], is not among the versions supported by
+/// a target version of the wallet, e.g. before upgrading to it, so that no
+/// key becomes unreadable. Raw and watch-only keypairs have no keystore
+/// format and are never reported. Nothing is decrypted.
+pub fn check_format_compatibility(
+    keys: &[StoredKeypair],
+    supported_versions: &[u8],
+) -> Vec<IncompatibleKey> {
+    keys.iter()
+        .enumerate()
+        .filter_map(|(index, keypair)| {
+            let required_version = keypair.format_version()?;
+            if supported_versions.contains(&required_version) {
+                return None;
+            }
+            Some(IncompatibleKey {
+                index,
+                public_key: keypair.public_key(),
+                required_version,
+            })
+        })
+        .collect()
+}
+
+/// Get the names of the given keys whose implicit address is the given
+/// address. Only the public keys available without decryption are used, so
+/// encrypted keys written before their public key was being recorded never
+/// match.
+pub fn keys_for_address<'a>(
+    keys: &'a [(String, StoredKeypair)],
+    addr: &Address,
+) -> Vec<&'a str> {
+    keys.iter()
+        .filter(|(_, keypair)| {
+            keypair.public_key().map_or(false, |pk| {
+                let pkh = PublicKeyHash::from(&pk);
+                &Address::Implicit(ImplicitAddress(pkh)) == addr
+            })
+        })
+        .map(|(name, _)| name.as_str())
+        .collect()
+}
+
+/// Describe the given named keys as JSON for display in e.g. an API response.
+/// Only the names, public keys (when available without decryption), roles,
+/// derived from the names, and whether the keys are encrypted are included. The
+/// secret of a raw keypair is replaced with `"<redacted>"` and the ciphertext
+/// of an encrypted keypair is omitted.
+pub fn to_redacted_json(keys: &[(String, StoredKeypair)]) -> serde_json::Value {
+    let keys = keys
+        .iter()
+        .map(|(name, keypair)| {
+            let mut key = serde_json::json!({
+                "name": name,
+                "public_key": keypair.public_key().map(|pk| pk.to_string()),
+                "role": KeyRole::of_alias(name).to_string(),
+                "encrypted": keypair.is_encrypted(),
+                "watch_only": keypair.is_watch_only(),
+            });
+            if !keypair.is_encrypted() && !keypair.is_watch_only() {
+                key["secret"] = "<redacted>".into();
+            }
+            key
+        })
+        .collect();
+    serde_json::Value::Array(keys)
+}
+
+/// Export an inventory of the named keys as CSV, with a header row and a row
+/// per key with its name, public key, implicit address, role, encryption
+/// status and the KDF parameters of its password. No key is decrypted and no
+/// secret is included. The public key and address are empty for encrypted
+/// keys written before their public key was being recorded and the KDF
+/// parameters are empty for keys not encrypted with a password.
+pub fn wallet_to_csv(keys: &[(String, StoredKeypair)]) -> String {
+    let mut csv = String::from(
+        "name,public_key,address,role,encryption,kdf_iterations,kdf_memory\n",
+    );
+    for (name, keypair) in keys {
+        let public_key = keypair.public_key();
+        let address = public_key.as_ref().map(|pk| {
+            Address::Implicit(ImplicitAddress(PublicKeyHash::from(pk))).encode()
+        });
+        let (encryption, kdf_params) = match keypair {
+            StoredKeypair::Encrypted(encrypted)
+                if encrypted.header.kms.is_some() =>
+            {
+                ("kms", None)
+            }
+            StoredKeypair::Encrypted(encrypted)
+                if encrypted.header.dual_control.is_some() =>
+            {
+                (
+                    "dual-control",
+                    Some(encrypted.header.kdf_params.unwrap_or_default()),
+                )
+            }
+            StoredKeypair::Encrypted(encrypted) if encrypted.is_hybrid() => (
+                "hybrid-password",
+                Some(encrypted.header.kdf_params.unwrap_or_default()),
+            ),
+            StoredKeypair::Encrypted(encrypted) => (
+                "password",
+                Some(encrypted.header.kdf_params.unwrap_or_default()),
+            ),
+            StoredKeypair::Raw(_) => ("none", None),
+            StoredKeypair::WatchOnly(_) => ("watch-only", None),
+        };
+        let fields = [
+            name.clone(),
+            public_key.map(|pk| pk.to_string()).unwrap_or_default(),
+            address.unwrap_or_default(),
+            KeyRole::of_alias(name).to_string(),
+            encryption.to_owned(),
+            kdf_params
+                .map(|params| params.iterations.to_string())
+                .unwrap_or_default(),
+            kdf_params
+                .map(|params| params.memory.to_string())
+                .unwrap_or_default(),
+        ];
+        let row: Vec<String> =
+            fields.iter().map(|field| csv_field(field)).collect();
+        csv.push_str(&row.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+/// Quote a CSV field if it contains a separator, a quote or a line break, as
+/// in RFC 4180
+fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+/// Derive a keypair for the application with the given ID from a master
+/// keypair. The secret key is derived with HKDF-SHA256 from the master secret
+/// key, with a fixed domain separator as the salt and the application ID as
+/// the info, so every application gets a stable key and the keys of different
+/// applications can't be linked to each other or to the master key without
+/// the master secret key.
+pub fn derive_app_key(
+    master: &common::SecretKey,
+    app_id: &str,
+) -> common::SecretKey {
+    use hkdf::Hkdf;
+    use sha2::Sha256;
+
+    let common::SecretKey::Ed25519(master) = master;
+    let ikm = Zeroizing::new(master.0.to_bytes());
+    let hkdf = Hkdf::<Sha256>::new(Some(APP_KEY_DOMAIN), &ikm[..]);
+    let mut seed = Zeroizing::new([0; 32]);
+    hkdf.expand(app_id.as_bytes(), &mut seed[..])
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    ed25519::SecretKey::try_from_slice(&seed[..])
+        .expect("Any 32 bytes are a valid Ed25519 secret key")
+        .try_to_sk()
+        .expect("Converting the secret key shouldn't fail")
+}
+
+/// Derive the encryption key of a keypair protected by a TOTP from the key
+/// derived from the password and the TOTP secret
+fn totp_key(
+    password_key: &aead::SecretKey,
+    totp_secret: &[u8],
+) -> aead::SecretKey {
+    use sha2::{Digest, Sha256};
+
+    let mut hasher = Sha256::new();
+    hasher.update(TOTP_KEY_DOMAIN);
+    hasher.update(password_key.unprotected_as_bytes());
+    hasher.update(totp_secret);
+    aead::SecretKey::from_slice(&hasher.finalize())
+        .expect("A SHA-256 hash should be a valid encryption key")
+}
+
+/// The domain-separated hash of a machine fingerprint for the given purpose
+fn machine_hash(purpose: &[u8], fingerprint: &[u8]) -> [u8; 32] {
+    use sha2::{Digest, Sha256};
+
+    let mut hasher = Sha256::new();
+    hasher.update(MACHINE_BINDING_DOMAIN);
+    hasher.update(purpose);
+    hasher.update(fingerprint);
+    hasher.finalize().into()
+}
+
+/// Encrypt the data with the key like [`aead::seal`], into a random nonce
+/// followed by the ciphertext and its tag, but also authenticate the given
+/// associated data, which isn't encrypted
+fn seal_with_ad(key: &aead::SecretKey, data: &[u8], ad: &[u8]) -> Vec<u8> {
+    seal_with_nonce(key, &xchacha20poly1305::Nonce::generate(), data, ad)
+}
+
+/// Encrypt the data like [`seal_with_ad`], with the given nonce
+fn seal_with_nonce(
+    key: &aead::SecretKey,
+    nonce: &xchacha20poly1305::Nonce,
+    data: &[u8],
+    ad: &[u8],
+) -> Vec<u8> {
+    let key = chacha20::SecretKey::from_slice(key.unprotected_as_bytes())
+        .expect("The encryption key should be 32 bytes");
+    let mut sealed = vec![0; AEAD_OVERHEAD + data.len()];
+    sealed[..AEAD_NONCE_LEN].copy_from_slice(nonce.as_ref());
+    xchacha20poly1305::seal(
+        &key,
+        nonce,
+        data,
+        Some(ad),
+        &mut sealed[AEAD_NONCE_LEN..],
+    )
+    .expect("Encryption of data shouldn't fail");
+    sealed
+}
+
+/// Decrypt the data encrypted by [`seal_with_ad`], which fails unless the
+/// associated data is the same
+fn open_with_ad(
+    key: &aead::SecretKey,
+    sealed: &[u8],
+    ad: &[u8],
+) -> Result<Zeroizing<Vec<u8>>, DecryptionError> {
+    if sealed.len() <= AEAD_OVERHEAD {
+        return Err(DecryptionError::DecryptionError);
+    }
+    let key = chacha20::SecretKey::from_slice(key.unprotected_as_bytes())
+        .map_err(|_| DecryptionError::DecryptionError)?;
+    let (nonce, cipher) = sealed.split_at(AEAD_NONCE_LEN);
+    let nonce = xchacha20poly1305::Nonce::from_slice(nonce)
+        .map_err(|_| DecryptionError::DecryptionError)?;
+    let mut data = Zeroizing::new(vec![0; sealed.len() - AEAD_OVERHEAD]);
+    xchacha20poly1305::open(&key, &nonce, cipher, Some(ad), &mut data)
+        .map_err(|_| DecryptionError::DecryptionError)?;
+    Ok(data)
+}
+
+/// Decrypt a wrapped data key
+fn unwrap_data_key(
+    key: &aead::SecretKey,
+    wrapped_data_key: &[u8],
+) -> Result<aead::SecretKey, DecryptionError> {
+    let data_key = aead::open(key, wrapped_data_key)
+        .map(Zeroizing::new)
+        .map_err(|_| DecryptionError::DecryptionError)?;
+    aead::SecretKey::from_slice(&data_key)
+        .map_err(|_| DecryptionError::DecryptionError)
+}
+
+/// Derive the key wrapping the data key of an escrowed keypair from the
+/// X25519 shared secret and the public keys of both parties
+fn escrow_key(
+    shared_secret: &x25519_dalek::SharedSecret,
+    ephemeral_public_key: &x25519_dalek::PublicKey,
+    auditor_public_key: &x25519_dalek::PublicKey,
+) -> aead::SecretKey {
+    use sha2::{Digest, Sha256};
+    let mut hasher = Sha256::new();
+    hasher.update(ESCROW_KEY_DOMAIN);
+    hasher.update(shared_secret.as_bytes());
+    hasher.update(ephemeral_public_key.as_bytes());
+    hasher.update(auditor_public_key.as_bytes());
+    aead::SecretKey::from_slice(&hasher.finalize())
+        .expect("A SHA-256 hash should be a valid encryption key")
+}
+
+/// Keypair encryption salt
+fn encryption_salt() -> kdf::Salt {
+    kdf::Salt::default()
+}
+
+/// Serialize a keypair to encrypt it, compressed with [`Compression::Deflate`]
+/// if that makes it smaller
+fn serialize_keypair(
+    keypair: &common::SecretKey,
+) -> (Zeroizing<Vec<u8>>, Option<Compression>) {
+    let data = keypair
+        .try_to_vec()
+        .map(Zeroizing::new)
+        .expect("Serializing keypair shouldn't fail");
+    maybe_compress(data)
+}
+
+/// Compress the data with [`Compression::Deflate`] only if that makes it
+/// smaller
+fn maybe_compress(
+    data: Zeroizing<Vec<u8>>,
+) -> (Zeroizing<Vec<u8>>, Option<Compression>) {
+    let compressed = Compression::Deflate.compress(&data);
+    if compressed.len() < data.len() {
+        (compressed, Some(Compression::Deflate))
+    } else {
+        (data, None)
+    }
+}
+
+/// The current time in seconds since the Unix epoch
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("The system time shouldn't be before the Unix epoch")
+        .as_secs()
+}
+
+/// Draw a random instance ID for a new keystore
+fn new_instance_id() -> [u8; 16] {
+    use rand::rngs::OsRng;
+    use rand::RngCore;
+
+    let mut instance_id = [0u8; 16];
+    OsRng.fill_bytes(&mut instance_id);
+    instance_id
+}
+
+/// Make encryption secret key from a password. Fails if the KDF parameters
+/// are invalid. Blocks until the memory cost of the derivation fits in the
+/// [`kdf_memory_budget`].
+fn encryption_key(
+    salt: &kdf::Salt,
+    password: String,
+    kdf_params: &KdfParams,
+) -> Result<kdf::SecretKey, orion::errors::UnknownCryptoError> {
+    let _permit = kdf_memory_budget().acquire(kdf_params.memory);
+    kdf::Password::from_slice(password.as_bytes()).and_then(|password| {
+        kdf::derive_key(
+            &password,
+            salt,
+            kdf_params.iterations,
+            kdf_params.memory,
+            32,
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use anoma::types::key::testing::{keypair_1, keypair_2};
+
+    use super::*;
+
+    #[test]
+    fn test_created_by_version_roundtrip() {
+        let password = "password".to_owned();
+        let (stored, _) =
+            StoredKeypair::new(keypair_1(), Some(password.clone()));
+        assert_eq!(
+            stored.created_by_version(),
+            Some(env!("CARGO_PKG_VERSION"))
+        );
+
+        let json = serde_json::to_string(&stored).unwrap();
+        let parsed: StoredKeypair = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            parsed.created_by_version(),
+            Some(env!("CARGO_PKG_VERSION"))
+        );
+        let key = parsed.get(true, Some(password)).unwrap();
+        assert_eq!(key.ref_to(), keypair_1().ref_to());
+    }
+
+    /// A keypair encrypted in the format without a header, with the default
+    /// KDF parameters
+    fn legacy_keypair(
+        keypair: &common::SecretKey,
+        password: &str,
+        salt: kdf::Salt,
+    ) -> EncryptedKeypair {
+        let encrypted = EncryptedKeypair::new_with_sealer(
+            keypair,
+            password.to_owned(),
+            salt,
+            &KdfParams::default(),
+            KeystoreHeader::legacy(),
+            |key, data, _ad| aead::seal(key, data).unwrap(),
+        );
+        EncryptedKeypair::from_legacy_str(&hex::encode(&encrypted.data))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_legacy_encrypted_keypair_parses() {
+        let password = "password".to_owned();
+        let encrypted =
+            legacy_keypair(&keypair_1(), &password, encryption_salt());
+        let legacy = format!(
+            "\"{}{}\"",
+            ENCRYPTED_KEY_PREFIX,
+            hex::encode(&encrypted.data)
+        );
+        let parsed: StoredKeypair = serde_json::from_str(&legacy).unwrap();
+        assert_eq!(parsed.created_by_version(), None);
+        let key = parsed.get(true, Some(password)).unwrap();
+        assert_eq!(key.ref_to(), keypair_1().ref_to());
+    }
+
+    #[test]
+    fn test_redacted_json_has_no_secrets() {
+        let (encrypted, _) =
+            StoredKeypair::new(keypair_1(), Some("password".to_owned()));
+        let encrypted_data = match &encrypted {
+            StoredKeypair::Encrypted(encrypted) => hex::encode(&encrypted.data),
+            _ => unreachable!(),
+        };
+        let (raw, _) = StoredKeypair::new(keypair_2(), None);
+        let keys = vec![
+            ("encrypted".to_owned(), encrypted),
+            ("alice-consensus-key".to_owned(), raw),
+        ];
+
+        let json = to_redacted_json(&keys);
+        assert_eq!(json[0]["role"], "account");
+        assert_eq!(json[1]["role"], "consensus");
+
+        let json = json.to_string();
+
+        assert!(json.contains(&keypair_1().ref_to().to_string()));
+        assert!(json.contains(&keypair_2().ref_to().to_string()));
+        assert!(json.contains("<redacted>"));
+        for secret in [keypair_1(), keypair_2()] {
+            let common::SecretKey::Ed25519(ed25519::SecretKey(sk)) = secret;
+            let seed = hex::encode(sk.to_bytes());
+            assert!(!json.contains(&seed));
+        }
+        assert!(!json.contains(&encrypted_data));
+    }
+
+    #[test]
+    fn test_save_to_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let (stored, _) =
+            StoredKeypair::new(keypair_1(), Some("password".to_owned()));
+        let path = stored.save_to_dir(dir.path()).unwrap();
+
+        let pkh = PublicKeyHash::from(&keypair_1().ref_to());
+        let address = Address::Implicit(ImplicitAddress(pkh)).encode();
+        assert_eq!(path, dir.path().join(format!("{}.key", address)));
+
+        let parsed = StoredKeypair::load_from_file(&path, None).unwrap();
+        assert_eq!(parsed.public_key(), Some(keypair_1().ref_to()));
+    }
+
+    #[test]
+    fn test_verify_wallet_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let (stored, _) =
+            StoredKeypair::new(keypair_1(), Some("password".to_owned()));
+        let good = stored.save_to_dir(dir.path()).unwrap();
+        let (stored, _) = StoredKeypair::new(keypair_2(), None);
+        let corrupted = stored.save_to_dir(dir.path()).unwrap();
+        fs::write(&corrupted, "unencrypted:00").unwrap();
+
+        let report = verify_wallet_dir(dir.path()).unwrap();
+        assert!(!report.is_ok());
+        assert_eq!(report.files.len(), 2);
+        for (path, status) in report.files {
+            if path == good {
+                assert_eq!(status, KeyFileStatus::Ok);
+            } else {
+                assert_eq!(path, corrupted);
+                assert!(matches!(status, KeyFileStatus::Invalid(_)));
+            }
+        }
+    }
+
+    /// A KMS mock that wraps data keys with a fixed local key
+    struct MockKms {
+        key: aead::SecretKey,
+    }
+
+    impl KmsBackend for MockKms {
+        fn key_id(&self) -> &str {
+            "mock-key"
+        }
+
+        fn region(&self) -> Option<&str> {
+            Some("mock-region")
+        }
+
+        fn wrap(&self, plaintext: &[u8]) -> Result<Vec<u8>, KmsError> {
+            aead::seal(&self.key, plaintext)
+                .map_err(|err| KmsError(err.to_string()))
+        }
+
+        fn unwrap(&self, ciphertext: &[u8]) -> Result<Vec<u8>, KmsError> {
+            aead::open(&self.key, ciphertext)
+                .map_err(|err| KmsError(err.to_string()))
+        }
+    }
+
+    #[test]
+    fn test_kms_roundtrip() {
+        let kms = MockKms {
+            key: aead::SecretKey::default(),
+        };
+        let encrypted = EncryptedKeypair::new_kms(&keypair_1(), &kms).unwrap();
+        let parsed =
+            EncryptedKeypair::from_str(&encrypted.to_string()).unwrap();
+        let wrapping = parsed.header().kms.as_ref().unwrap();
+        assert_eq!(wrapping.key_id, "mock-key");
+        assert_eq!(wrapping.region.as_deref(), Some("mock-region"));
+
+        let key = parsed.decrypt_kms(&kms).unwrap();
+        assert_eq!(key.ref_to(), keypair_1().ref_to());
+        assert!(matches!(
+            parsed.decrypt("password".to_owned()),
+            Err(DecryptionError::KmsRequired(_))
+        ));
+
+        let other_kms = MockKms {
+            key: aead::SecretKey::default(),
+        };
+        assert!(parsed.decrypt_kms(&other_kms).is_err());
+    }
+
+    #[test]
+    fn test_recovery_code() {
+        let password = "password".to_owned();
+        let kdf_params = KdfParams {
+            iterations: 3,
+            memory: 8,
+        };
+        let (encrypted, code) = EncryptedKeypair::new_with_recovery(
+            &keypair_1(),
+            password.clone(),
+            &kdf_params,
+        );
+        let parsed =
+            EncryptedKeypair::from_str(&encrypted.to_string()).unwrap();
+        assert_eq!(parsed.header().kdf_params, Some(kdf_params));
+
+        let key = parsed.decrypt(password).unwrap();
+        assert_eq!(key.ref_to(), keypair_1().ref_to());
+
+        // The printed code parses back, regardless of the case and spacing
+        let printed = code.to_string().to_lowercase().replace('-', " ");
+        let code = RecoveryCode::from_str(&printed).unwrap();
+        let key = parsed.decrypt_with_recovery(&code).unwrap();
+        assert_eq!(key.ref_to(), keypair_1().ref_to());
+        assert!(parsed.decrypt("wrong".to_owned()).is_err());
+
+        // A typo is caught by the checksum
+        let mut typo = code.to_string().into_bytes();
+        typo[0] = if typo[0] == b'0' { b'1' } else { b'0' };
+        assert!(matches!(
+            RecoveryCode::from_str(std::str::from_utf8(&typo).unwrap()),
+            Err(ParseRecoveryCodeError::ChecksumMismatch)
+        ));
+
+        // A keypair encrypted without recovery cannot be recovered
+        let encrypted = EncryptedKeypair::new(&keypair_1(), "password".into());
+        assert!(matches!(
+            encrypted.decrypt_with_recovery(&code),
+            Err(DecryptionError::NotRecoverable)
+        ));
+    }
+
+    #[test]
+    fn test_change_wallet_password() {
+        let (recoverable, code) = EncryptedKeypair::new_with_recovery(
+            &keypair_1(),
+            "old".into(),
+            &KdfParams::default(),
+        );
+        let mut keys = vec![
+            StoredKeypair::new(keypair_1(), Some("old".into())).0,
+            StoredKeypair::Encrypted(recoverable),
+            StoredKeypair::new(keypair_2(), None).0,
+        ];
+        change_wallet_password(&mut keys, "old", "new").unwrap();
+        for keypair in &keys {
+            assert!(keypair.get(true, Some("new".into())).is_ok());
+            if keypair.is_encrypted() {
+                assert!(keypair.get(true, Some("old".into())).is_err());
+            }
+        }
+        match &keys[1] {
+            StoredKeypair::Encrypted(encrypted) => {
+                assert!(encrypted.decrypt_with_recovery(&code).is_ok())
+            }
+            _ => unreachable!(),
+        }
+
+        // A key on another password fails the change of all the keys
+        keys.push(StoredKeypair::new(keypair_2(), Some("other".into())).0);
+        let result = change_wallet_password(&mut keys, "new", "newer");
+        assert!(matches!(
+            result,
+            Err(ChangePasswordError::Decryption { index: 3, .. })
+        ));
+        assert!(keys[0].get(true, Some("new".into())).is_ok());
+    }
+
+    #[test]
+    fn test_content_type_json_roundtrip() {
+        let encrypted = EncryptedKeypair::new(&keypair_1(), "password".into());
+        let json = encrypted.to_json();
+        assert_eq!(json["content_type"], encrypted.content_type());
+        let parsed = EncryptedKeypair::from_json(&json).unwrap();
+        assert_eq!(parsed.public_key(), Some(keypair_1().ref_to()));
+
+        let mut json = json;
+        json["content_type"] = "application/vnd.anoma.keystore.v2+hex".into();
+        assert!(matches!(
+            EncryptedKeypair::from_json(&json),
+            Err(ParseEncryptedKeypairError::ContentTypeMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn test_escrow() {
+        use rand::rngs::OsRng;
+        use rand::RngCore;
+
+        let gen_x25519 = || {
+            let mut bytes = [0; 32];
+            OsRng.fill_bytes(&mut bytes);
+            x25519_dalek::StaticSecret::from(bytes)
+        };
+        let auditor = gen_x25519();
+        let auditor_pk = x25519_dalek::PublicKey::from(&auditor);
+        let encrypted = EncryptedKeypair::new_with_escrow(
+            &keypair_1(),
+            "password".into(),
+            &auditor_pk,
+            &KdfParams::default(),
+        );
+        let parsed =
+            EncryptedKeypair::from_str(&encrypted.to_string()).unwrap();
+        assert_eq!(parsed.escrow_auditor(), Some(auditor_pk.as_bytes()));
+
+        let key = parsed.decrypt("password".into()).unwrap();
+        assert_eq!(key.ref_to(), keypair_1().ref_to());
+        let key = parsed.decrypt_as_auditor(&auditor).unwrap();
+        assert_eq!(key.ref_to(), keypair_1().ref_to());
+        assert!(matches!(
+            parsed.decrypt_as_auditor(&gen_x25519()),
+            Err(DecryptionError::EscrowKeyMismatch)
+        ));
+
+        // The escrow survives a password change
+        let changed = parsed
+            .change_password("password".into(), "new".into())
+            .unwrap();
+        assert!(changed.decrypt_as_auditor(&auditor).is_ok());
+    }
+
+    #[test]
+    fn test_rotate_salts() {
+        let salt = |keypair: &StoredKeypair| match keypair {
+            StoredKeypair::Encrypted(encrypted) => {
+                encrypted.data[..encryption_salt().len()].to_vec()
+            }
+            _ => unreachable!(),
+        };
+        let mut keys = vec![
+            StoredKeypair::new(keypair_1(), Some("password".into())).0,
+            StoredKeypair::new(keypair_2(), None).0,
+        ];
+        let old_salt = salt(&keys[0]);
+        assert!(rotate_salts(&mut keys, "password").unwrap().is_empty());
+        assert_ne!(salt(&keys[0]), old_salt);
+        let key = keys[0].get(true, Some("password".into())).unwrap();
+        assert_eq!(key.ref_to(), keypair_1().ref_to());
+
+        assert!(rotate_salts(&mut keys, "wrong").is_err());
+    }
+
+    #[test]
+    fn test_derive_app_key() {
+        let master = keypair_1();
+        let app_a = derive_app_key(&master, "app-a");
+        // The derivation is deterministic
+        assert_eq!(app_a.ref_to(), derive_app_key(&master, "app-a").ref_to());
+        let app_b = derive_app_key(&master, "app-b");
+        assert_ne!(app_a.ref_to(), app_b.ref_to());
+        assert_ne!(app_a.ref_to(), master.ref_to());
+        // Another master yields another key for the same application
+        assert_ne!(
+            app_a.ref_to(),
+            derive_app_key(&keypair_2(), "app-a").ref_to()
+        );
+    }
+
+    #[test]
+    fn test_repair_public() {
+        let password = "password";
+        let mut encrypted =
+            EncryptedKeypair::new(&keypair_1(), password.to_owned());
+        encrypted.header.public_key = Some(keypair_2().ref_to());
+        assert!(matches!(
+            encrypted.decrypt(password.to_owned()),
+            Err(DecryptionError::PublicKeyMismatch)
+        ));
+
+        // The wrong password doesn't authorize the repair
+        assert!(encrypted.repair_public("wrong").is_err());
+        assert_eq!(encrypted.public_key(), Some(keypair_2().ref_to()));
+
+        encrypted.repair_public(password).unwrap();
+        assert_eq!(encrypted.public_key(), Some(keypair_1().ref_to()));
+        let key = encrypted.decrypt(password.to_owned()).unwrap();
+        assert_eq!(key.ref_to(), keypair_1().ref_to());
+    }
+
+    #[test]
+    fn test_verify_against_pinned() {
+        let (stored, _) =
+            StoredKeypair::new(keypair_1(), Some("password".to_owned()));
+        stored.verify_against_pinned(&keypair_1().ref_to()).unwrap();
+        assert!(matches!(
+            stored.verify_against_pinned(&keypair_2().ref_to()),
+            Err(KeyMismatchError::Mismatch { .. })
+        ));
+
+        let legacy = StoredKeypair::Encrypted(EncryptedKeypair {
+            header: KeystoreHeader::legacy(),
+            ..EncryptedKeypair::new(&keypair_1(), "password".to_owned())
+        });
+        assert!(matches!(
+            legacy.verify_against_pinned(&keypair_1().ref_to()),
+            Err(KeyMismatchError::MissingPublicKey)
+        ));
+    }
+
+    #[test]
+    fn test_content_hash() {
+        let encrypted = EncryptedKeypair::new(&keypair_1(), "password".into());
+        let bytes = encrypted.try_to_vec().unwrap();
+        let hash = encrypted.content_hash();
+        assert_eq!(
+            EncryptedKeypair::try_from_slice(&bytes)
+                .unwrap()
+                .content_hash(),
+            hash
+        );
+        // Another salt gives another hash for the same keypair
+        let other = EncryptedKeypair::new(&keypair_1(), "password".into());
+        assert_ne!(other.content_hash(), hash);
+
+        // Changing any byte that still decodes changes the hash
+        let mut decoded = 0;
+        for index in 0..bytes.len() {
+            let mut changed = bytes.clone();
+            changed[index] ^= 1;
+            if let Ok(changed) = EncryptedKeypair::try_from_slice(&changed) {
+                assert_ne!(changed.content_hash(), hash);
+                decoded += 1;
+            }
+        }
+        // At least every byte of the encrypted data is covered
+        assert!(decoded >= encrypted.data.len());
+    }
+
+    #[test]
+    fn test_machine_binding() {
+        let dir = tempfile::tempdir().unwrap();
+        let this_machine = MachineIdFile(dir.path().join("this"));
+        let other_machine = MachineIdFile(dir.path().join("other"));
+        fs::write(&this_machine.0, "0123456789abcdef\n").unwrap();
+        fs::write(&other_machine.0, "fedcba9876543210\n").unwrap();
+
+        let password = "password".to_owned();
+        let encrypted = EncryptedKeypair::new_bound_to_machine(
+            &keypair_1(),
+            password.clone(),
+            &KdfParams::default(),
+            &this_machine,
+        )
+        .unwrap();
+        let key = encrypted
+            .decrypt_on_machine(password.clone(), &this_machine)
+            .unwrap();
+        assert_eq!(key.ref_to(), keypair_1().ref_to());
+        assert!(matches!(
+            encrypted.decrypt_on_machine(password.clone(), &other_machine),
+            Err(DecryptionError::MachineBindingMismatch)
+        ));
+        assert!(matches!(
+            encrypted.decrypt_on_machine("wrong".into(), &this_machine),
+            Err(DecryptionError::DecryptionError)
+        ));
+        // The password alone doesn't decrypt a bound keypair
+        let mut unbound =
+            EncryptedKeypair::try_from_slice(&encrypted.try_to_vec().unwrap())
+                .unwrap();
+        unbound.header.machine_binding = None;
+        assert!(unbound.decrypt(password).is_err());
+    }
+
+    #[test]
+    fn test_load_with_commitment() {
+        let dir = tempfile::tempdir().unwrap();
+        let (stored, _) =
+            StoredKeypair::new(keypair_1(), Some("password".to_owned()));
+        let commitment = stored.commitment();
+        let path = stored.save_to_dir(dir.path()).unwrap();
+        let loaded =
+            StoredKeypair::load_from_file(&path, Some(&commitment)).unwrap();
+        assert_eq!(loaded.commitment(), commitment);
+
+        // Substitute the file with another keypair
+        let (other, _) =
+            StoredKeypair::new(keypair_2(), Some("password".to_owned()));
+        fs::write(&path, other.to_string()).unwrap();
+        assert!(StoredKeypair::load_from_file(&path, None).is_ok());
+        assert!(matches!(
+            StoredKeypair::load_from_file(&path, Some(&commitment)),
+            Err(LoadKeypairError::CommitmentMismatch)
+        ));
+    }
+
+    #[test]
+    fn test_estimate_unlock_time() {
+        let params = KdfParams {
+            iterations: 3,
+            memory: 8,
+        };
+        let raw = || StoredKeypair::new(keypair_1(), None).0;
+        let encrypted = || {
+            StoredKeypair::new_with_kdf_params(
+                keypair_1(),
+                Some("password".into()),
+                &params,
+            )
+            .0
+        };
+        assert_eq!(estimate_unlock_time(&[raw()], &params), Duration::ZERO);
+        let estimate =
+            estimate_unlock_time(&[raw(), encrypted(), encrypted()], &params);
+        assert!(estimate > Duration::ZERO);
+    }
+
+    /// RFC 6238 test vectors of TOTP with HMAC-SHA1
+    #[test]
+    fn test_totp_rfc6238() {
+        let secret = b"12345678901234567890";
+        let params = TotpParams {
+            step: 30,
+            digits: 8,
+        };
+        assert_eq!(params.code(secret, 59), "94287082");
+        assert_eq!(params.code(secret, 1111111109), "07081804");
+        assert_eq!(params.code(secret, 20000000000), "65353130");
+    }
+
+    #[test]
+    fn test_totp_keypair() {
+        let secret = b"totp secret";
+        let params = TotpParams::default();
+        let password = "password".to_owned();
+        let kdf_params = KdfParams {
+            iterations: 3,
+            memory: 8,
+        };
+        let encrypted = EncryptedKeypair::new_with_totp(
+            &keypair_1(),
+            password.clone(),
+            secret,
+            params,
+            &kdf_params,
+        );
+        let now = 1_000_000;
+        let code = params.code(secret, now);
+        let key = encrypted
+            .decrypt_with_totp(password.clone(), &code, now, 0)
+            .unwrap();
+        assert_eq!(key.ref_to(), keypair_1().ref_to());
+
+        // A code of the previous step is only accepted within the window
+        let previous = params.code(secret, now - params.step);
+        assert!(encrypted
+            .decrypt_with_totp(password.clone(), &previous, now, 1)
+            .is_ok());
+        assert!(matches!(
+            encrypted.decrypt_with_totp(password.clone(), &previous, now, 0),
+            Err(DecryptionError::InvalidTotpCode)
+        ));
+        // A wrong password is told apart from a wrong code
+        assert!(matches!(
+            encrypted.decrypt_with_totp("wrong".into(), &code, now, 0),
+            Err(DecryptionError::DecryptionError)
+        ));
+        assert!(matches!(
+            encrypted.decrypt(password),
+            Err(DecryptionError::TotpRequired)
+        ));
+    }
+
+    #[test]
+    fn test_keys_for_address() {
+        let address = |sk: &common::SecretKey| {
+            Address::Implicit(ImplicitAddress(PublicKeyHash::from(
+                &sk.ref_to(),
+            )))
+        };
+        let keys = vec![
+            ("raw".to_owned(), StoredKeypair::new(keypair_1(), None).0),
+            (
+                "encrypted".to_owned(),
+                StoredKeypair::new(keypair_1(), Some("password".into())).0,
+            ),
+            ("other".to_owned(), StoredKeypair::new(keypair_2(), None).0),
+        ];
+        assert_eq!(
+            keys_for_address(&keys, &address(&keypair_1())),
+            vec!["raw", "encrypted"]
+        );
+        assert_eq!(
+            keys_for_address(&keys, &address(&keypair_2())),
+            vec!["other"]
+        );
+        assert!(keys_for_address(&keys[..1], &address(&keypair_2())).is_empty());
+    }
+
+    #[test]
+    fn test_watch_only_roundtrip() {
+        let stored = StoredKeypair::WatchOnly(keypair_1().ref_to());
+        let parsed: StoredKeypair = stored.to_string().parse().unwrap();
+        assert!(parsed.is_watch_only());
+        assert!(!parsed.is_encrypted());
+        assert_eq!(parsed.public_key(), Some(keypair_1().ref_to()));
+        assert!(matches!(
+            parsed.get(true, None),
+            Err(DecryptionError::WatchOnly)
+        ));
+    }
+
+    #[test]
+    fn test_instance_id() {
+        let password = "password".to_owned();
+        let (first, _) =
+            StoredKeypair::new(keypair_1(), Some(password.clone()));
+        let (second, _) =
+            StoredKeypair::new(keypair_1(), Some(password.clone()));
+        let first_id = first.instance_id().unwrap();
+        assert_ne!(Some(first_id), second.instance_id());
+
+        // It's kept through serialization and changing the password
+        let parsed: StoredKeypair = first.to_string().parse().unwrap();
+        assert_eq!(parsed.instance_id(), Some(first_id));
+        let encrypted = match parsed {
+            StoredKeypair::Encrypted(encrypted) => encrypted,
+            _ => unreachable!(),
+        };
+        let changed = encrypted
+            .change_password(password, "new password".to_owned())
+            .unwrap();
+        assert_eq!(changed.instance_id(), Some(first_id));
+        let keypair = changed.decrypt("new password".to_owned()).unwrap();
+        assert_eq!(keypair.ref_to(), keypair_1().ref_to());
+
+        let (raw, _) = StoredKeypair::new(keypair_1(), None);
+        assert_eq!(raw.instance_id(), None);
+    }
+
+    #[test]
+    fn test_wallet_to_csv() {
+        let (raw, _) = StoredKeypair::new(keypair_1(), None);
+        let watch_only = StoredKeypair::WatchOnly(keypair_2().ref_to());
+        let keys = vec![
+            ("validator-consensus-key".to_owned(), raw),
+            ("a \"quoted\", name".to_owned(), watch_only),
+        ];
+        let csv = wallet_to_csv(&keys);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(
+            lines[0],
+            "name,public_key,address,role,encryption,kdf_iterations,kdf_memory"
+        );
+        let pk = keypair_1().ref_to();
+        let address =
+            Address::Implicit(ImplicitAddress(PublicKeyHash::from(&pk)));
+        assert_eq!(
+            lines[1],
+            format!(
+                "validator-consensus-key,{},{},{},none,,",
+                pk,
+                address.encode(),
+                KeyRole::of_alias("validator-consensus-key")
+            )
+        );
+        assert!(lines[2].starts_with("\"a \"\"quoted\"\", name\","));
+        assert!(lines[2].ends_with(",watch-only,,"));
+        assert_eq!(lines.len(), 3);
+    }
+
+    #[test]
+    fn test_new_with_salt_is_deterministic() {
+        let params = KdfParams {
+            iterations: 3,
+            memory: 8,
+        };
+        let encrypt = || {
+            EncryptedKeypair::new_with_salt(
+                &keypair_1(),
+                "password".to_owned(),
+                [1; 16],
+                [2; 24],
+                &params,
+            )
+        };
+        let encrypted = encrypt();
+        assert_eq!(encrypted.to_string(), encrypt().to_string());
+        assert_eq!(encrypted.instance_id(), None);
+        let keypair = encrypted.decrypt("password".to_owned()).unwrap();
+        assert_eq!(keypair.ref_to(), keypair_1().ref_to());
+    }
+
+    #[test]
+    fn test_metadata_protection() {
+        let (stored, _) =
+            StoredKeypair::new(keypair_1(), Some("password".to_owned()));
+        let protection = stored.metadata_protection();
+        assert_eq!(protection.public_key, FieldProtection::Authenticated);
+        assert_eq!(protection.kdf_params, FieldProtection::Authenticated);
+        assert_eq!(protection.kms, FieldProtection::Absent);
+        assert_eq!(protection.instance_id, FieldProtection::Authenticated);
+        assert!(protection.unauthenticated_fields().is_empty());
+
+        let (raw, _) = StoredKeypair::new(keypair_1(), None);
+        assert_eq!(raw.metadata_protection(), MetadataProtection::absent());
+        let watch_only = StoredKeypair::WatchOnly(keypair_1().ref_to());
+        assert_eq!(
+            watch_only.metadata_protection().unauthenticated_fields(),
+            vec!["public_key"]
+        );
+    }
+
+    #[test]
+    fn test_compression_roundtrip() {
+        // A keypair alone is too small to shrink
+        let (stored, _) =
+            StoredKeypair::new(keypair_1(), Some("password".to_owned()));
+        let encrypted = match &stored {
+            StoredKeypair::Encrypted(encrypted) => encrypted,
+            _ => unreachable!(),
+        };
+        assert_eq!(encrypted.header.compression, None);
+
+        let data: Vec<u8> = b"metadata ".repeat(100);
+        let (compressed, compression) =
+            maybe_compress(Zeroizing::new(data.clone()));
+        assert_eq!(compression, Some(Compression::Deflate));
+        assert!(compressed.len() < data.len());
+        let decompressed =
+            Compression::Deflate.decompress(&compressed).unwrap();
+        assert_eq!(*decompressed, data);
+
+        let bomb =
+            Compression::Deflate.compress(&vec![0; MAX_DECOMPRESSED_LEN + 1]);
+        assert!(matches!(
+            Compression::Deflate.decompress(&bomb),
+            Err(DecryptionError::DeserializingError)
+        ));
+    }
+
+    #[test]
+    fn test_stable_id_order() {
+        let key_1 = SortablePublicKey(keypair_1().ref_to());
+        let key_2 = SortablePublicKey(keypair_2().ref_to());
+        assert_eq!(key_1.cmp(&key_1.clone()), std::cmp::Ordering::Equal);
+        let mut keys = vec![key_1.clone(), key_2.clone(), key_1.clone()];
+        let mut reversed = vec![key_2, key_1.clone(), key_1.clone()];
+        keys.sort();
+        reversed.sort();
+        assert_eq!(keys, reversed);
+        // Equal keys sort next to each other
+        assert!(keys[0] == keys[1] || keys[1] == keys[2]);
+
+        let (stored, _) =
+            StoredKeypair::new(keypair_1(), Some("password".to_owned()));
+        assert_eq!(stored.stable_id(), Some(key_1.stable_id()));
+    }
+
+    #[test]
+    fn test_migrate_salt_length() {
+        let params = KdfParams {
+            iterations: 3,
+            memory: 8,
+        };
+        let password = "password";
+        let salt = kdf::Salt::from_slice(&[1; 32]).unwrap();
+        let encrypted = EncryptedKeypair::new_with_sealer(
+            &keypair_1(),
+            password.to_owned(),
+            salt,
+            &params,
+            KeystoreHeader::new(),
+            seal_with_ad,
+        );
+        assert_eq!(encrypted.header.salt_len, Some(32));
+        assert_eq!(encrypted.detect_salt_length(password), None);
+
+        // A legacy keystore with a 32 bytes salt, which doesn't record it
+        let salt = kdf::Salt::from_slice(&[1; 32]).unwrap();
+        let mut encrypted = legacy_keypair(&keypair_1(), password, salt);
+        assert!(encrypted.decrypt(password.to_owned()).is_err());
+        assert_eq!(encrypted.detect_salt_length("wrong"), None);
+        assert_eq!(encrypted.migrate_salt_length(password), Some(32));
+        let keypair = encrypted.decrypt(password.to_owned()).unwrap();
+        assert_eq!(keypair.ref_to(), keypair_1().ref_to());
+    }
+
+    #[test]
+    fn test_verify_roundtrip() {
+        let (encrypted, _) =
+            StoredKeypair::new(keypair_1(), Some("password".to_owned()));
+        assert!(encrypted.verify_roundtrip("password", &keypair_1()));
+        assert!(!encrypted.verify_roundtrip("wrong", &keypair_1()));
+        assert!(!encrypted.verify_roundtrip("password", &keypair_2()));
+
+        let (raw, _) = StoredKeypair::new(keypair_1(), None);
+        assert!(raw.verify_roundtrip("", &keypair_1()));
+        let watch_only = StoredKeypair::WatchOnly(keypair_1().ref_to());
+        assert!(!watch_only.verify_roundtrip("", &keypair_1()));
+    }
+
+    #[test]
+    fn test_keys_exceeding_age() {
+        let day = Duration::from_secs(24 * 60 * 60);
+        let (fresh, _) =
+            StoredKeypair::new(keypair_1(), Some("password".to_owned()));
+        let mut stale = EncryptedKeypair::new(&keypair_2(), "password".into());
+        let created_at = stale.created_at().unwrap();
+        stale.header.created_at = Some(created_at - 31 * day.as_secs());
+        let mut legacy = EncryptedKeypair::new(&keypair_2(), "password".into());
+        legacy.header.created_at = None;
+        let (raw, _) = StoredKeypair::new(keypair_1(), None);
+        let keys = [
+            fresh,
+            StoredKeypair::Encrypted(stale),
+            StoredKeypair::Encrypted(legacy),
+            raw,
+        ];
+
+        assert!(!keys[0].is_older_than(30 * day, created_at));
+        assert!(
+            keys[0].is_older_than(30 * day, created_at + 31 * day.as_secs())
+        );
+        assert_eq!(keys_exceeding_age(&keys, 30 * day, created_at), vec![1, 2]);
+        assert_eq!(keys_exceeding_age(&keys, 60 * day, created_at), vec![2]);
+    }
+
+    #[test]
+    fn test_dual_control() {
+        let params = KdfParams::default();
+        let encrypted = EncryptedKeypair::new_with_dual_control(
+            &keypair_1(),
+            "alice".into(),
+            "bob".into(),
+            &params,
+        );
+        let parsed =
+            EncryptedKeypair::from_str(&encrypted.to_string()).unwrap();
+        assert!(parsed.is_well_formed());
+
+        let key = parsed
+            .decrypt_with_authorizers(Some("alice".into()), Some("bob".into()))
+            .unwrap();
+        assert_eq!(key.ref_to(), keypair_1().ref_to());
+        // Neither password alone decrypts the keypair
+        assert!(matches!(
+            parsed.decrypt_with_authorizers(Some("alice".into()), None),
+            Err(DecryptionError::InsufficientAuthorizers)
+        ));
+        assert!(matches!(
+            parsed.decrypt_with_authorizers(None, Some("bob".into())),
+            Err(DecryptionError::InsufficientAuthorizers)
+        ));
+        assert!(matches!(
+            parsed.decrypt("alice".into()),
+            Err(DecryptionError::InsufficientAuthorizers)
+        ));
+        assert!(matches!(
+            parsed.decrypt_with_authorizers(
+                Some("bob".into()),
+                Some("alice".into())
+            ),
+            Err(DecryptionError::DecryptionError)
+        ));
+        assert!(matches!(
+            EncryptedKeypair::new(&keypair_1(), "alice".into())
+                .decrypt_with_authorizers(
+                    Some("alice".into()),
+                    Some("bob".into())
+                ),
+            Err(DecryptionError::NotDualControl)
+        ));
+    }
+
+    #[test]
+    fn test_uses_default_salt() {
+        let params = KdfParams {
+            iterations: 3,
+            memory: 8,
+        };
+        let with_salt = |salt| {
+            EncryptedKeypair::new_with_salt(
+                &keypair_1(),
+                "password".to_owned(),
+                salt,
+                [2; 24],
+                &params,
+            )
+        };
+        assert!(with_salt([0; 16]).uses_default_salt());
+        assert!(with_salt([1; 16]).uses_default_salt());
+        let mut salt = [0; 16];
+        salt[15] = 1;
+        assert!(!with_salt(salt).uses_default_salt());
+        let encrypted = EncryptedKeypair::new_with_kdf_params(
+            &keypair_1(),
+            "password".to_owned(),
+            &params,
+        );
+        assert!(!encrypted.uses_default_salt());
+    }
+
+    #[test]
+    fn test_check_format_compatibility() {
+        let (current, _) =
+            StoredKeypair::new(keypair_1(), Some("password".to_owned()));
+        let legacy = format!(
+            "{}{}",
+            ENCRYPTED_KEY_PREFIX,
+            hex::encode(
+                &legacy_keypair(&keypair_2(), "password", encryption_salt())
+                    .data
+            )
+        );
+        let legacy = StoredKeypair::from_str(&legacy).unwrap();
+        let (raw, _) = StoredKeypair::new(keypair_1(), None);
+        let keys = [current, legacy, raw];
+        assert_eq!(keys[0].format_version(), Some(KEYSTORE_FORMAT_VERSION));
+        assert_eq!(
+            keys[1].format_version(),
+            Some(LEGACY_KEYSTORE_FORMAT_VERSION)
+        );
+        assert_eq!(keys[2].format_version(), None);
+
+        assert!(check_format_compatibility(&keys, &[0, 1]).is_empty());
+        assert_eq!(
+            check_format_compatibility(&keys, &[KEYSTORE_FORMAT_VERSION]),
+            vec![IncompatibleKey {
+                index: 1,
+                public_key: None,
+                required_version: LEGACY_KEYSTORE_FORMAT_VERSION,
+            }]
+        );
+        let incompatible = check_format_compatibility(&keys, &[]);
+        assert_eq!(
+            incompatible
+                .iter()
+                .map(|key| (key.index, key.required_version))
+                .collect::<Vec<_>>(),
+            vec![(0, 1), (1, 0)]
+        );
+        assert_eq!(incompatible[0].public_key, Some(keypair_1().ref_to()));
+    }
+
+    #[test]
+    fn test_modified_header_fails_decryption() {
+        let password = "password".to_owned();
+        let encrypted = EncryptedKeypair::new(&keypair_1(), password.clone());
+        let other = EncryptedKeypair::new(&keypair_1(), password.clone());
+        let with_header = |header: KeystoreHeader| EncryptedKeypair {
+            header,
+            data: encrypted.data.clone(),
+        };
+        assert!(with_header(encrypted.header.clone())
+            .decrypt(password.clone())
+            .is_ok());
+
+        let modified = [
+            KeystoreHeader {
+                instance_id: Some([0; 16]),
+                ..encrypted.header.clone()
+            },
+            KeystoreHeader {
+                created_at: Some(0),
+                ..encrypted.header.clone()
+            },
+            KeystoreHeader {
+                created_by_version: None,
+                ..encrypted.header.clone()
+            },
+            KeystoreHeader {
+                unknown_fields: [(100, vec![1])].into_iter().collect(),
+                ..encrypted.header.clone()
+            },
+            // Stripped
+            KeystoreHeader::new(),
+            // Swapped with the header of another keystore
+            other.header.clone(),
+        ];
+        for header in modified {
+            assert!(matches!(
+                with_header(header).decrypt(password.clone()),
+                Err(DecryptionError::DecryptionError)
+            ));
+        }
+        // The public key is checked against the decrypted keypair instead
+        assert!(matches!(
+            with_header(KeystoreHeader {
+                public_key: Some(keypair_2().ref_to()),
+                ..encrypted.header.clone()
+            })
+            .decrypt(password),
+            Err(DecryptionError::PublicKeyMismatch)
+        ));
+    }
+
+    #[test]
+    fn test_keystore_header_unknown_fields() {
+        let password = "password";
+        let header = KeystoreHeader {
+            unknown_fields: [(100, vec![1, 2, 3])].into_iter().collect(),
+            ..KeystoreHeader::new()
+        };
+        let encrypted = EncryptedKeypair::new_with_sealer(
+            &keypair_1(),
+            password.to_owned(),
+            encryption_salt(),
+            &KdfParams::default(),
+            header,
+            seal_with_ad,
+        );
+
+        // An unknown field is kept and still authenticated
+        let parsed =
+            EncryptedKeypair::from_str(&encrypted.to_string()).unwrap();
+        assert_eq!(
+            parsed.header.unknown_fields,
+            encrypted.header.unknown_fields
+        );
+        assert_eq!(parsed.to_string(), encrypted.to_string());
+        assert!(parsed.decrypt(password.to_owned()).is_ok());
+        let mut stripped = parsed;
+        stripped.header.unknown_fields.clear();
+        assert!(stripped.decrypt(password.to_owned()).is_err());
+    }
+
+    #[test]
+    fn test_keystore_header_encoding() {
+        let header = KeystoreHeader::new();
+        let bytes = header.try_to_vec().unwrap();
+        assert_eq!(bytes[0], KEYSTORE_HEADER_VERSION);
+        let decoded = KeystoreHeader::try_from_slice(&bytes).unwrap();
+        assert_eq!(decoded.try_to_vec().unwrap(), bytes);
+
+        // A newer version is rejected instead of misread
+        let mut newer = bytes;
+        newer[0] = KEYSTORE_HEADER_VERSION + 1;
+        assert!(KeystoreHeader::try_from_slice(&newer).is_err());
+
+        // Fields must be in increasing tag order, once
+        let encode = |fields: Vec<(u8, Vec<u8>)>| {
+            (KEYSTORE_HEADER_VERSION, fields).try_to_vec().unwrap()
+        };
+        let created_at = 1u64.try_to_vec().unwrap();
+        let instance_id = [1u8; 16].try_to_vec().unwrap();
+        assert!(KeystoreHeader::try_from_slice(&encode(vec![
+            (header_tag::CREATED_AT, created_at.clone()),
+            (header_tag::INSTANCE_ID, instance_id),
+        ]))
+        .is_err());
+        assert!(KeystoreHeader::try_from_slice(&encode(vec![
+            (header_tag::CREATED_AT, created_at.clone()),
+            (header_tag::CREATED_AT, created_at),
+        ]))
+        .is_err());
+        assert!(KeystoreHeader::try_from_slice(&encode(vec![(
+            header_tag::LEGACY,
+            vec![1]
+        )]))
+        .is_err());
+    }
+
+    #[test]
+    fn test_v1_keypair_is_rejected_by_older_versions() {
+        let (stored, _) =
+            StoredKeypair::new(keypair_1(), Some("password".to_owned()));
+        let stored = stored.to_string();
+        assert!(stored.starts_with(ENCRYPTED_KEY_V1_PREFIX));
+        // The only prefixes of encrypted and raw keypairs known before the
+        // header was introduced
+        assert!(!stored.starts_with(ENCRYPTED_KEY_PREFIX));
+        assert!(!stored.starts_with(UNENCRYPTED_KEY_PREFIX));
+    }
+
+    #[test]
+    fn test_recovery_and_escrow_are_authenticated() {
+        let password = "password".to_owned();
+        let modified =
+            |encrypted: &EncryptedKeypair,
+             modify: &dyn Fn(&mut KeystoreHeader)| {
+                let mut header = encrypted.header.clone();
+                modify(&mut header);
+                EncryptedKeypair {
+                    header,
+                    data: encrypted.data.clone(),
+                }
+            };
+
+        let (recoverable, code) = EncryptedKeypair::new_with_recovery(
+            &keypair_1(),
+            password.clone(),
+            &KdfParams::default(),
+        );
+        assert_eq!(
+            recoverable.metadata_protection().recovery,
+            FieldProtection::Authenticated
+        );
+        let flips: [&dyn Fn(&mut KeystoreHeader); 2] = [
+            &|header| header.recovery.as_mut().unwrap().recovery_salt[0] ^= 1,
+            &|header| {
+                header.recovery.as_mut().unwrap().recovery_wrapped_data_key
+                    [0] ^= 1
+            },
+        ];
+        for flip in flips {
+            let modified = modified(&recoverable, flip);
+            assert!(modified.decrypt(password.clone()).is_err());
+            assert!(modified.decrypt_with_recovery(&code).is_err());
+        }
+
+        let auditor = x25519_dalek::StaticSecret::from([7; 32]);
+        let escrowed = EncryptedKeypair::new_with_escrow(
+            &keypair_1(),
+            password.clone(),
+            &x25519_dalek::PublicKey::from(&auditor),
+            &KdfParams::default(),
+        );
+        assert_eq!(
+            escrowed.metadata_protection().escrow,
+            FieldProtection::Authenticated
+        );
+        let flips: [&dyn Fn(&mut KeystoreHeader); 3] = [
+            &|header| {
+                header.escrow.as_mut().unwrap().auditor_public_key[0] ^= 1
+            },
+            &|header| {
+                header.escrow.as_mut().unwrap().ephemeral_public_key[0] ^= 1
+            },
+            &|header| header.escrow.as_mut().unwrap().wrapped_data_key[0] ^= 1,
+        ];
+        for flip in flips {
+            let modified = modified(&escrowed, flip);
+            assert!(modified.decrypt(password.clone()).is_err());
+            assert!(modified.decrypt_as_auditor(&auditor).is_err());
+        }
+    }
+
+    #[test]
+    fn test_change_mixed_wallet_password() {
+        let kdf_params = KdfParams {
+            iterations: 3,
+            memory: 8,
+        };
+        let kms = MockKms {
+            key: aead::SecretKey::default(),
+        };
+        let mut keys = vec![
+            StoredKeypair::new(keypair_1(), Some("old".into())).0,
+            StoredKeypair::Encrypted(
+                EncryptedKeypair::new_kms(&keypair_1(), &kms).unwrap(),
+            ),
+            StoredKeypair::Encrypted(EncryptedKeypair::new_with_totp(
+                &keypair_1(),
+                "old".into(),
+                b"12345678901234567890",
+                TotpParams::default(),
+                &kdf_params,
+            )),
+            StoredKeypair::Encrypted(EncryptedKeypair::new_with_dual_control(
+                &keypair_1(),
+                "old".into(),
+                "other".into(),
+                &kdf_params,
+            )),
+            StoredKeypair::new(keypair_2(), None).0,
+        ];
+        let unchanged: Vec<String> =
+            keys.iter().map(|keypair| keypair.to_string()).collect();
+
+        let skipped = change_wallet_password(&mut keys, "old", "new").unwrap();
+        assert_eq!(skipped, vec![1, 2, 3]);
+        assert!(keys[0].get(true, Some("new".into())).is_ok());
+        for (keypair, unchanged) in keys.iter().zip(unchanged).skip(1) {
+            assert_eq!(keypair.to_string(), unchanged);
+        }
+
+        // Hybrid keypairs are re-encrypted with both of their secret keys
+        #[cfg(feature = "pq")]
+        {
+            let hybrid = HybridSecretKey::generate();
+            let mut keys =
+                vec![StoredKeypair::Encrypted(EncryptedKeypair::new_hybrid(
+                    &hybrid,
+                    "old".into(),
+                    &kdf_params,
+                ))];
+            let skipped =
+                change_wallet_password(&mut keys, "old", "new").unwrap();
+            assert!(skipped.is_empty());
+            let decrypted = keys[0].get_hybrid(Some("new".into())).unwrap();
+            assert_eq!(decrypted.public_key(), hybrid.public_key());
+            assert!(keys[0].get_hybrid(Some("old".into())).is_err());
+        }
+    }
+
+    #[test]
+    fn test_verify_wallet_dir_checksums() {
+        let dir = tempfile::tempdir().unwrap();
+        let (stored, _) =
+            StoredKeypair::new(keypair_1(), Some("password".to_owned()));
+        let corrupted = stored.save_to_dir(dir.path()).unwrap();
+        let (stored, _) =
+            StoredKeypair::new(keypair_2(), Some("password".to_owned()));
+        let unchecked = stored.save_to_dir(dir.path()).unwrap();
+        fs::write(&unchecked, stored.to_string()).unwrap();
+
+        // Flip a digit of the encrypted data, which still parses
+        let content = fs::read_to_string(&corrupted).unwrap();
+        let (serialized, checksum) = split_key_file(&content);
+        let mut serialized = serialized.to_owned().into_bytes();
+        let last = serialized.len() - 1;
+        serialized[last] = if serialized[last] == b'0' { b'1' } else { b'0' };
+        let serialized = String::from_utf8(serialized).unwrap();
+        assert!(StoredKeypair::from_str(&serialized).is_ok());
+        fs::write(
+            &corrupted,
+            format!("{}\n{}\n", serialized, checksum.unwrap()),
+        )
+        .unwrap();
+
+        let report = verify_wallet_dir(dir.path()).unwrap();
+        assert!(!report.is_ok());
+        for (path, status) in report.files {
+            if path == corrupted {
+                assert_eq!(status, KeyFileStatus::ChecksumMismatch);
+            } else {
+                assert_eq!(path, unchecked);
+                assert_eq!(status, KeyFileStatus::NoChecksum);
+            }
+        }
+        assert!(matches!(
+            StoredKeypair::load_from_file(&corrupted, None),
+            Err(LoadKeypairError::ChecksumMismatch)
+        ));
+        assert!(StoredKeypair::load_from_file(&unchecked, None).is_ok());
+    }
+
+    #[test]
+    fn test_change_password_keeps_the_header() {
+        let header = KeystoreHeader {
+            unknown_fields: [(100, vec![1, 2, 3])].into_iter().collect(),
+            ..KeystoreHeader::new()
+        };
+        let encrypted = EncryptedKeypair::new_with_sealer(
+            &keypair_1(),
+            "old".to_owned(),
+            encryption_salt(),
+            &KdfParams::default(),
+            header,
+            seal_with_ad,
+        );
+        assert!(encrypted.decrypt("old".to_owned()).is_ok());
+        let changed = EncryptedKeypair::from_str(
+            &encrypted
+                .change_password("old".to_owned(), "new".to_owned())
+                .unwrap()
+                .to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            changed.header.unknown_fields,
+            encrypted.header.unknown_fields
+        );
+        assert_eq!(
+            changed.created_by_version(),
+            encrypted.created_by_version()
+        );
+        assert_eq!(changed.instance_id(), encrypted.instance_id());
+        let key = changed.decrypt("new".to_owned()).unwrap();
+        assert_eq!(key.ref_to(), keypair_1().ref_to());
+        assert!(changed.decrypt("old".to_owned()).is_err());
+
+        // The unknown field is still authenticated
+        let mut stripped = changed;
+        stripped.header.unknown_fields.clear();
+        assert!(stripped.decrypt("new".to_owned()).is_err());
+    }
+
+    #[test]
+    fn test_rotate_mixed_wallet_salts() {
+        let kdf_params = KdfParams {
+            iterations: 3,
+            memory: 8,
+        };
+        let kms = MockKms {
+            key: aead::SecretKey::default(),
+        };
+        let mut keys = vec![
+            StoredKeypair::new(keypair_1(), Some("password".into())).0,
+            StoredKeypair::Encrypted(
+                EncryptedKeypair::new_kms(&keypair_1(), &kms).unwrap(),
+            ),
+            StoredKeypair::Encrypted(EncryptedKeypair::new_with_totp(
+                &keypair_1(),
+                "password".into(),
+                b"12345678901234567890",
+                TotpParams::default(),
+                &kdf_params,
+            )),
+            StoredKeypair::Encrypted(EncryptedKeypair::new_with_dual_control(
+                &keypair_1(),
+                "password".into(),
+                "other".into(),
+                &kdf_params,
+            )),
+            StoredKeypair::new(keypair_2(), None).0,
+        ];
+        #[cfg(feature = "pq")]
+        let hybrid = HybridSecretKey::generate();
+        #[cfg(feature = "pq")]
+        keys.push(StoredKeypair::Encrypted(EncryptedKeypair::new_hybrid(
+            &hybrid,
+            "password".into(),
+            &kdf_params,
+        )));
+        let unchanged: Vec<String> =
+            keys.iter().map(|keypair| keypair.to_string()).collect();
+
+        let skipped = rotate_salts(&mut keys, "password").unwrap();
+        assert_eq!(skipped, vec![1, 2, 3]);
+        for index in skipped {
+            assert_eq!(keys[index].to_string(), unchanged[index]);
+        }
+        assert_ne!(keys[0].to_string(), unchanged[0]);
+        let key = keys[0].get(true, Some("password".into())).unwrap();
+        assert_eq!(key.ref_to(), keypair_1().ref_to());
+        assert_eq!(keys[4].to_string(), unchanged[4]);
+
+        #[cfg(feature = "pq")]
+        {
+            assert_ne!(keys[5].to_string(), unchanged[5]);
+            let decrypted =
+                keys[5].get_hybrid(Some("password".into())).unwrap();
+            assert_eq!(decrypted.public_key(), hybrid.public_key());
+        }
+    }
+
+    #[test]
+    fn test_legacy_metadata_protection() {
+        let encrypted =
+            legacy_keypair(&keypair_1(), "password", encryption_salt());
+        assert!(encrypted.header.associated_data().is_empty());
+        assert_eq!(
+            encrypted.metadata_protection(),
+            MetadataProtection::absent()
+        );
+        let key = encrypted.decrypt("password".to_owned()).unwrap();
+        assert_eq!(key.ref_to(), keypair_1().ref_to());
+    }
+
+    #[test]
+    fn test_v1_metadata_protection() {
+        let password = "password".to_owned();
+        let encrypted = EncryptedKeypair::new(&keypair_1(), password.clone());
+        let mut without_public_key = encrypted.header.clone();
+        without_public_key.public_key = None;
+        assert_eq!(
+            encrypted.header.associated_data(),
+            without_public_key.try_to_vec().unwrap()
+        );
+        let protection = encrypted.metadata_protection();
+        assert_eq!(protection.public_key, FieldProtection::Authenticated);
+        assert_eq!(protection.created_at, FieldProtection::Authenticated);
+        assert_eq!(
+            protection.password_wrapped_data_key,
+            FieldProtection::Absent
+        );
+        assert!(protection.unauthenticated_fields().is_empty());
+
+        // The header is authenticated by the decryption, and the public key
+        // by the check against the decrypted keypair
+        let mut tampered = EncryptedKeypair {
+            header: encrypted.header.clone(),
+            data: encrypted.data.clone(),
+        };
+        tampered.header.created_at = Some(0);
+        assert!(matches!(
+            tampered.decrypt(password.clone()),
+            Err(DecryptionError::DecryptionError)
+        ));
+        let mut tampered = encrypted;
+        tampered.header.public_key = Some(keypair_2().ref_to());
+        assert!(matches!(
+            tampered.decrypt(password),
+            Err(DecryptionError::PublicKeyMismatch)
+        ));
+    }
+
+    #[test]
+    fn test_data_key_metadata_protection() {
+        let password = "password".to_owned();
+        let (encrypted, _) = EncryptedKeypair::new_with_recovery(
+            &keypair_1(),
+            password.clone(),
+            &KdfParams::default(),
+        );
+        let mut without_public_key = encrypted.header.clone();
+        without_public_key.public_key = None;
+        assert_eq!(
+            encrypted.header.associated_data(),
+            without_public_key.try_to_vec().unwrap()
+        );
+        let protection = encrypted.metadata_protection();
+        assert_eq!(
+            protection.password_wrapped_data_key,
+            FieldProtection::Authenticated
+        );
+        assert_eq!(protection.recovery, FieldProtection::Authenticated);
+        assert!(protection.unauthenticated_fields().is_empty());
+
+        let mut tampered = encrypted;
+        tampered.header.created_at = Some(0);
+        assert!(matches!(
+            tampered.decrypt(password),
+            Err(DecryptionError::DecryptionError)
+        ));
+    }
+
+    #[cfg(feature = "pq")]
+    #[test]
+    fn test_hybrid_metadata_protection() {
+        let password = "password".to_owned();
+        let hybrid = HybridSecretKey::generate();
+        let encrypted = EncryptedKeypair::new_hybrid(
+            &hybrid,
+            password.clone(),
+            &KdfParams::default(),
+        );
+        let mut without_public_key = encrypted.header.clone();
+        without_public_key.public_key = None;
+        assert_eq!(
+            encrypted.header.associated_data(),
+            without_public_key.try_to_vec().unwrap()
+        );
+        let protection = encrypted.metadata_protection();
+        assert_eq!(protection.post_quantum, FieldProtection::Authenticated);
+        assert!(protection.unauthenticated_fields().is_empty());
+
+        // The post-quantum public key is authenticated with the header
+        let mut tampered = encrypted;
+        tampered.header.post_quantum.as_mut().unwrap().public_key[0] ^= 1;
+        assert!(matches!(
+            tampered.decrypt_hybrid(password),
+            Err(DecryptionError::DecryptionError)
+        ));
+    }
 }
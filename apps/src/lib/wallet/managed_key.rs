@@ -0,0 +1,231 @@
+//! A keypair that can be unlocked, locked and replaced at runtime, whose
+//! state changes are reported to observers, e.g. to update a wallet UI
+//! without polling the key.
+
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use anoma::types::key::*;
+use zeroize::Zeroize;
+
+use super::{FindKeyError, Wallet};
+
+/// A change of the state of a [`ManagedKey`]. Events only carry public keys,
+/// never the secret key.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyEvent {
+    /// The key was unlocked
+    Unlocked(common::PublicKey),
+    /// The key was locked and its secret key zeroized
+    Locked(common::PublicKey),
+    /// The key was replaced by another one, and its secret key zeroized
+    Replaced {
+        /// The public key of the replaced key, if it was unlocked
+        old: Option<common::PublicKey>,
+        /// The public key of the new key
+        new: common::PublicKey,
+    },
+}
+
+/// A callback of the observers of a [`ManagedKey`]
+type Observer = Arc<dyn Fn(&KeyEvent) + Send + Sync>;
+
+/// A keypair that is either locked or holds its unlocked secret key, and
+/// reports every change of its state to the subscribed observers. A secret
+/// key that is locked or replaced is zeroized.
+///
+/// The observers are called after the state has changed, without any lock
+/// held, so they may use the key again, e.g. to read its public key.
+#[derive(Default)]
+pub struct ManagedKey {
+    keypair: Mutex<Option<common::SecretKey>>,
+    observers: Mutex<Vec<Observer>>,
+}
+
+impl std::fmt::Debug for ManagedKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ManagedKey")
+            .field("public_key", &self.public_key())
+            .finish()
+    }
+}
+
+impl ManagedKey {
+    /// A locked key
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Call the given function on every state change of the key from now on
+    pub fn subscribe(
+        &self,
+        observer: impl Fn(&KeyEvent) + Send + Sync + 'static,
+    ) {
+        guard(&self.observers).push(Arc::new(observer));
+    }
+
+    /// Unlock the key by decrypting the wallet's keypair with the alias with
+    /// the password, if it's encrypted, as by [`Wallet::unlock_key`]. The
+    /// keypair is decrypted before the key's lock is taken, and it's taken out
+    /// of the wallet's cache of unlocked keys, so that once the key is locked
+    /// the keypair needs the password again. If the key was
+    /// already unlocked, it's replaced by the decrypted keypair, as by
+    /// [`ManagedKey::replace`], and the observers get a
+    /// [`KeyEvent::Replaced`] event instead of [`KeyEvent::Unlocked`].
+    pub fn unlock(
+        &self,
+        wallet: &mut Wallet,
+        alias: &str,
+        password: Option<String>,
+    ) -> Result<common::PublicKey, FindKeyError> {
+        let keypair = (*wallet.unlock_key(alias, password)?).clone();
+        wallet.forget_unlocked_key(alias);
+        let new = keypair.ref_to();
+        let old = guard(&self.keypair).replace(keypair).map(zeroize_keypair);
+        let event = match old {
+            Some(old) => KeyEvent::Replaced {
+                old: Some(old),
+                new: new.clone(),
+            },
+            None => KeyEvent::Unlocked(new.clone()),
+        };
+        self.notify(event);
+        Ok(new)
+    }
+
+    /// Lock the key, zeroizing its secret key. Returns `false` if the key was
+    /// already locked, in which case the observers are not called.
+    pub fn lock(&self) -> bool {
+        let keypair = guard(&self.keypair).take();
+        match keypair.map(zeroize_keypair) {
+            Some(pk) => {
+                self.notify(KeyEvent::Locked(pk));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Replace the key by the given unlocked keypair
+    pub fn replace(&self, keypair: common::SecretKey) {
+        let new = keypair.ref_to();
+        let old = guard(&self.keypair).replace(keypair);
+        self.notify(KeyEvent::Replaced {
+            old: old.map(zeroize_keypair),
+            new,
+        });
+    }
+
+    /// Check if the key is unlocked
+    pub fn is_unlocked(&self) -> bool {
+        guard(&self.keypair).is_some()
+    }
+
+    /// The public key, if the key is unlocked
+    pub fn public_key(&self) -> Option<common::PublicKey> {
+        guard(&self.keypair)
+            .as_ref()
+            .map(|keypair| keypair.ref_to())
+    }
+
+    /// Sign the message, if the key is unlocked
+    pub fn sign(&self, msg: impl AsRef<[u8]>) -> Option<common::Signature> {
+        guard(&self.keypair)
+            .as_ref()
+            .map(|keypair| common::SigScheme::sign(keypair, msg))
+    }
+
+    /// Call the observers with the event. The list of observers is copied
+    /// first, so that no lock is held while they run.
+    fn notify(&self, event: KeyEvent) {
+        let observers = guard(&self.observers).clone();
+        for observer in observers {
+            observer(&event);
+        }
+    }
+}
+
+/// Zeroize a secret key that is no longer held, before it's dropped. Returns
+/// its public key.
+fn zeroize_keypair(mut keypair: common::SecretKey) -> common::PublicKey {
+    let pk = keypair.ref_to();
+    let common::SecretKey::Ed25519(ed25519::SecretKey(sk)) = &mut keypair;
+    sk.zeroize();
+    pk
+}
+
+/// The state is always consistent, so it's still usable if a thread panicked
+/// while holding the lock
+fn guard<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use anoma::types::key::testing::{keypair_1, keypair_2};
+
+    use super::*;
+    use crate::wallet::StoredKeypair;
+
+    #[test]
+    fn test_managed_key_events() {
+        let key = Arc::new(ManagedKey::new());
+        let events = Arc::new(Mutex::new(Vec::new()));
+        {
+            let events = events.clone();
+            // Only a weak reference, to not leak the key through a cycle
+            let weak_key = Arc::downgrade(&key);
+            key.subscribe(move |event| {
+                // The observer may use the key without deadlocking
+                let pk = weak_key.upgrade().and_then(|key| key.public_key());
+                events.lock().unwrap().push((event.clone(), pk));
+            });
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut wallet = Wallet::load_or_new(dir.path());
+        let (stored, _) =
+            StoredKeypair::new(keypair_1(), Some("password".to_owned()));
+        let pkh = PublicKeyHash::from(&keypair_1().ref_to());
+        wallet.insert_keypair("alice".to_owned(), stored, pkh);
+        let unlock = |key: &ManagedKey, wallet: &mut Wallet, password: &str| {
+            key.unlock(wallet, "alice", Some(password.to_owned()))
+        };
+        assert!(unlock(&key, &mut wallet, "wrong").is_err());
+        unlock(&key, &mut wallet, "password").unwrap();
+        assert!(key.sign(b"message").is_some());
+        // Unlocking an unlocked key replaces it
+        let pk_1 = unlock(&key, &mut wallet, "password").unwrap();
+        key.replace(keypair_2());
+        assert!(key.lock());
+        assert!(!key.lock());
+        assert!(key.sign(b"message").is_none());
+        // The wallet doesn't keep an unlocked copy of the locked key
+        assert!(wallet.decrypted_key_cache.is_empty());
+        assert!(unlock(&key, &mut wallet, "wrong").is_err());
+
+        let pk_2 = keypair_2().ref_to();
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                (KeyEvent::Unlocked(pk_1.clone()), Some(pk_1.clone())),
+                (
+                    KeyEvent::Replaced {
+                        old: Some(pk_1.clone()),
+                        new: pk_1.clone()
+                    },
+                    Some(pk_1.clone())
+                ),
+                (
+                    KeyEvent::Replaced {
+                        old: Some(pk_1),
+                        new: pk_2.clone()
+                    },
+                    Some(pk_2.clone())
+                ),
+                (KeyEvent::Locked(pk_2), None),
+            ]
+        );
+    }
+}
@@ -1,13 +1,33 @@
+pub mod address_book;
+#[cfg(unix)]
+pub mod agent;
 mod alias;
+mod capabilities;
 pub mod defaults;
+pub mod file_encryption;
+#[cfg(feature = "pq")]
+pub mod hybrid;
+pub mod import;
+mod kdf_budget;
+pub mod key_agreement;
 mod keys;
+mod managed_key;
+pub mod musig2;
 pub mod pre_genesis;
+#[cfg(feature = "selftest")]
+pub mod selftest;
+pub mod signing;
+pub mod social_recovery;
+#[cfg(feature = "sqlite")]
+pub mod sqlite_store;
 mod store;
+#[cfg(any(test, feature = "testing"))]
+pub mod test_vectors;
 
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
-use std::{env, fs};
+use std::{env, fs, io};
 
 use anoma::types::address::Address;
 use anoma::types::key::*;
@@ -15,9 +35,29 @@ pub use store::wallet_file;
 use thiserror::Error;
 
 use self::alias::Alias;
-pub use self::keys::{DecryptionError, StoredKeypair};
+pub use self::alias::KeyRole;
+pub use self::capabilities::{capabilities, Capabilities};
+pub use self::kdf_budget::{
+    kdf_memory_budget, KdfMemoryBudget, KdfMemoryPermit,
+};
+pub use self::keys::{
+    change_wallet_password, check_format_compatibility, decrypt_by_role,
+    derive_app_key, estimate_unlock_time, keys_exceeding_age, keys_for_address,
+    rotate_salts, same_key, to_redacted_json, verify_wallet_dir, wallet_to_csv,
+    ChangePasswordError, DecryptionError, EncryptedKeypair, FieldProtection,
+    IncompatibleKey, KdfParams, KeyFileStatus, KeyMismatchError, KmsBackend,
+    KmsError, LoadKeypairError, MachineFingerprint, MachineIdFile,
+    MetadataProtection, ParseRecoveryCodeError, RecoveryCode,
+    SortablePublicKey, StoredKeypair, TotpParams, WalletIntegrityReport,
+    KEYSTORE_FORMAT_VERSION, LEGACY_KEYSTORE_FORMAT_VERSION,
+};
+pub use self::managed_key::{KeyEvent, ManagedKey};
 use self::store::Store;
-pub use self::store::{ValidatorData, ValidatorKeys};
+pub use self::store::{
+    pins_file, try_gen_sk, AccountBundle, DuressConfig, GenerateError, KeyPins,
+    KeyPinsError, ProvisionError, TransferKeyError, ValidationIssue,
+    ValidatorData, ValidatorKeys, WalletConfig,
+};
 use crate::cli;
 use crate::config::genesis::genesis_config::GenesisConfig;
 
@@ -34,6 +74,10 @@ pub enum FindKeyError {
     KeyNotFound,
     #[error("{0}")]
     KeyDecryptionError(keys::DecryptionError),
+    #[error("{0}")]
+    KeyPins(KeyPinsError),
+    #[error("{0}")]
+    KeyMismatch(KeyMismatchError),
 }
 
 impl Wallet {
@@ -111,6 +155,21 @@ impl Wallet {
         (alias.into(), key)
     }
 
+    /// Generate a new hybrid Ed25519 and Dilithium3 keypair and insert it
+    /// into the wallet with the provided alias. If none provided, the alias
+    /// will be the public key hash of its Ed25519 key. Hybrid keypairs are
+    /// always encrypted, so the password is prompted from stdin.
+    #[cfg(feature = "pq")]
+    pub fn gen_hybrid_key(
+        &mut self,
+        alias: Option<String>,
+    ) -> (String, hybrid::HybridPublicKey) {
+        let password = read_and_confirm_pwd(false)
+            .expect("A password is always read when encrypting");
+        let (alias, public_key) = self.store.gen_hybrid_key(alias, password);
+        (alias.into(), public_key)
+    }
+
     /// Generate keypair
     /// for signing protocol txs and for the DKG (which will also be stored)
     /// A protocol keypair may be optionally provided, indicating that
@@ -138,6 +197,36 @@ impl Wallet {
         }
     }
 
+    /// Returns the wallet configuration
+    pub fn config(&self) -> &WalletConfig {
+        self.store.config()
+    }
+
+    /// Generate a new keypair for an account, encrypted with the given
+    /// password like the keys of [`Wallet::gen_key`], together with its
+    /// public key and implicit address. The keypair isn't added to the
+    /// wallet.
+    pub fn provision_account(
+        &self,
+        password: &str,
+    ) -> Result<AccountBundle, ProvisionError> {
+        self.store.provision_account(password)
+    }
+
+    /// Set the KDF parameters used to encrypt new keys, e.g. after
+    /// calibrating them for this device. Call [`Wallet::save`] to persist
+    /// them.
+    pub fn set_default_kdf_params(&mut self, kdf_params: KdfParams) {
+        self.store.set_default_kdf_params(kdf_params)
+    }
+
+    /// Set whether new encrypted keys are bound to this machine, so that they
+    /// can only be decrypted on it. Moving such keys to new hardware requires
+    /// re-provisioning them. Call [`Wallet::save`] to persist it.
+    pub fn set_bind_to_machine(&mut self, bind_to_machine: bool) {
+        self.store.set_bind_to_machine(bind_to_machine)
+    }
+
     /// Add validator data to the store
     pub fn add_validator_data(
         &mut self,
@@ -167,6 +256,30 @@ impl Wallet {
         &mut self,
         alias_pkh_or_pk: impl AsRef<str>,
     ) -> Result<Rc<common::SecretKey>, FindKeyError> {
+        self.unlock_key(alias_pkh_or_pk, None)
+    }
+
+    /// Find the stored key by an alias, a public key hash or a public key
+    /// like [`Wallet::find_key`] and decrypt it with the given password, if
+    /// it's encrypted. If no password is given, it's prompted from stdin.
+    ///
+    /// This is the entry point of the decryption of the wallet's keys, which
+    /// checks the duress password (see [`Wallet::set_duress_password`]). The
+    /// keys should be decrypted with it rather than with
+    /// [`StoredKeypair::get`], which doesn't know about the duress password.
+    /// A given password is checked against the duress password before the
+    /// cache, so that it also wipes the keys once this one is unlocked.
+    pub fn unlock_key(
+        &mut self,
+        alias_pkh_or_pk: impl AsRef<str>,
+        password: Option<String>,
+    ) -> Result<Rc<common::SecretKey>, FindKeyError> {
+        let duress = self.store.config().duress.as_ref();
+        if let (Some(duress), Some(password)) = (duress, &password) {
+            if duress.matches(password) {
+                return self.wipe_on_duress(None);
+            }
+        }
         // Try cache first
         if let Some(cached_key) = self
             .decrypted_key_cache
@@ -179,11 +292,56 @@ impl Wallet {
             .store
             .find_key(alias_pkh_or_pk.as_ref())
             .ok_or(FindKeyError::KeyNotFound)?;
-        Self::decrypt_stored_key(
+        let decrypted = Self::decrypt_stored_key(
             &mut self.decrypted_key_cache,
             stored_key,
             alias_pkh_or_pk.into(),
-        )
+            self.store.config().duress.as_ref(),
+            password,
+        )?;
+        self.wipe_on_duress(decrypted)
+    }
+
+    /// Drop the key unlocked with the given alias, public key hash or public
+    /// key from the cache of unlocked keys, so that it's decrypted again on
+    /// the next use
+    fn forget_unlocked_key(&mut self, alias_pkh_or_pk: impl AsRef<str>) {
+        self.decrypted_key_cache
+            .remove(&alias_pkh_or_pk.as_ref().into());
+    }
+
+    /// Find the stored key by an alias like [`Wallet::find_key`], but first
+    /// check its public key against the one pinned for the alias in the given
+    /// pins file (see [`pins_file`] for the default location), without
+    /// decrypting it. If no key is pinned for the alias yet, the public key of
+    /// the stored key is pinned on this first use.
+    pub fn find_pinned_key(
+        &mut self,
+        alias: impl AsRef<str>,
+        pins_path: &Path,
+    ) -> Result<Rc<common::SecretKey>, FindKeyError> {
+        let stored_key = self
+            .store
+            .find_key(alias.as_ref())
+            .ok_or(FindKeyError::KeyNotFound)?;
+        let mut pins =
+            KeyPins::load(pins_path).map_err(FindKeyError::KeyPins)?;
+        let alias: Alias = alias.as_ref().into();
+        match pins.get(&alias).map_err(FindKeyError::KeyPins)? {
+            Some(pinned) => stored_key
+                .verify_against_pinned(&pinned)
+                .map_err(FindKeyError::KeyMismatch)?,
+            None => {
+                let pk = stored_key.public_key().ok_or(
+                    FindKeyError::KeyMismatch(
+                        KeyMismatchError::MissingPublicKey,
+                    ),
+                )?;
+                pins.pin(alias.clone(), &pk);
+                pins.save(pins_path).map_err(FindKeyError::KeyPins)?;
+            }
+        }
+        self.find_key(alias.to_string())
     }
 
     /// Find the stored key by a public key.
@@ -209,11 +367,14 @@ impl Wallet {
             .store
             .find_key_by_pk(pk)
             .ok_or(FindKeyError::KeyNotFound)?;
-        Self::decrypt_stored_key(
+        let decrypted = Self::decrypt_stored_key(
             &mut self.decrypted_key_cache,
             stored_key,
             alias,
-        )
+            self.store.config().duress.as_ref(),
+            None,
+        )?;
+        self.wipe_on_duress(decrypted)
     }
 
     /// Find the stored key by a public key hash.
@@ -238,37 +399,108 @@ impl Wallet {
             .store
             .find_key_by_pkh(pkh)
             .ok_or(FindKeyError::KeyNotFound)?;
-        Self::decrypt_stored_key(
+        let decrypted = Self::decrypt_stored_key(
             &mut self.decrypted_key_cache,
             stored_key,
             alias,
-        )
+            self.store.config().duress.as_ref(),
+            None,
+        )?;
+        self.wipe_on_duress(decrypted)
     }
 
     /// Decrypt stored key, if it's not stored un-encrypted.
-    /// If a given storage key needs to be decrypted, prompt for password from
-    /// stdin and if successfully decrypted, store it in a cache.
+    /// If a given storage key needs to be decrypted and no password is given,
+    /// prompt for password from stdin and if successfully decrypted, store it
+    /// in a cache. Returns `None` if the duress password has been entered
+    /// instead.
     fn decrypt_stored_key(
         decrypted_key_cache: &mut HashMap<Alias, Rc<common::SecretKey>>,
         stored_key: &StoredKeypair,
         alias: Alias,
-    ) -> Result<Rc<common::SecretKey>, FindKeyError> {
+        duress: Option<&DuressConfig>,
+        password: Option<String>,
+    ) -> Result<Option<Rc<common::SecretKey>>, FindKeyError> {
         match stored_key {
-            StoredKeypair::Encrypted(encrypted) => {
-                let password = read_password("Enter decryption password: ");
-                let key = encrypted
-                    .decrypt(password)
+            StoredKeypair::Raw(raw) => Ok(Some(raw.clone())),
+            StoredKeypair::WatchOnly(_) => Err(
+                FindKeyError::KeyDecryptionError(DecryptionError::WatchOnly),
+            ),
+            StoredKeypair::Encrypted(_) => {
+                let password = password.unwrap_or_else(|| {
+                    read_password("Enter decryption password: ")
+                });
+                if duress.map_or(false, |duress| duress.matches(&password)) {
+                    return Ok(None);
+                }
+                let key = stored_key
+                    .get(true, Some(password))
                     .map_err(FindKeyError::KeyDecryptionError)?;
-                decrypted_key_cache.insert(alias.clone(), Rc::new(key));
-                decrypted_key_cache
-                    .get(&alias)
-                    .cloned()
-                    .ok_or(FindKeyError::KeyNotFound)
+                decrypted_key_cache.insert(alias, key.clone());
+                Ok(Some(key))
+            }
+        }
+    }
+
+    /// If the duress password has been entered, wipe the keys and fail the
+    /// same way as with a wrong password.
+    fn wipe_on_duress(
+        &mut self,
+        decrypted: Option<Rc<common::SecretKey>>,
+    ) -> Result<Rc<common::SecretKey>, FindKeyError> {
+        match decrypted {
+            Some(key) => Ok(key),
+            None => {
+                self.wipe();
+                Err(FindKeyError::KeyDecryptionError(
+                    DecryptionError::DecryptionError,
+                ))
             }
-            StoredKeypair::Raw(raw) => Ok(raw.clone()),
         }
     }
 
+    /// Drop all the keys from memory and save the wallet without them or, if
+    /// configured by the duress password, delete the wallet file. Errors are
+    /// ignored to not reveal that the wipe happened.
+    fn wipe(&mut self) {
+        self.decrypted_key_cache.clear();
+        self.store.wipe_keys();
+        let delete_files = self
+            .store
+            .config()
+            .duress
+            .as_ref()
+            .map_or(false, |duress| duress.delete_files);
+        if delete_files {
+            let _ = fs::remove_file(wallet_file(&self.store_dir));
+        } else {
+            let _ = self.save();
+        }
+    }
+
+    /// Set a duress password that wipes the wallet when entered in place of a
+    /// decryption password, see [`DuressConfig`] for its limitations. If
+    /// `delete_files` is set, the wallet file is deleted too. Call
+    /// [`Wallet::save`] to persist it.
+    pub fn set_duress_password(
+        &mut self,
+        password: &str,
+        delete_files: bool,
+    ) -> Result<(), orion::errors::UnknownCryptoError> {
+        let duress = DuressConfig::new(
+            password,
+            delete_files,
+            &self.store.config().default_kdf_params,
+        )?;
+        self.store.set_duress(Some(duress));
+        Ok(())
+    }
+
+    /// Remove the duress password. Call [`Wallet::save`] to persist it.
+    pub fn remove_duress_password(&mut self) {
+        self.store.set_duress(None)
+    }
+
     /// Get all known keys by their alias, paired with PKH, if known.
     pub fn get_keys(
         &self,
@@ -322,6 +554,34 @@ impl Wallet {
             .map(Into::into)
     }
 
+    /// Check the consistency of the wallet after loading it, without
+    /// decrypting any key. Returns all the issues found rather than only the
+    /// first. See [`ValidationIssue`] for the checks.
+    pub fn validate(&self) -> Result<(), Vec<ValidationIssue>> {
+        self.store.validate()
+    }
+
+    /// Move the key with the given alias, and the address with the same
+    /// alias, into another wallet. The key, and its decrypted copy if
+    /// cached, are moved by reference so that the secret is never
+    /// re-encrypted nor serialized in the process. Fails without any changes
+    /// if the alias or the key is already present in the destination wallet.
+    pub fn transfer_key(
+        &mut self,
+        name: &str,
+        to: &mut Wallet,
+    ) -> Result<(), TransferKeyError> {
+        let alias: Alias = name.into();
+        self.store.transfer_key(&alias, &mut to.store)?;
+        if let Some(key) = self.decrypted_key_cache.remove(&alias) {
+            // Drop any other cached references to the moved key
+            self.decrypted_key_cache
+                .retain(|_alias, cached| !Rc::ptr_eq(cached, &key));
+            to.decrypted_key_cache.insert(alias, key);
+        }
+        Ok(())
+    }
+
     /// Extend this wallet from pre-genesis validator wallet.
     pub fn extend_from_pre_genesis_validator(
         &mut self,
@@ -360,6 +620,42 @@ pub fn read_and_confirm_pwd(unsafe_dont_encrypt: bool) -> Option<String> {
     password
 }
 
+/// The number of times [`prompt_new_password`] asks for a new password before
+/// giving up.
+const NEW_PASSWORD_ATTEMPTS: usize = 3;
+
+/// Prompt for a new password from the TTY without echo and ask for it once
+/// more to confirm. The two inputs are compared in constant time. On mismatch,
+/// the prompts are repeated up to [`NEW_PASSWORD_ATTEMPTS`] times before
+/// returning an error.
+pub fn prompt_new_password(
+    prompt: &str,
+    confirm_prompt: &str,
+) -> io::Result<String> {
+    for _ in 0..NEW_PASSWORD_ATTEMPTS {
+        let password = rpassword::read_password_from_tty(Some(prompt))?;
+        if password.is_empty() {
+            eprintln!("Password cannot be empty");
+            continue;
+        }
+        let to_confirm =
+            rpassword::read_password_from_tty(Some(confirm_prompt))?;
+        if orion::util::secure_cmp(password.as_bytes(), to_confirm.as_bytes())
+            .is_ok()
+        {
+            return Ok(password);
+        }
+        eprintln!("Passwords do not match");
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!(
+            "Passwords did not match after {} attempts",
+            NEW_PASSWORD_ATTEMPTS
+        ),
+    ))
+}
+
 /// Read the password for encryption/decryption from the file/env/stdin. Panics
 /// if all options are empty/invalid.
 pub fn read_password(prompt_msg: &str) -> String {
@@ -378,3 +674,141 @@ pub fn read_password(prompt_msg: &str) -> String {
     }
     pwd
 }
+
+/// The number of bits of entropy counted for a character that repeats or
+/// continues a sequence of the previous characters
+const PATTERN_CHAR_ENTROPY: f64 = 1.0;
+
+/// Estimate the entropy of a password in bits, e.g. for a strength meter. It
+/// is derived from the length and the size of the alphabet of the character
+/// classes in use (lowercase, uppercase, digits, ASCII symbols and others).
+/// Characters that repeat the previous character or continue an ascending or
+/// descending sequence (e.g. `aaa`, `abc`, `321`) only count for
+/// [`PATTERN_CHAR_ENTROPY`] each. This is a rough estimate that doesn't know
+/// about dictionary words or common passwords, so it overestimates the
+/// strength of passwords made of them.
+pub fn estimate_password_entropy(password: &str) -> f64 {
+    let (mut lower, mut upper, mut digit, mut symbol, mut other) =
+        (false, false, false, false, false);
+    for c in password.chars() {
+        match c {
+            'a'..='z' => lower = true,
+            'A'..='Z' => upper = true,
+            '0'..='9' => digit = true,
+            c if c.is_ascii_graphic() || c == ' ' => symbol = true,
+            _ => other = true,
+        }
+    }
+    let alphabet_size = [
+        (lower, 26),
+        (upper, 26),
+        (digit, 10),
+        (symbol, 33),
+        (other, 100),
+    ]
+    .iter()
+    .filter(|(in_use, _)| *in_use)
+    .map(|(_, size)| size)
+    .sum::<u32>();
+    if alphabet_size == 0 {
+        return 0.0;
+    }
+    let char_entropy = f64::from(alphabet_size).log2();
+
+    let chars: Vec<u32> = password.chars().map(u32::from).collect();
+    chars
+        .iter()
+        .enumerate()
+        .map(|(i, c)| {
+            let repeats = i >= 1 && chars[i - 1] == *c;
+            let continues_sequence = i >= 2 && {
+                let step = i64::from(*c) - i64::from(chars[i - 1]);
+                let previous_step =
+                    i64::from(chars[i - 1]) - i64::from(chars[i - 2]);
+                step.abs() == 1 && step == previous_step
+            };
+            if repeats || continues_sequence {
+                PATTERN_CHAR_ENTROPY
+            } else {
+                char_entropy
+            }
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate_password_entropy() {
+        assert_eq!(estimate_password_entropy(""), 0.0);
+        // 8 lowercase letters
+        let entropy = estimate_password_entropy("qzmxnwbv");
+        assert!((entropy - 8.0 * 26f64.log2()).abs() < 1e-9);
+        // More character classes and more length give more entropy
+        assert!(estimate_password_entropy("qZ3!nwbv") > entropy);
+        assert!(estimate_password_entropy("qzmxnwbvkp") > entropy);
+        // Repeats and sequences are penalized
+        assert!(estimate_password_entropy("aaaaaaaa") < entropy / 2.0);
+        assert!(estimate_password_entropy("abcdefgh") < entropy / 2.0);
+        assert!(estimate_password_entropy("87654321") < entropy / 2.0);
+    }
+
+    #[test]
+    fn test_duress_password_wipes_the_saved_wallet() {
+        use anoma::types::key::testing::keypair_1;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut wallet = Wallet::load_or_new(dir.path());
+        let (stored, _) =
+            StoredKeypair::new(keypair_1(), Some("password".to_owned()));
+        let pkh = PublicKeyHash::from(&keypair_1().ref_to());
+        wallet.insert_keypair("alice".to_owned(), stored, pkh);
+        wallet.set_duress_password("duress", false).unwrap();
+        wallet.save().unwrap();
+
+        let mut wallet = Wallet::load(dir.path()).unwrap();
+        assert!(matches!(
+            wallet.unlock_key("alice", Some("duress".to_owned())),
+            Err(FindKeyError::KeyDecryptionError(
+                DecryptionError::DecryptionError
+            ))
+        ));
+        assert!(wallet.get_keys().is_empty());
+        // The wipe is saved without calling `Wallet::save`
+        let wallet = Wallet::load(dir.path()).unwrap();
+        assert!(wallet.get_keys().is_empty());
+        assert!(wallet.store.config().duress.is_some());
+    }
+
+    #[test]
+    fn test_duress_password_wipes_an_unlocked_key() {
+        use anoma::types::key::testing::keypair_1;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut wallet = Wallet::load_or_new(dir.path());
+        let (stored, _) =
+            StoredKeypair::new(keypair_1(), Some("password".to_owned()));
+        let pkh = PublicKeyHash::from(&keypair_1().ref_to());
+        wallet.insert_keypair("alice".to_owned(), stored, pkh);
+        wallet.set_duress_password("duress", false).unwrap();
+
+        let key = wallet
+            .unlock_key("alice", Some("password".to_owned()))
+            .unwrap();
+        assert_eq!(key.ref_to(), keypair_1().ref_to());
+        assert!(matches!(
+            wallet.unlock_key("alice", Some("duress".to_owned())),
+            Err(FindKeyError::KeyDecryptionError(
+                DecryptionError::DecryptionError
+            ))
+        ));
+        assert!(wallet.get_keys().is_empty());
+        assert!(wallet.decrypted_key_cache.is_empty());
+        assert!(matches!(
+            wallet.unlock_key("alice", Some("password".to_owned())),
+            Err(FindKeyError::KeyNotFound)
+        ));
+    }
+}
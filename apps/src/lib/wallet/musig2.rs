@@ -0,0 +1,483 @@
+//! MuSig2 multi-signatures over Ed25519 keys.
+//!
+//! The participants of a session aggregate their public keys into a single
+//! Ed25519 public key and, in two rounds, produce a single signature that
+//! verifies under it like any other Ed25519 signature:
+//!
+//! 1. Every participant generates a nonce with [`gen_nonce`] and sends its
+//!    [`PublicNonce`] to the others, who add it to their session with
+//!    [`MuSig2Session::add_nonce`].
+//! 2. Once all the nonces are known, every participant signs with
+//!    [`MuSig2Session::partial_sign`] and sends its [`PartialSignature`] to the
+//!    aggregator, who adds it with [`MuSig2Session::add_partial_signature`] and
+//!    finally combines them with [`MuSig2Session::aggregate`].
+//!
+//! A [`SecretNonce`] must never be used for more than one signature, as that
+//! leaks the secret key. It is consumed on signing to prevent its reuse.
+
+use anoma::types::key::*;
+use borsh::{BorshDeserialize, BorshSerialize};
+use curve25519_dalek::constants::ED25519_BASEPOINT_TABLE;
+use curve25519_dalek::edwards::{CompressedEdwardsY, EdwardsPoint};
+use curve25519_dalek::scalar::Scalar;
+use curve25519_dalek::traits::Identity;
+use thiserror::Error;
+use zeroize::Zeroize;
+
+use super::signing::{expand_secret_key, hash_to_scalar};
+
+/// Domain separator of the key aggregation coefficients
+const KEY_AGG_DOMAIN: &[u8] = b"anoma-wallet:musig2:keyagg:v1";
+/// Domain separator of the nonce aggregation coefficient
+const NONCE_COEF_DOMAIN: &[u8] = b"anoma-wallet:musig2:noncecoef:v1";
+
+#[allow(missing_docs)]
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum MuSig2Error {
+    #[error("A MuSig2 session needs at least one participant")]
+    NoParticipants,
+    #[error("The participant {0} is given more than once")]
+    DuplicateParticipant(common::PublicKey),
+    #[error("The public key {0} is not a valid Ed25519 point")]
+    InvalidPublicKey(common::PublicKey),
+    #[error("{0} is not a participant of the session")]
+    UnknownParticipant(common::PublicKey),
+    #[error("A nonce of {0} has already been added")]
+    DuplicateNonce(common::PublicKey),
+    #[error("The nonce of {0} is not a valid Ed25519 point of large order")]
+    InvalidNonce(common::PublicKey),
+    #[error("Not all the participants' nonces have been added")]
+    MissingNonces,
+    #[error("The secret nonce doesn't match the public nonce of {0}")]
+    NonceMismatch(common::PublicKey),
+    #[error("A partial signature of {0} has already been added")]
+    DuplicatePartialSignature(common::PublicKey),
+    #[error("The partial signature of {0} is invalid")]
+    InvalidPartialSignature(common::PublicKey),
+    #[error("Not all the participants' partial signatures have been added")]
+    MissingPartialSignatures,
+}
+
+/// The public part of a participant's nonce, shared in the first round
+#[derive(Clone, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct PublicNonce {
+    r1: [u8; 32],
+    r2: [u8; 32],
+}
+
+/// The secret part of a participant's nonce. It is consumed when signing and
+/// zeroized on drop.
+pub struct SecretNonce {
+    r1: Scalar,
+    r2: Scalar,
+    public: PublicNonce,
+}
+
+impl Drop for SecretNonce {
+    fn drop(&mut self) {
+        self.r1.zeroize();
+        self.r2.zeroize();
+    }
+}
+
+/// A participant's share of the signature, sent in the second round
+#[derive(Clone, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct PartialSignature([u8; 32]);
+
+/// A participant of a session
+#[derive(Debug)]
+struct Participant {
+    public_key: common::PublicKey,
+    point: EdwardsPoint,
+    /// The key aggregation coefficient
+    coefficient: Scalar,
+    nonce: Option<[EdwardsPoint; 2]>,
+    partial_signature: Option<Scalar>,
+}
+
+/// The state of a MuSig2 signing session over a message
+#[derive(Debug)]
+pub struct MuSig2Session {
+    message: Vec<u8>,
+    /// Sorted by the public keys' encodings, so that the aggregated key
+    /// doesn't depend on the order the participants are given in
+    participants: Vec<Participant>,
+    aggregated_key: EdwardsPoint,
+}
+
+/// Generate a fresh random nonce for a session
+pub fn gen_nonce() -> (SecretNonce, PublicNonce) {
+    let r1 = random_scalar();
+    let r2 = random_scalar();
+    let public = PublicNonce {
+        r1: (&r1 * &ED25519_BASEPOINT_TABLE).compress().to_bytes(),
+        r2: (&r2 * &ED25519_BASEPOINT_TABLE).compress().to_bytes(),
+    };
+    let secret = SecretNonce {
+        r1,
+        r2,
+        public: public.clone(),
+    };
+    (secret, public)
+}
+
+impl MuSig2Session {
+    /// Start a session to sign the message by the given participants and
+    /// aggregate their public keys
+    pub fn new(
+        participants: &[common::PublicKey],
+        message: &[u8],
+    ) -> Result<Self, MuSig2Error> {
+        if participants.is_empty() {
+            return Err(MuSig2Error::NoParticipants);
+        }
+        let mut keys = participants
+            .iter()
+            .map(|pk| {
+                let bytes = pk_bytes(pk);
+                let point = CompressedEdwardsY(bytes)
+                    .decompress()
+                    .filter(|point| !point.is_small_order())
+                    .ok_or_else(|| MuSig2Error::InvalidPublicKey(pk.clone()))?;
+                Ok((bytes, pk.clone(), point))
+            })
+            .collect::<Result<Vec<_>, MuSig2Error>>()?;
+        keys.sort_by(|(a, _, _), (b, _, _)| a.cmp(b));
+        if let Some(window) =
+            keys.windows(2).find(|pair| pair[0].0 == pair[1].0)
+        {
+            return Err(MuSig2Error::DuplicateParticipant(window[0].1.clone()));
+        }
+
+        let key_list: Vec<u8> =
+            keys.iter().flat_map(|(bytes, _, _)| *bytes).collect();
+        let participants: Vec<Participant> = keys
+            .into_iter()
+            .map(|(bytes, public_key, point)| Participant {
+                public_key,
+                point,
+                coefficient: hash_to_scalar(&[
+                    KEY_AGG_DOMAIN,
+                    &key_list,
+                    &bytes,
+                ]),
+                nonce: None,
+                partial_signature: None,
+            })
+            .collect();
+        let aggregated_key = participants
+            .iter()
+            .map(|participant| participant.coefficient * participant.point)
+            .fold(EdwardsPoint::identity(), |acc, point| acc + point);
+
+        Ok(Self {
+            message: message.to_vec(),
+            participants,
+            aggregated_key,
+        })
+    }
+
+    /// The aggregated public key, under which the aggregated signature
+    /// verifies
+    pub fn aggregated_public_key(&self) -> common::PublicKey {
+        let vk = ed25519_consensus::VerificationKey::try_from(
+            self.aggregated_key.compress().to_bytes(),
+        )
+        .expect("The aggregated key should be a valid point");
+        ed25519::PublicKey(vk)
+            .try_to_pk()
+            .expect("Converting the public key shouldn't fail")
+    }
+
+    /// Add the public nonce of a participant. Nonce points of small order,
+    /// including the identity, are rejected, as they would let the
+    /// participant bias the aggregated nonce.
+    pub fn add_nonce(
+        &mut self,
+        signer: &common::PublicKey,
+        nonce: PublicNonce,
+    ) -> Result<(), MuSig2Error> {
+        let participant = self.participant_mut(signer)?;
+        if participant.nonce.is_some() {
+            return Err(MuSig2Error::DuplicateNonce(signer.clone()));
+        }
+        let decompress = |bytes: [u8; 32]| {
+            CompressedEdwardsY(bytes)
+                .decompress()
+                .filter(|point| !point.is_small_order())
+                .ok_or_else(|| MuSig2Error::InvalidNonce(signer.clone()))
+        };
+        participant.nonce =
+            Some([decompress(nonce.r1)?, decompress(nonce.r2)?]);
+        Ok(())
+    }
+
+    /// Produce the partial signature of the given participant's keypair with
+    /// its secret nonce, whose public part must have been added to the
+    /// session. The nonce is consumed.
+    pub fn partial_sign(
+        &self,
+        keypair: &common::SecretKey,
+        nonce: SecretNonce,
+    ) -> Result<PartialSignature, MuSig2Error> {
+        let signer = keypair.ref_to();
+        let participant = self.participant(&signer)?;
+        let (aggregated_nonce, nonce_coefficient) = self.aggregated_nonce()?;
+        let own_nonce = [
+            &nonce.r1 * &ED25519_BASEPOINT_TABLE,
+            &nonce.r2 * &ED25519_BASEPOINT_TABLE,
+        ];
+        if participant.nonce != Some(own_nonce) {
+            return Err(MuSig2Error::NonceMismatch(signer));
+        }
+        let challenge = self.challenge(&aggregated_nonce);
+        let (mut secret, _prefix) = expand_secret_key(keypair);
+        let s = nonce.r1
+            + nonce_coefficient * nonce.r2
+            + challenge * participant.coefficient * secret;
+        secret.zeroize();
+        Ok(PartialSignature(s.to_bytes()))
+    }
+
+    /// Verify and add the partial signature of a participant
+    pub fn add_partial_signature(
+        &mut self,
+        signer: &common::PublicKey,
+        partial_signature: PartialSignature,
+    ) -> Result<(), MuSig2Error> {
+        let (aggregated_nonce, nonce_coefficient) = self.aggregated_nonce()?;
+        let challenge = self.challenge(&aggregated_nonce);
+        let participant = self.participant_mut(signer)?;
+        if participant.partial_signature.is_some() {
+            return Err(MuSig2Error::DuplicatePartialSignature(signer.clone()));
+        }
+        let [r1, r2] = participant.nonce.expect("All nonces are known");
+        let s = Scalar::from_canonical_bytes(partial_signature.0).ok_or_else(
+            || MuSig2Error::InvalidPartialSignature(signer.clone()),
+        )?;
+        let expected = r1
+            + nonce_coefficient * r2
+            + challenge * participant.coefficient * participant.point;
+        if &s * &ED25519_BASEPOINT_TABLE != expected {
+            return Err(MuSig2Error::InvalidPartialSignature(signer.clone()));
+        }
+        participant.partial_signature = Some(s);
+        Ok(())
+    }
+
+    /// Combine the partial signatures of all the participants into an
+    /// Ed25519 signature of the message under the aggregated public key
+    pub fn aggregate(&self) -> Result<common::Signature, MuSig2Error> {
+        let (aggregated_nonce, _) = self.aggregated_nonce()?;
+        let s = self
+            .participants
+            .iter()
+            .map(|participant| participant.partial_signature)
+            .sum::<Option<Scalar>>()
+            .ok_or(MuSig2Error::MissingPartialSignatures)?;
+        let mut bytes = [0; 64];
+        bytes[..32].copy_from_slice(&aggregated_nonce.compress().to_bytes());
+        bytes[32..].copy_from_slice(&s.to_bytes());
+        let signature = ed25519::Signature(bytes.into());
+        Ok(signature
+            .try_to_sig()
+            .expect("Converting the signature shouldn't fail"))
+    }
+
+    fn participant(
+        &self,
+        signer: &common::PublicKey,
+    ) -> Result<&Participant, MuSig2Error> {
+        self.participants
+            .iter()
+            .find(|participant| &participant.public_key == signer)
+            .ok_or_else(|| MuSig2Error::UnknownParticipant(signer.clone()))
+    }
+
+    fn participant_mut(
+        &mut self,
+        signer: &common::PublicKey,
+    ) -> Result<&mut Participant, MuSig2Error> {
+        self.participants
+            .iter_mut()
+            .find(|participant| &participant.public_key == signer)
+            .ok_or_else(|| MuSig2Error::UnknownParticipant(signer.clone()))
+    }
+
+    /// The aggregated nonce and the coefficient of the second nonces, once
+    /// all the nonces are known
+    fn aggregated_nonce(&self) -> Result<(EdwardsPoint, Scalar), MuSig2Error> {
+        let (r1, r2) = self.participants.iter().try_fold(
+            (EdwardsPoint::identity(), EdwardsPoint::identity()),
+            |(r1, r2), participant| {
+                let [p1, p2] =
+                    participant.nonce.ok_or(MuSig2Error::MissingNonces)?;
+                Ok::<_, MuSig2Error>((r1 + p1, r2 + p2))
+            },
+        )?;
+        let coefficient = hash_to_scalar(&[
+            NONCE_COEF_DOMAIN,
+            self.aggregated_key.compress().as_bytes(),
+            r1.compress().as_bytes(),
+            r2.compress().as_bytes(),
+            &self.message,
+        ]);
+        Ok((r1 + coefficient * r2, coefficient))
+    }
+
+    /// The Ed25519 challenge over the message
+    fn challenge(&self, aggregated_nonce: &EdwardsPoint) -> Scalar {
+        hash_to_scalar(&[
+            aggregated_nonce.compress().as_bytes(),
+            self.aggregated_key.compress().as_bytes(),
+            &self.message,
+        ])
+    }
+}
+
+/// The encoding of an Ed25519 public key
+fn pk_bytes(pk: &common::PublicKey) -> [u8; 32] {
+    let common::PublicKey::Ed25519(pk) = pk;
+    let mut bytes = [0; 32];
+    bytes.copy_from_slice(
+        &pk.try_to_vec().expect("Encoding public key shouldn't fail"),
+    );
+    bytes
+}
+
+fn random_scalar() -> Scalar {
+    use rand::rngs::OsRng;
+    use rand::RngCore;
+    let mut wide = [0; 64];
+    OsRng.fill_bytes(&mut wide);
+    let scalar = Scalar::from_bytes_mod_order_wide(&wide);
+    wide.zeroize();
+    scalar
+}
+
+#[cfg(test)]
+mod tests {
+    use anoma::types::key::testing::{gen_keypair, keypair_1, keypair_2};
+
+    use super::*;
+
+    fn keypairs() -> Vec<common::SecretKey> {
+        vec![
+            keypair_1(),
+            keypair_2(),
+            gen_keypair::<ed25519::SigScheme>().try_to_sk().unwrap(),
+        ]
+    }
+
+    #[test]
+    fn test_musig2_signature_verifies() {
+        let message = b"message";
+        let keypairs = keypairs();
+        let pks: Vec<_> = keypairs.iter().map(|sk| sk.ref_to()).collect();
+        let mut session = MuSig2Session::new(&pks, message).unwrap();
+
+        // The aggregated key doesn't depend on the order of the participants
+        let reversed: Vec<_> = pks.iter().rev().cloned().collect();
+        assert_eq!(
+            MuSig2Session::new(&reversed, message)
+                .unwrap()
+                .aggregated_public_key(),
+            session.aggregated_public_key()
+        );
+
+        // Round one
+        let mut secret_nonces = Vec::new();
+        for pk in &pks {
+            let (secret, public) = gen_nonce();
+            session.add_nonce(pk, public).unwrap();
+            secret_nonces.push(secret);
+        }
+        // Round two
+        for (keypair, nonce) in keypairs.iter().zip(secret_nonces) {
+            let partial = session.partial_sign(keypair, nonce).unwrap();
+            session
+                .add_partial_signature(&keypair.ref_to(), partial)
+                .unwrap();
+        }
+        let signature = session.aggregate().unwrap();
+
+        common::SigScheme::verify_signature_raw(
+            &session.aggregated_public_key(),
+            message,
+            &signature,
+        )
+        .unwrap();
+        assert!(common::SigScheme::verify_signature_raw(
+            &session.aggregated_public_key(),
+            b"other",
+            &signature,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_musig2_protocol_misuse() {
+        let keypairs = keypairs();
+        let pks: Vec<_> = keypairs.iter().map(|sk| sk.ref_to()).collect();
+        assert!(matches!(
+            MuSig2Session::new(&[pks[0].clone(), pks[0].clone()], b"msg"),
+            Err(MuSig2Error::DuplicateParticipant(_))
+        ));
+
+        let mut session = MuSig2Session::new(&pks[..2], b"msg").unwrap();
+        let (secret, public) = gen_nonce();
+        assert_eq!(
+            session.add_nonce(&pks[2], public.clone()),
+            Err(MuSig2Error::UnknownParticipant(pks[2].clone()))
+        );
+        session.add_nonce(&pks[0], public.clone()).unwrap();
+        assert_eq!(
+            session.add_nonce(&pks[0], public),
+            Err(MuSig2Error::DuplicateNonce(pks[0].clone()))
+        );
+        assert_eq!(
+            session.partial_sign(&keypairs[0], secret).err(),
+            Some(MuSig2Error::MissingNonces)
+        );
+
+        // Nonces of small order are rejected
+        let (secret, public) = gen_nonce();
+        let identity = {
+            let mut bytes = [0; 32];
+            bytes[0] = 1;
+            bytes
+        };
+        // The point (0, -1), of order 2
+        let order_two = {
+            let mut bytes = [0xff; 32];
+            bytes[0] = 0xec;
+            bytes[31] = 0x7f;
+            bytes
+        };
+        for small_order in [identity, order_two] {
+            let nonce = PublicNonce {
+                r1: public.r1,
+                r2: small_order,
+            };
+            assert_eq!(
+                session.add_nonce(&pks[1], nonce),
+                Err(MuSig2Error::InvalidNonce(pks[1].clone()))
+            );
+        }
+        session.add_nonce(&pks[1], public).unwrap();
+        // Signing with another participant's nonce is rejected
+        assert_eq!(
+            session.partial_sign(&keypairs[0], secret).err(),
+            Some(MuSig2Error::NonceMismatch(pks[0].clone()))
+        );
+        // A partial signature of the wrong key is rejected
+        assert_eq!(
+            session.add_partial_signature(&pks[1], PartialSignature([1; 32])),
+            Err(MuSig2Error::InvalidPartialSignature(pks[1].clone()))
+        );
+        assert_eq!(
+            session.aggregate().err(),
+            Some(MuSig2Error::MissingPartialSignatures)
+        );
+    }
+}
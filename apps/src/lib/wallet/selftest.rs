@@ -0,0 +1,98 @@
+//! A self-test of the Ed25519 implementation used by the wallet, to run at
+//! startup in high-assurance deployments before any real key is used.
+//!
+//! The test signs the messages of fixed test vectors and checks the public
+//! keys and signatures against the expected bytes, which catches a broken
+//! build or a misbehaving dependency. It only takes a few signatures and
+//! verifications.
+
+use anoma::types::key::*;
+use borsh::{BorshDeserialize, BorshSerialize};
+use thiserror::Error;
+
+/// The seed, the public key, the message and the signature of the tests 1, 2
+/// and 3 of the Ed25519 test vectors of RFC 8032, hex encoded
+const RFC8032_VECTORS: [(&str, &str, &str, &str); 3] = [
+    (
+        "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+        "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+        "",
+        "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e06522490155\
+         5fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+    ),
+    (
+        "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+        "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+        "72",
+        "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da\
+         085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+    ),
+    (
+        "c5aa8df43f9f837bedb7442f31dcb7b166d38535076f094b85ce3a2e0b4458f7",
+        "fc51cd8e6218a1a38da47ed00230f0580816ed13ba3303ac5deb911548908025",
+        "af82",
+        "6291d657deec24024827e69c3abe01a30ce548a284743a445e3680d7db5ac3ac\
+         18ff9b538d16f290ae67f760984dc6594a7c15e9716ed28dc027beceea1ec40a",
+    ),
+];
+
+#[allow(missing_docs)]
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum SelftestError {
+    #[error("Self-test vector {0}: unexpected public key of the seed")]
+    PublicKeyMismatch(usize),
+    #[error("Self-test vector {0}: unexpected signature of the message")]
+    SignatureMismatch(usize),
+    #[error("Self-test vector {0}: the expected signature doesn't verify")]
+    VerificationFailed(usize),
+    #[error("Self-test vector {0}: a corrupted signature verifies")]
+    ForgeryAccepted(usize),
+}
+
+/// Check that Ed25519 keys, signatures and their verification behave as
+/// expected on fixed test vectors. Returns the first failed check of each
+/// vector in order.
+pub fn selftest() -> Result<(), SelftestError> {
+    let decode =
+        |hex: &str| hex::decode(hex).expect("The vectors should be hex");
+    for (index, (seed, public_key, message, expected_signature)) in
+        RFC8032_VECTORS.iter().enumerate()
+    {
+        let message = decode(message);
+        let sk = ed25519::SecretKey::try_from_slice(&decode(seed))
+            .expect("The seeds should be valid secret keys");
+        let pk = sk.ref_to();
+        if pk.try_to_vec().ok() != Some(decode(public_key)) {
+            return Err(SelftestError::PublicKeyMismatch(index));
+        }
+        let signature = ed25519::SigScheme::sign(&sk, &message);
+        if signature.try_to_vec().ok() != Some(decode(expected_signature)) {
+            return Err(SelftestError::SignatureMismatch(index));
+        }
+        if ed25519::SigScheme::verify_signature_raw(&pk, &message, &signature)
+            .is_err()
+        {
+            return Err(SelftestError::VerificationFailed(index));
+        }
+        let mut corrupted = decode(expected_signature);
+        corrupted[0] ^= 1;
+        let corrupted = ed25519::Signature::try_from_slice(&corrupted)
+            .expect("A signature with a flipped bit should still decode");
+        if ed25519::SigScheme::verify_signature_raw(&pk, &message, &corrupted)
+            .is_ok()
+        {
+            return Err(SelftestError::ForgeryAccepted(index));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_selftest_passes() {
+        selftest().unwrap();
+    }
+}
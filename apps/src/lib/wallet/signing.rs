@@ -0,0 +1,1037 @@
+//! Signing helpers for wallet keys that are not part of transaction signing.
+
+use std::collections::HashSet;
+use std::io::{self, Read};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anoma::types::key::*;
+use borsh::{BorshDeserialize, BorshSerialize};
+use curve25519_dalek::constants::ED25519_BASEPOINT_TABLE;
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use curve25519_dalek::scalar::Scalar;
+use curve25519_dalek::traits::IsIdentity;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+use thiserror::Error;
+use zeroize::{Zeroize, Zeroizing};
+
+/// Domain separator prepended to the preimage of ownership proofs, so that a
+/// proof can never be mistaken for a signature over a transaction.
+const OWNERSHIP_PROOF_DOMAIN: &str = "anoma-wallet:ownership-proof:v1";
+/// Domain separator prepended to the preimage of timestamped signatures.
+const TIMESTAMPED_SIGNATURE_DOMAIN: &str =
+    "anoma-wallet:timestamped-signature:v1";
+/// Domain separator prepended to the preimage of chain-bound signatures.
+const CHAIN_SIGNATURE_DOMAIN: &str = "anoma-wallet:chain-signature:v1";
+/// Domain separator prepended to the preimage of signed bundles.
+const SIGNED_BUNDLE_DOMAIN: &str = "anoma-wallet:signed-bundle:v1";
+/// Domain separator prepended to the preimage of delegations.
+const DELEGATION_DOMAIN: &str = "anoma-wallet:delegation:v1";
+/// The `dom2` prefix of Ed25519ph with an empty context (RFC 8032)
+const ED25519PH_DOM2: &[u8] = b"SigEd25519 no Ed25519 collisions\x01\x00";
+/// The DER tag of a BIT STRING
+const DER_BIT_STRING_TAG: u8 = 0x03;
+/// The length of an Ed25519 signature
+const SIGNATURE_LEN: usize = 64;
+/// The size of the chunks read from a stream when hashing it
+pub const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+#[allow(missing_docs)]
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ParseDerSignatureError {
+    #[error("Expected a DER BIT STRING, found the tag {0:#04x}")]
+    UnexpectedTag(u8),
+    #[error("The DER encoding is truncated")]
+    Truncated,
+    #[error("Unexpected length of the DER signature: {0} bytes")]
+    InvalidLength(usize),
+    #[error("Unexpected number of unused bits {0}, expected 0")]
+    UnusedBits(u8),
+    #[error("Unexpected data after the DER signature")]
+    TrailingData,
+}
+
+/// The outcome of a signature verification by [`verify_detailed`], with the
+/// reason of a failure
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VerifyResult {
+    /// The signature is valid
+    Valid,
+    /// The public key isn't the encoding of a curve point
+    InvalidPublicKey,
+    /// The `R` component of the signature isn't the encoding of a curve
+    /// point
+    MalformedSignature,
+    /// The `s` component of the signature isn't reduced modulo the group
+    /// order, which Ed25519 requires to prevent malleable signatures
+    NonCanonical,
+    /// The signature is well-formed but wasn't produced for this message by
+    /// the secret key of this public key
+    Mismatch,
+}
+
+impl VerifyResult {
+    /// Returns `true` if the signature is valid
+    pub fn is_valid(&self) -> bool {
+        matches!(self, VerifyResult::Valid)
+    }
+}
+
+/// A proof that the holder of a secret key has signed a given challenge
+#[derive(
+    Clone,
+    Debug,
+    PartialEq,
+    BorshSerialize,
+    BorshDeserialize,
+    Serialize,
+    Deserialize,
+)]
+pub struct OwnershipProof {
+    /// The time of the proof creation in seconds since the Unix epoch. It is
+    /// part of the signed preimage, verifiers should reject proofs that are
+    /// too old.
+    pub timestamp: u64,
+    /// The signature over the framed challenge
+    pub signature: common::Signature,
+}
+
+/// A signature of a message together with the time it was made at
+#[derive(
+    Clone,
+    Debug,
+    PartialEq,
+    BorshSerialize,
+    BorshDeserialize,
+    Serialize,
+    Deserialize,
+)]
+pub struct TimestampedSignature {
+    /// The time of signing in seconds since the Unix epoch. It is part of the
+    /// signed preimage.
+    pub timestamp: u64,
+    /// The signature over the framed message and timestamp
+    pub signature: common::Signature,
+}
+
+/// A delegation of the authority of a key to another key, as a link of a
+/// chain verified by [`verify_delegation_chain`]
+#[derive(
+    Clone,
+    Debug,
+    PartialEq,
+    BorshSerialize,
+    BorshDeserialize,
+    Serialize,
+    Deserialize,
+)]
+pub struct DelegationLink {
+    /// The key the authority is delegated to
+    pub delegate: common::PublicKey,
+    /// The time in seconds since the Unix epoch after which the delegation
+    /// is no longer valid, if it expires. It is part of the signed preimage.
+    pub expires_at: Option<u64>,
+    /// The signature over the delegation by the delegating key
+    pub signature: common::Signature,
+}
+
+/// Prove the ownership of the given keypair by signing a domain-separated
+/// challenge together with the current time. The challenge should be a fresh
+/// random nonce issued by the verifier to prevent replay.
+pub fn prove_ownership(
+    keypair: &common::SecretKey,
+    challenge: &[u8],
+) -> OwnershipProof {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("The system time shouldn't be before the Unix epoch")
+        .as_secs();
+    let preimage = ownership_preimage(challenge, timestamp);
+    OwnershipProof {
+        timestamp,
+        signature: common::SigScheme::sign(keypair, preimage),
+    }
+}
+
+/// Verify that the proof has been produced for the given challenge by the
+/// secret key of the given public key.
+pub fn verify_ownership(
+    pk: &common::PublicKey,
+    challenge: &[u8],
+    proof: &OwnershipProof,
+) -> bool {
+    let preimage = ownership_preimage(challenge, proof.timestamp);
+    common::SigScheme::verify_signature_raw(pk, &preimage, &proof.signature)
+        .is_ok()
+}
+
+/// Sign a message together with the given time in seconds since the Unix
+/// epoch. The signed preimage is the Borsh encoding of the tuple
+/// `(domain, msg, timestamp)` where the domain is the string
+/// `"anoma-wallet:timestamped-signature:v1"`, `msg` is encoded as a byte
+/// vector and `timestamp` as a `u64`, so the timestamp can't be altered.
+pub fn sign_timestamped(
+    keypair: &common::SecretKey,
+    msg: &[u8],
+    now: u64,
+) -> TimestampedSignature {
+    let preimage = timestamped_preimage(msg, now);
+    TimestampedSignature {
+        timestamp: now,
+        signature: common::SigScheme::sign(keypair, preimage),
+    }
+}
+
+/// Verify a signature produced by [`sign_timestamped`] and check that its
+/// timestamp is at most `max_age` seconds away from `now`, in either
+/// direction to tolerate some clock skew of the signer.
+pub fn verify_timestamped(
+    pk: &common::PublicKey,
+    msg: &[u8],
+    ts_sig: &TimestampedSignature,
+    now: u64,
+    max_age: u64,
+) -> bool {
+    let age = now.max(ts_sig.timestamp) - now.min(ts_sig.timestamp);
+    if age > max_age {
+        return false;
+    }
+    let preimage = timestamped_preimage(msg, ts_sig.timestamp);
+    common::SigScheme::verify_signature_raw(pk, &preimage, &ts_sig.signature)
+        .is_ok()
+}
+
+/// Encode a signature in DER as a BIT STRING of the 64 bytes signature
+/// without unused bits, as in the `signatureValue` of X.509.
+pub fn signature_to_der(sig: &common::Signature) -> Vec<u8> {
+    let common::Signature::Ed25519(sig) = sig;
+    let mut der = vec![DER_BIT_STRING_TAG, SIGNATURE_LEN as u8 + 1, 0];
+    der.extend_from_slice(&sig.0.to_bytes());
+    der
+}
+
+/// Decode a signature encoded by [`signature_to_der`]. Only the definite
+/// short form of the length is valid DER for a BIT STRING of this length, so
+/// any other encoding is rejected.
+pub fn signature_from_der(
+    der: &[u8],
+) -> Result<common::Signature, ParseDerSignatureError> {
+    let (tag, rest) =
+        der.split_first().ok_or(ParseDerSignatureError::Truncated)?;
+    if *tag != DER_BIT_STRING_TAG {
+        return Err(ParseDerSignatureError::UnexpectedTag(*tag));
+    }
+    let (len, rest) = rest
+        .split_first()
+        .ok_or(ParseDerSignatureError::Truncated)?;
+    let len = *len as usize;
+    if len != SIGNATURE_LEN + 1 {
+        return Err(ParseDerSignatureError::InvalidLength(len));
+    }
+    if rest.len() < len {
+        return Err(ParseDerSignatureError::Truncated);
+    }
+    if rest.len() > len {
+        return Err(ParseDerSignatureError::TrailingData);
+    }
+    let (unused_bits, sig) = rest
+        .split_first()
+        .ok_or(ParseDerSignatureError::Truncated)?;
+    if *unused_bits != 0 {
+        return Err(ParseDerSignatureError::UnusedBits(*unused_bits));
+    }
+    let sig = ed25519::Signature::try_from_slice(sig)
+        .expect("Any 64 bytes should decode as a signature");
+    Ok(sig
+        .try_to_sig()
+        .expect("Converting the signature shouldn't fail"))
+}
+
+/// Verify a signature of a raw message like
+/// [`common::SigScheme::verify_signature_raw`], but tell why it is invalid:
+/// the public key or the signature can't be decoded, the signature isn't
+/// canonical or it doesn't match the message and public key.
+pub fn verify_detailed(
+    pk: &common::PublicKey,
+    msg: &[u8],
+    sig: &common::Signature,
+) -> VerifyResult {
+    let common::PublicKey::Ed25519(ed_pk) = pk;
+    let (r_bytes, s_bytes) = signature_parts(sig);
+    if CompressedEdwardsY(ed_pk.0.to_bytes())
+        .decompress()
+        .is_none()
+    {
+        VerifyResult::InvalidPublicKey
+    } else if CompressedEdwardsY(r_bytes).decompress().is_none() {
+        VerifyResult::MalformedSignature
+    } else if Scalar::from_canonical_bytes(s_bytes).is_none() {
+        VerifyResult::NonCanonical
+    } else if common::SigScheme::verify_signature_raw(pk, msg, sig).is_ok() {
+        VerifyResult::Valid
+    } else {
+        VerifyResult::Mismatch
+    }
+}
+
+/// A record of a signature made by an [`AuditedKeypair`]. Only the hash of
+/// the signed message is recorded, never the message itself.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// The time of the signature in seconds since the Unix epoch
+    pub timestamp: u64,
+    /// The SHA-256 hash of the signed message
+    pub message_hash: [u8; 32],
+}
+
+/// An in-memory log of the signatures made by a keypair. Clones share the
+/// same log, e.g. to export it from another thread. It's never persisted
+/// unless the caller does so with its [`AuditLog::entries`].
+#[derive(Clone, Debug, Default)]
+pub struct AuditLog(Arc<Mutex<Vec<AuditEntry>>>);
+
+impl AuditLog {
+    /// The recorded signatures, in the order they were made
+    pub fn entries(&self) -> Vec<AuditEntry> {
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    fn record(&self, msg: &[u8]) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("The system time shouldn't be before the Unix epoch")
+            .as_secs();
+        let entry = AuditEntry {
+            timestamp,
+            message_hash: Sha256::digest(msg).into(),
+        };
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .push(entry);
+    }
+}
+
+/// A keypair of the wallet that can record an [`AuditLog`] of the messages
+/// it signs, once enabled with [`AuditedKeypair::with_audit_log`]
+#[derive(Clone, Debug)]
+pub struct AuditedKeypair {
+    keypair: Rc<common::SecretKey>,
+    audit_log: Option<AuditLog>,
+}
+
+impl AuditedKeypair {
+    /// Wrap a keypair, without an audit log
+    pub fn new(keypair: Rc<common::SecretKey>) -> Self {
+        Self {
+            keypair,
+            audit_log: None,
+        }
+    }
+
+    /// Enable the audit log of this keypair. Clones made afterwards record
+    /// to the same log.
+    pub fn with_audit_log(mut self) -> Self {
+        self.audit_log.get_or_insert_with(AuditLog::default);
+        self
+    }
+
+    /// The audit log, if enabled
+    pub fn audit_log(&self) -> Option<&AuditLog> {
+        self.audit_log.as_ref()
+    }
+
+    /// The recorded signatures, or none if the audit log isn't enabled
+    pub fn entries(&self) -> Vec<AuditEntry> {
+        self.audit_log
+            .as_ref()
+            .map(AuditLog::entries)
+            .unwrap_or_default()
+    }
+
+    /// The public key of the keypair
+    pub fn public_key(&self) -> common::PublicKey {
+        self.keypair.ref_to()
+    }
+
+    /// Sign a raw message, recording it in the audit log if enabled
+    pub fn sign(&self, msg: &[u8]) -> common::Signature {
+        let signature = common::SigScheme::sign(&self.keypair, msg);
+        if let Some(audit_log) = &self.audit_log {
+            audit_log.record(msg);
+        }
+        signature
+    }
+}
+
+/// The hash of a payload, e.g. a release archive, signed by several keys.
+///
+/// The file format is the Borsh encoding of the bundle: the 32 bytes SHA-256
+/// hash of the payload followed by a `u32` count of signatures and each
+/// `(public_key, signature)` entry in their Borsh encoding. Each signature is
+/// over the Borsh encoding of the tuple `(domain, payload_hash)` where the
+/// domain is the string `"anoma-wallet:signed-bundle:v1"`.
+#[derive(
+    Clone,
+    Debug,
+    Default,
+    PartialEq,
+    BorshSerialize,
+    BorshDeserialize,
+    Serialize,
+    Deserialize,
+)]
+pub struct SignedBundle {
+    /// The SHA-256 hash of the signed payload
+    pub payload_hash: [u8; 32],
+    /// The signatures of the payload hash with their public keys
+    pub signatures: Vec<(common::PublicKey, common::Signature)>,
+}
+
+impl SignedBundle {
+    /// A bundle of the given payload, without any signature yet
+    pub fn new(payload: &[u8]) -> Self {
+        Self {
+            payload_hash: Sha256::digest(payload).into(),
+            signatures: Vec::new(),
+        }
+    }
+
+    /// Decode a bundle from its file format
+    pub fn parse(bytes: &[u8]) -> io::Result<Self> {
+        Self::try_from_slice(bytes)
+    }
+
+    /// Encode the bundle in its file format
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.try_to_vec()
+            .expect("Encoding signed bundle shouldn't fail")
+    }
+
+    /// Add a signature of the payload hash by the given keypair
+    pub fn sign(&mut self, keypair: &common::SecretKey) {
+        let signature = common::SigScheme::sign(keypair, self.preimage());
+        self.signatures.push((keypair.ref_to(), signature));
+    }
+
+    /// Check that this bundle is of the given payload
+    pub fn matches_payload(&self, payload: &[u8]) -> bool {
+        let hash: [u8; 32] = Sha256::digest(payload).into();
+        hash == self.payload_hash
+    }
+
+    /// Check that at least `threshold` distinct keys of the `allowed` ones
+    /// have validly signed the payload hash, as in [`verify_threshold`]. The
+    /// payload itself should be checked with [`SignedBundle::matches_payload`].
+    pub fn verify_policy(
+        &self,
+        allowed: &[common::PublicKey],
+        threshold: usize,
+    ) -> bool {
+        verify_threshold(allowed, threshold, &self.preimage(), &self.signatures)
+    }
+
+    fn preimage(&self) -> Vec<u8> {
+        (SIGNED_BUNDLE_DOMAIN.to_owned(), self.payload_hash)
+            .try_to_vec()
+            .expect("Encoding signed bundle preimage shouldn't fail")
+    }
+}
+
+/// Check that at least `threshold` distinct members of `signers` have validly
+/// signed `msg`. Signatures from public keys that are not in `signers`,
+/// invalid signatures and repeated signatures of the same signer are not
+/// counted. A threshold of 0, which any input would meet, is never met.
+pub fn verify_threshold(
+    signers: &[common::PublicKey],
+    threshold: usize,
+    msg: &[u8],
+    sigs: &[(common::PublicKey, common::Signature)],
+) -> bool {
+    if threshold == 0 {
+        return false;
+    }
+    let valid_signers: HashSet<&common::PublicKey> = sigs
+        .iter()
+        .filter(|(pk, sig)| {
+            signers.contains(pk)
+                && common::SigScheme::verify_signature_raw(pk, msg, sig).is_ok()
+        })
+        .map(|(pk, _sig)| pk)
+        .collect();
+    valid_signers.len() >= threshold
+}
+
+/// Sign a message for the given chain only. The signed preimage is the Borsh
+/// encoding of the tuple `(domain, chain_id, msg)` where the domain is the
+/// string `"anoma-wallet:chain-signature:v1"` and `msg` is encoded as a byte
+/// vector, so the signature can't be replayed on a chain with another ID and
+/// it can't be mistaken for a plain signature of the message.
+pub fn sign_for_chain(
+    keypair: &common::SecretKey,
+    chain_id: &str,
+    msg: &[u8],
+) -> common::Signature {
+    common::SigScheme::sign(keypair, chain_preimage(chain_id, msg))
+}
+
+/// Verify a signature produced by [`sign_for_chain`] for the given chain.
+pub fn verify_for_chain(
+    pk: &common::PublicKey,
+    chain_id: &str,
+    msg: &[u8],
+    sig: &common::Signature,
+) -> bool {
+    let preimage = chain_preimage(chain_id, msg);
+    common::SigScheme::verify_signature_raw(pk, &preimage, sig).is_ok()
+}
+
+/// Delegate the authority of the keypair to another key, until the given
+/// expiry time if any. The signed preimage is the Borsh encoding of the
+/// tuple `(domain, delegator, delegate, expires_at)` where the domain is the
+/// string `"anoma-wallet:delegation:v1"`, so that a delegation can't be
+/// mistaken for a signature of a message.
+pub fn delegate(
+    keypair: &common::SecretKey,
+    delegate: &common::PublicKey,
+    expires_at: Option<u64>,
+) -> DelegationLink {
+    let preimage = delegation_preimage(&keypair.ref_to(), delegate, expires_at);
+    DelegationLink {
+        delegate: delegate.clone(),
+        expires_at,
+        signature: common::SigScheme::sign(keypair, preimage),
+    }
+}
+
+/// Verify a chain of delegations from the root key and the signature of the
+/// message by the last delegate of the chain, or by the root key itself if
+/// the chain is empty. Every link must be signed by the key delegated to by
+/// the link before it, or by the root key for the first one, so a broken or
+/// reordered chain is rejected, and no link may be expired at the time
+/// `now` in seconds since the Unix epoch.
+pub fn verify_delegation_chain(
+    root: &common::PublicKey,
+    chain: &[DelegationLink],
+    msg: &[u8],
+    final_sig: &common::Signature,
+    now: u64,
+) -> bool {
+    let mut delegator = root;
+    for link in chain {
+        if link.expires_at.map_or(false, |expires_at| now > expires_at) {
+            return false;
+        }
+        let preimage =
+            delegation_preimage(delegator, &link.delegate, link.expires_at);
+        if common::SigScheme::verify_signature_raw(
+            delegator,
+            &preimage,
+            &link.signature,
+        )
+        .is_err()
+        {
+            return false;
+        }
+        delegator = &link.delegate;
+    }
+    common::SigScheme::verify_signature_raw(delegator, msg, final_sig).is_ok()
+}
+
+/// Sign the data read from the stream with Ed25519ph, the prehashed variant
+/// of Ed25519 (RFC 8032) with an empty context. The data is hashed with
+/// SHA-512 in chunks of [`STREAM_CHUNK_SIZE`] bytes, so it never has to fit
+/// in memory. The signature is only valid for [`verify_stream`], not as a
+/// plain Ed25519 signature of the data.
+pub fn sign_prehashed(
+    keypair: &common::SecretKey,
+    reader: impl Read,
+) -> io::Result<common::Signature> {
+    let prehash = prehash_stream(reader)?;
+    let (mut secret, prefix) = expand_secret_key(keypair);
+    let public = keypair.ref_to();
+    let common::PublicKey::Ed25519(public) = &public;
+    let mut nonce =
+        hash_to_scalar(&[ED25519PH_DOM2, &prefix[..], &prehash[..]]);
+    let r = (&nonce * &ED25519_BASEPOINT_TABLE).compress();
+    let challenge = hash_to_scalar(&[
+        ED25519PH_DOM2,
+        r.as_bytes(),
+        &public.0.to_bytes(),
+        &prehash[..],
+    ]);
+    let s = nonce + challenge * secret;
+    secret.zeroize();
+    nonce.zeroize();
+    let mut bytes = [0; 64];
+    bytes[..32].copy_from_slice(r.as_bytes());
+    bytes[32..].copy_from_slice(&s.to_bytes());
+    Ok(ed25519::Signature(bytes.into())
+        .try_to_sig()
+        .expect("Converting the signature shouldn't fail"))
+}
+
+/// Verify an Ed25519ph signature produced by [`sign_prehashed`] of the data
+/// read from the stream. The data is hashed in chunks of
+/// [`STREAM_CHUNK_SIZE`] bytes. Like the plain Ed25519 verification of the
+/// wallet keys, the verification equation is the cofactored one. An error
+/// is only returned when reading fails.
+pub fn verify_stream(
+    pk: &common::PublicKey,
+    reader: impl Read,
+    sig: &common::Signature,
+) -> io::Result<bool> {
+    let prehash = prehash_stream(reader)?;
+    let common::PublicKey::Ed25519(pk) = pk;
+    let pk_bytes = pk.0.to_bytes();
+    let (r_bytes, s_bytes) = signature_parts(sig);
+    let (public, r, s) = match (
+        CompressedEdwardsY(pk_bytes).decompress(),
+        CompressedEdwardsY(r_bytes).decompress(),
+        Scalar::from_canonical_bytes(s_bytes),
+    ) {
+        (Some(public), Some(r), Some(s)) => (public, r, s),
+        _ => return Ok(false),
+    };
+    let challenge =
+        hash_to_scalar(&[ED25519PH_DOM2, &r_bytes, &pk_bytes, &prehash[..]]);
+    let lhs = &s * &ED25519_BASEPOINT_TABLE;
+    let rhs = r + challenge * public;
+    Ok((lhs - rhs).mul_by_cofactor().is_identity())
+}
+
+/// The encodings of the `R` point and the `s` scalar of a signature
+fn signature_parts(sig: &common::Signature) -> ([u8; 32], [u8; 32]) {
+    let common::Signature::Ed25519(sig) = sig;
+    let sig_bytes = sig.0.to_bytes();
+    let mut r_bytes = [0; 32];
+    r_bytes.copy_from_slice(&sig_bytes[..32]);
+    let mut s_bytes = [0; 32];
+    s_bytes.copy_from_slice(&sig_bytes[32..]);
+    (r_bytes, s_bytes)
+}
+
+/// The SHA-512 hash of the data read from the stream in chunks
+fn prehash_stream(mut reader: impl Read) -> io::Result<[u8; 64]> {
+    let mut hasher = Sha512::new();
+    let mut chunk = vec![0; STREAM_CHUNK_SIZE];
+    loop {
+        match reader.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(len) => hasher.update(&chunk[..len]),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    let mut prehash = [0; 64];
+    prehash.copy_from_slice(&hasher.finalize());
+    Ok(prehash)
+}
+
+/// The Ed25519 secret scalar and nonce prefix of a keypair, as derived from
+/// its seed by RFC 8032
+pub(super) fn expand_secret_key(
+    keypair: &common::SecretKey,
+) -> (Scalar, Zeroizing<[u8; 32]>) {
+    let common::SecretKey::Ed25519(sk) = keypair;
+    let mut hash = Sha512::digest(&sk.0.to_bytes());
+    let mut bits = [0; 32];
+    bits.copy_from_slice(&hash[..32]);
+    bits[0] &= 248;
+    bits[31] &= 127;
+    bits[31] |= 64;
+    let scalar = Scalar::from_bytes_mod_order(bits);
+    let mut prefix = Zeroizing::new([0; 32]);
+    prefix.copy_from_slice(&hash[32..]);
+    bits.zeroize();
+    hash.iter_mut().for_each(|byte| *byte = 0);
+    (scalar, prefix)
+}
+
+/// Hash the concatenation of the inputs with SHA-512 into a scalar
+pub(super) fn hash_to_scalar(inputs: &[&[u8]]) -> Scalar {
+    let mut hasher = Sha512::new();
+    for input in inputs {
+        hasher.update(input);
+    }
+    let mut wide = [0; 64];
+    wide.copy_from_slice(&hasher.finalize());
+    Scalar::from_bytes_mod_order_wide(&wide)
+}
+
+/// The signed preimage of a timestamped signature
+fn timestamped_preimage(msg: &[u8], timestamp: u64) -> Vec<u8> {
+    (
+        TIMESTAMPED_SIGNATURE_DOMAIN.to_owned(),
+        msg.to_vec(),
+        timestamp,
+    )
+        .try_to_vec()
+        .expect("Encoding timestamped signature preimage shouldn't fail")
+}
+
+/// The signed preimage of a chain-bound signature
+fn chain_preimage(chain_id: &str, msg: &[u8]) -> Vec<u8> {
+    (
+        CHAIN_SIGNATURE_DOMAIN.to_owned(),
+        chain_id.to_owned(),
+        msg.to_vec(),
+    )
+        .try_to_vec()
+        .expect("Encoding chain signature preimage shouldn't fail")
+}
+
+/// The signed preimage of a delegation
+fn delegation_preimage(
+    delegator: &common::PublicKey,
+    delegate: &common::PublicKey,
+    expires_at: Option<u64>,
+) -> Vec<u8> {
+    (
+        DELEGATION_DOMAIN.to_owned(),
+        delegator.clone(),
+        delegate.clone(),
+        expires_at,
+    )
+        .try_to_vec()
+        .expect("Encoding delegation preimage shouldn't fail")
+}
+
+/// The signed preimage of an ownership proof is the Borsh encoding of the
+/// domain separator, the challenge and the timestamp.
+fn ownership_preimage(challenge: &[u8], timestamp: u64) -> Vec<u8> {
+    (
+        OWNERSHIP_PROOF_DOMAIN.to_owned(),
+        challenge.to_vec(),
+        timestamp,
+    )
+        .try_to_vec()
+        .expect("Encoding ownership proof preimage shouldn't fail")
+}
+
+#[cfg(test)]
+mod tests {
+    use anoma::types::key::testing::{gen_keypair, keypair_1, keypair_2};
+
+    use super::*;
+
+    #[test]
+    fn test_signed_bundle() {
+        let payload = b"release archive";
+        let maintainers = [keypair_1().ref_to(), keypair_2().ref_to()];
+        let mut bundle = SignedBundle::new(payload);
+        bundle.sign(&keypair_1());
+        // An outsider's signature doesn't count
+        bundle.sign(&gen_keypair::<ed25519::SigScheme>().try_to_sk().unwrap());
+
+        let parsed = SignedBundle::parse(&bundle.to_bytes()).unwrap();
+        assert_eq!(parsed, bundle);
+        assert!(parsed.matches_payload(payload));
+        assert!(!parsed.matches_payload(b"tampered archive"));
+        assert!(parsed.verify_policy(&maintainers, 1));
+        assert!(!parsed.verify_policy(&maintainers, 2));
+
+        bundle.sign(&keypair_2());
+        assert!(bundle.verify_policy(&maintainers, 2));
+        // The signatures are bound to the payload hash
+        let mut other = SignedBundle::new(b"another archive");
+        other.signatures = bundle.signatures.clone();
+        assert!(!other.verify_policy(&maintainers, 1));
+    }
+
+    #[test]
+    fn test_audit_log() {
+        let keypair = AuditedKeypair::new(Rc::new(keypair_1()));
+        keypair.sign(b"unaudited");
+        assert!(keypair.entries().is_empty());
+
+        let keypair = keypair.with_audit_log();
+        let shared = keypair.clone();
+        let sig = keypair.sign(b"first");
+        assert!(common::SigScheme::verify_signature_raw(
+            &keypair.public_key(),
+            b"first",
+            &sig
+        )
+        .is_ok());
+        shared.sign(b"second");
+        let entries = keypair.audit_log().unwrap().entries();
+        assert_eq!(entries.len(), 2);
+        let hash: [u8; 32] = Sha256::digest(b"first").into();
+        assert_eq!(entries[0].message_hash, hash);
+        assert_eq!(shared.entries(), entries);
+    }
+
+    #[test]
+    fn test_verify_detailed() {
+        let keypair = keypair_1();
+        let pk = keypair.ref_to();
+        let sig = common::SigScheme::sign(&keypair, b"message");
+        assert_eq!(verify_detailed(&pk, b"message", &sig), VerifyResult::Valid);
+        assert_eq!(
+            verify_detailed(&pk, b"other message", &sig),
+            VerifyResult::Mismatch
+        );
+        assert_eq!(
+            verify_detailed(&keypair_2().ref_to(), b"message", &sig),
+            VerifyResult::Mismatch
+        );
+
+        let with_bytes = |update: &dyn Fn(&mut [u8])| {
+            let mut der = signature_to_der(&sig);
+            update(&mut der[3..]);
+            signature_from_der(&der).unwrap()
+        };
+        // No point has the y-coordinate 2
+        let malformed = with_bytes(&|bytes| {
+            bytes[..32].copy_from_slice(&[0; 32]);
+            bytes[0] = 2;
+        });
+        assert_eq!(
+            verify_detailed(&pk, b"message", &malformed),
+            VerifyResult::MalformedSignature
+        );
+        let non_canonical = with_bytes(&|bytes| bytes[63] |= 0xf0);
+        assert_eq!(
+            verify_detailed(&pk, b"message", &non_canonical),
+            VerifyResult::NonCanonical
+        );
+    }
+
+    #[test]
+    fn test_ownership_proof() {
+        let keypair = keypair_1();
+        let challenge = b"challenge";
+        let proof = prove_ownership(&keypair, challenge);
+        assert!(verify_ownership(&keypair.ref_to(), challenge, &proof));
+        // A different challenge or key must not verify
+        assert!(!verify_ownership(&keypair.ref_to(), b"other", &proof));
+        assert!(!verify_ownership(&keypair_2().ref_to(), challenge, &proof));
+        // A plain signature over the challenge is not a valid proof
+        let plain = OwnershipProof {
+            timestamp: proof.timestamp,
+            signature: common::SigScheme::sign(&keypair, challenge),
+        };
+        assert!(!verify_ownership(&keypair.ref_to(), challenge, &plain));
+    }
+
+    #[test]
+    fn test_timestamped_signature() {
+        let keypair = keypair_1();
+        let pk = keypair.ref_to();
+        let msg = b"challenge";
+        let sig = sign_timestamped(&keypair, msg, 1_000);
+        assert!(verify_timestamped(&pk, msg, &sig, 1_000, 0));
+        assert!(verify_timestamped(&pk, msg, &sig, 1_060, 60));
+        assert!(verify_timestamped(&pk, msg, &sig, 940, 60));
+        // Too old or too far in the future
+        assert!(!verify_timestamped(&pk, msg, &sig, 1_061, 60));
+        assert!(!verify_timestamped(&pk, msg, &sig, 939, 60));
+        // The timestamp can't be altered
+        let altered = TimestampedSignature {
+            timestamp: 1_060,
+            ..sig.clone()
+        };
+        assert!(!verify_timestamped(&pk, msg, &altered, 1_060, 60));
+        assert!(!verify_timestamped(&pk, b"other", &sig, 1_000, 60));
+    }
+
+    #[test]
+    fn test_delegation_chain() {
+        let root = keypair_1();
+        let middle = keypair_2();
+        let leaf = gen_keypair::<ed25519::SigScheme>().try_to_sk().unwrap();
+        let chain = vec![
+            delegate(&root, &middle.ref_to(), None),
+            delegate(&middle, &leaf.ref_to(), Some(1_000)),
+        ];
+        let msg = b"message";
+        let sig = common::SigScheme::sign(&leaf, msg);
+        assert!(verify_delegation_chain(
+            &root.ref_to(),
+            &chain,
+            msg,
+            &sig,
+            1_000
+        ));
+        assert!(!verify_delegation_chain(
+            &root.ref_to(),
+            &chain,
+            b"other",
+            &sig,
+            1_000
+        ));
+        // Expired
+        assert!(!verify_delegation_chain(
+            &root.ref_to(),
+            &chain,
+            msg,
+            &sig,
+            1_001
+        ));
+        // Out of order
+        let reordered = vec![chain[1].clone(), chain[0].clone()];
+        assert!(!verify_delegation_chain(
+            &root.ref_to(),
+            &reordered,
+            msg,
+            &sig,
+            1_000
+        ));
+        // Broken, skipping the middle key
+        assert!(!verify_delegation_chain(
+            &root.ref_to(),
+            &chain[1..],
+            msg,
+            &sig,
+            1_000
+        ));
+        // The expiry can't be extended
+        let mut extended = chain.clone();
+        extended[1].expires_at = Some(2_000);
+        assert!(!verify_delegation_chain(
+            &root.ref_to(),
+            &extended,
+            msg,
+            &sig,
+            1_500
+        ));
+        // Without delegation, only the root key's signature is valid
+        let root_sig = common::SigScheme::sign(&root, msg);
+        assert!(verify_delegation_chain(
+            &root.ref_to(),
+            &[],
+            msg,
+            &root_sig,
+            1_000
+        ));
+        assert!(!verify_delegation_chain(
+            &root.ref_to(),
+            &[],
+            msg,
+            &sig,
+            1_000
+        ));
+    }
+
+    #[test]
+    fn test_der_signature_roundtrip() {
+        let sig = common::SigScheme::sign(&keypair_1(), b"message");
+        let der = signature_to_der(&sig);
+        assert_eq!(der.len(), 67);
+        assert_eq!(&der[..3], &[0x03, 0x41, 0x00]);
+        assert_eq!(signature_from_der(&der).unwrap(), sig);
+
+        let mut bad_tag = der.clone();
+        bad_tag[0] = 0x04;
+        assert_eq!(
+            signature_from_der(&bad_tag),
+            Err(ParseDerSignatureError::UnexpectedTag(0x04))
+        );
+        let mut unused_bits = der.clone();
+        unused_bits[2] = 1;
+        assert_eq!(
+            signature_from_der(&unused_bits),
+            Err(ParseDerSignatureError::UnusedBits(1))
+        );
+        assert_eq!(
+            signature_from_der(&der[..40]),
+            Err(ParseDerSignatureError::Truncated)
+        );
+        let mut trailing = der;
+        trailing.push(0);
+        assert_eq!(
+            signature_from_der(&trailing),
+            Err(ParseDerSignatureError::TrailingData)
+        );
+    }
+
+    #[test]
+    fn test_sign_for_chain() {
+        let keypair = keypair_1();
+        let pk = keypair.ref_to();
+        let msg = b"transfer";
+        let sig = sign_for_chain(&keypair, "chain-a", msg);
+        assert!(verify_for_chain(&pk, "chain-a", msg, &sig));
+        assert!(!verify_for_chain(&pk, "chain-b", msg, &sig));
+        assert_ne!(sig, sign_for_chain(&keypair, "chain-b", msg));
+        // A plain signature of the message is not bound to any chain
+        let plain = common::SigScheme::sign(&keypair, msg);
+        assert!(!verify_for_chain(&pk, "chain-a", msg, &plain));
+    }
+
+    #[test]
+    fn test_sign_prehashed() {
+        let keypair = keypair_1();
+        let pk = keypair.ref_to();
+        // Longer than a chunk to exercise the chunked hashing
+        let data = vec![7; STREAM_CHUNK_SIZE * 2 + 1];
+        let sig = sign_prehashed(&keypair, &data[..]).unwrap();
+        assert!(verify_stream(&pk, &data[..], &sig).unwrap());
+        assert!(!verify_stream(&pk, &data[1..], &sig).unwrap());
+        let other = keypair_2().ref_to();
+        assert!(!verify_stream(&other, &data[..], &sig).unwrap());
+        // A prehashed signature is not a plain signature of the data
+        assert!(
+            common::SigScheme::verify_signature_raw(&pk, &data, &sig).is_err()
+        );
+    }
+
+    /// RFC 8032 test vector of Ed25519ph
+    #[test]
+    fn test_ed25519ph_rfc8032() {
+        let seed = hex::decode(
+            "833fe62409237b9d62ec77587520911e9a759cec1d19755b7da901b96dca3d42",
+        )
+        .unwrap();
+        let keypair = ed25519::SecretKey::try_from_slice(&seed)
+            .unwrap()
+            .try_to_sk::<common::SecretKey>()
+            .unwrap();
+        let sig = sign_prehashed(&keypair, &b"abc"[..]).unwrap();
+        let common::Signature::Ed25519(ed_sig) = &sig;
+        assert_eq!(
+            hex::encode(ed_sig.0.to_bytes()),
+            "98a70222f0b8121aa9d30f813d683f809e462b469c7ff87639499bb94e6dae41\
+             31f85042463c2a355a2003d062adf5aaa10b8c61e636062aaad11c2a26083406"
+        );
+        assert!(verify_stream(&keypair.ref_to(), &b"abc"[..], &sig).unwrap());
+    }
+
+    #[test]
+    fn test_verify_threshold() {
+        let msg = b"proposal";
+        let gen_sk =
+            || gen_keypair::<ed25519::SigScheme>().try_to_sk().unwrap();
+        let keypairs = [keypair_1(), keypair_2(), gen_sk()];
+        let signers: Vec<_> = keypairs.iter().map(|sk| sk.ref_to()).collect();
+        let sign = |sk: &common::SecretKey| {
+            (sk.ref_to(), common::SigScheme::sign(sk, msg))
+        };
+
+        let sigs = vec![sign(&keypairs[0]), sign(&keypairs[1])];
+        assert!(verify_threshold(&signers, 2, msg, &sigs));
+        assert!(!verify_threshold(&signers, 3, msg, &sigs));
+
+        // Duplicate signatures are counted once
+        let sigs = vec![sign(&keypairs[0]), sign(&keypairs[0])];
+        assert!(!verify_threshold(&signers, 2, msg, &sigs));
+
+        // Non-members and invalid signatures are not counted
+        let outsider = gen_sk();
+        let forged = (signers[1].clone(), sign(&keypairs[2]).1);
+        let sigs = vec![sign(&keypairs[0]), sign(&outsider), forged];
+        assert!(!verify_threshold(&signers, 2, msg, &sigs));
+
+        // Thresholds that are met by anything or that can't be met fail
+        assert!(!verify_threshold(&signers, 0, msg, &[]));
+        let sigs: Vec<_> = keypairs.iter().map(sign).collect();
+        assert!(verify_threshold(&signers, 3, msg, &sigs));
+        assert!(!verify_threshold(&signers, 4, msg, &sigs));
+    }
+}
@@ -0,0 +1,506 @@
+//! Social recovery of a keypair: its seed is split into shares with Shamir's
+//! secret sharing, so that any `threshold` of them recover it and fewer
+//! reveal nothing about it, and every share is encrypted to the public key of
+//! a trusted guardian, in the manner of a sealed box, so that each guardian
+//! can only decrypt their own share.
+//!
+//! The sharing is done byte by byte of the seed over GF(2^8) with the AES
+//! polynomial, the share of the i-th guardian being the value of the
+//! polynomials at `i`. The shares are encrypted with XChaCha20-Poly1305 under
+//! a key derived from the X25519 shared secret of an ephemeral key and the
+//! guardian's key, converted from Ed25519 like in
+//! [`super::key_agreement`]. The key derivation also covers the metadata of
+//! the share, so that it can't be altered.
+
+use std::collections::BTreeSet;
+
+use anoma::types::key::*;
+use borsh::{BorshDeserialize, BorshSerialize};
+use orion::aead;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+use super::import::KeyValidationError;
+use super::key_agreement::{to_x25519_public, to_x25519_secret};
+
+/// Domain separator of the keys encrypting guardian shares
+const GUARDIAN_SHARE_DOMAIN: &[u8] = b"anoma-wallet:guardian-share:v1";
+/// The length of a secret key seed, and thus of a share
+const SEED_LEN: usize = 32;
+
+#[allow(missing_docs)]
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum SocialRecoveryError {
+    #[error(
+        "The threshold must be between 1 and the number of guardians {1}, got \
+         {0}"
+    )]
+    InvalidThreshold(u8, usize),
+    #[error("At most 255 guardians are supported, got {0}")]
+    TooManyGuardians(usize),
+    #[error("The guardian {0} is given more than once")]
+    DuplicateGuardian(common::PublicKey),
+    #[error("Invalid public key of a guardian: {0}")]
+    InvalidGuardianKey(KeyValidationError),
+    #[error("The share is held by another guardian")]
+    NotGuardian,
+    #[error("Unable to decrypt the share, it has been altered")]
+    DecryptionFailed,
+    #[error("{found} shares were given, but {threshold} are required")]
+    NotEnoughShares { found: usize, threshold: u8 },
+    #[error("The share {0} is given more than once")]
+    DuplicateShare(u8),
+    #[error("The shares don't belong to the same recovery")]
+    InconsistentShares,
+    #[error("The recovered keypair doesn't match the shared public key")]
+    PublicKeyMismatch,
+}
+
+/// A share of a keypair encrypted to a guardian
+#[derive(Clone, Debug, PartialEq, BorshSerialize, BorshDeserialize)]
+pub struct GuardianShare {
+    /// The public key of the guardian holding the share
+    pub guardian: common::PublicKey,
+    /// The index of the share, from 1 to the number of guardians
+    pub index: u8,
+    /// The number of shares required to recover the keypair
+    pub threshold: u8,
+    /// The public key of the shared keypair
+    pub public_key: common::PublicKey,
+    /// The X25519 public key of the ephemeral secret that was used to derive
+    /// the encryption key together with the guardian's key
+    pub ephemeral_public_key: [u8; 32],
+    /// The encrypted share
+    pub encrypted_share: Vec<u8>,
+}
+
+/// A share decrypted by its guardian, to be given to the owner of the
+/// keypair for [`recover_from_shares`]. It's handed over in its Borsh
+/// encoding: the index, the threshold, the public key of the shared keypair
+/// and the bytes of the share.
+#[derive(Clone)]
+pub struct DecryptedGuardianShare {
+    index: u8,
+    threshold: u8,
+    public_key: common::PublicKey,
+    share: Zeroizing<[u8; SEED_LEN]>,
+}
+
+impl std::fmt::Debug for DecryptedGuardianShare {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DecryptedGuardianShare")
+            .field("index", &self.index)
+            .field("threshold", &self.threshold)
+            .field("public_key", &self.public_key)
+            .finish_non_exhaustive()
+    }
+}
+
+impl BorshSerialize for DecryptedGuardianShare {
+    fn serialize<W: std::io::Write>(
+        &self,
+        writer: &mut W,
+    ) -> std::io::Result<()> {
+        self.index.serialize(writer)?;
+        self.threshold.serialize(writer)?;
+        self.public_key.serialize(writer)?;
+        writer.write_all(&*self.share)
+    }
+}
+
+impl BorshDeserialize for DecryptedGuardianShare {
+    fn deserialize(buf: &mut &[u8]) -> std::io::Result<Self> {
+        let index = u8::deserialize(buf)?;
+        let threshold = u8::deserialize(buf)?;
+        let public_key = common::PublicKey::deserialize(buf)?;
+        if buf.len() < SEED_LEN {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "The share is too short",
+            ));
+        }
+        let mut share = Zeroizing::new([0; SEED_LEN]);
+        share.copy_from_slice(&buf[..SEED_LEN]);
+        *buf = &buf[SEED_LEN..];
+        Ok(Self {
+            index,
+            threshold,
+            public_key,
+            share,
+        })
+    }
+}
+
+impl GuardianShare {
+    /// Decrypt the share with the keypair of its guardian
+    pub fn decrypt(
+        &self,
+        guardian_keypair: &common::SecretKey,
+    ) -> Result<DecryptedGuardianShare, SocialRecoveryError> {
+        if guardian_keypair.ref_to() != self.guardian {
+            return Err(SocialRecoveryError::NotGuardian);
+        }
+        let guardian_secret = to_x25519_secret(guardian_keypair);
+        let guardian_public = x25519_dalek::PublicKey::from(&guardian_secret);
+        let ephemeral_public_key =
+            x25519_dalek::PublicKey::from(self.ephemeral_public_key);
+        let key = self.encryption_key(
+            &guardian_secret.diffie_hellman(&ephemeral_public_key),
+            &guardian_public,
+        );
+        let share = aead::open(&key, &self.encrypted_share)
+            .map(Zeroizing::new)
+            .map_err(|_| SocialRecoveryError::DecryptionFailed)?;
+        if share.len() != SEED_LEN {
+            return Err(SocialRecoveryError::DecryptionFailed);
+        }
+        let mut decrypted = Zeroizing::new([0; SEED_LEN]);
+        decrypted.copy_from_slice(&share);
+        Ok(DecryptedGuardianShare {
+            index: self.index,
+            threshold: self.threshold,
+            public_key: self.public_key.clone(),
+            share: decrypted,
+        })
+    }
+
+    /// Derive the key encrypting the share from the X25519 shared secret,
+    /// both X25519 public keys and the metadata of the share
+    fn encryption_key(
+        &self,
+        shared_secret: &x25519_dalek::SharedSecret,
+        guardian_public: &x25519_dalek::PublicKey,
+    ) -> aead::SecretKey {
+        let mut hasher = Sha256::new();
+        hasher.update(GUARDIAN_SHARE_DOMAIN);
+        hasher.update(shared_secret.as_bytes());
+        hasher.update(self.ephemeral_public_key);
+        hasher.update(guardian_public.as_bytes());
+        hasher.update([self.index, self.threshold]);
+        hasher.update(
+            self.public_key
+                .try_to_vec()
+                .expect("Encoding a public key shouldn't fail"),
+        );
+        aead::SecretKey::from_slice(&hasher.finalize())
+            .expect("A SHA-256 hash should be a valid encryption key")
+    }
+}
+
+/// Split the seed of the keypair into a share for each guardian, any
+/// `threshold` of which recover the keypair, and encrypt every share to its
+/// guardian's public key. The shares are returned in the order of the
+/// guardians.
+pub fn setup_social_recovery(
+    keypair: &common::SecretKey,
+    guardians: &[common::PublicKey],
+    threshold: u8,
+) -> Result<Vec<GuardianShare>, SocialRecoveryError> {
+    use rand::rngs::OsRng;
+    use rand::RngCore;
+
+    if guardians.len() > u8::MAX as usize {
+        return Err(SocialRecoveryError::TooManyGuardians(guardians.len()));
+    }
+    if threshold == 0 || threshold as usize > guardians.len() {
+        return Err(SocialRecoveryError::InvalidThreshold(
+            threshold,
+            guardians.len(),
+        ));
+    }
+    let mut seen = BTreeSet::new();
+    let mut guardian_keys = Vec::with_capacity(guardians.len());
+    for guardian in guardians {
+        if !seen.insert(guardian) {
+            return Err(SocialRecoveryError::DuplicateGuardian(
+                guardian.clone(),
+            ));
+        }
+        guardian_keys.push(
+            to_x25519_public(guardian)
+                .map_err(SocialRecoveryError::InvalidGuardianKey)?,
+        );
+    }
+
+    let common::SecretKey::Ed25519(sk) = keypair;
+    let seed = Zeroizing::new(sk.0.to_bytes());
+    // The coefficients of the polynomials of every byte of the seed, the
+    // constant term being the byte itself
+    let mut coefficients =
+        Zeroizing::new(vec![[0; SEED_LEN]; threshold as usize]);
+    coefficients[0] = *seed;
+    for coefficient in coefficients[1..].iter_mut() {
+        OsRng.fill_bytes(coefficient);
+    }
+
+    let public_key = keypair.ref_to();
+    let shares = guardians
+        .iter()
+        .zip(guardian_keys)
+        .zip(1..=u8::MAX)
+        .map(|((guardian, guardian_public), index)| {
+            let mut share = Zeroizing::new([0; SEED_LEN]);
+            for (byte, share_byte) in share.iter_mut().enumerate() {
+                // Horner's method from the highest degree coefficient
+                *share_byte =
+                    coefficients.iter().rev().fold(0, |acc, coefficient| {
+                        gf_mul(acc, index) ^ coefficient[byte]
+                    });
+            }
+
+            let mut ephemeral_secret = Zeroizing::new([0; 32]);
+            OsRng.fill_bytes(&mut *ephemeral_secret);
+            let ephemeral_secret =
+                x25519_dalek::StaticSecret::from(*ephemeral_secret);
+            let mut guardian_share = GuardianShare {
+                guardian: guardian.clone(),
+                index,
+                threshold,
+                public_key: public_key.clone(),
+                ephemeral_public_key: *x25519_dalek::PublicKey::from(
+                    &ephemeral_secret,
+                )
+                .as_bytes(),
+                encrypted_share: vec![],
+            };
+            let key = guardian_share.encryption_key(
+                &ephemeral_secret.diffie_hellman(&guardian_public),
+                &guardian_public,
+            );
+            guardian_share.encrypted_share = aead::seal(&key, &share[..])
+                .expect("Encryption of data shouldn't fail");
+            guardian_share
+        })
+        .collect();
+    Ok(shares)
+}
+
+/// Recover the keypair from at least as many decrypted shares as the
+/// threshold. The recovered keypair is checked against the public key of the
+/// shares, which fails if any of the shares used is corrupted.
+pub fn recover_from_shares(
+    shares: &[DecryptedGuardianShare],
+) -> Result<common::SecretKey, SocialRecoveryError> {
+    let first = match shares.first() {
+        Some(first) => first,
+        None => {
+            return Err(SocialRecoveryError::NotEnoughShares {
+                found: 0,
+                threshold: 1,
+            });
+        }
+    };
+    let threshold = first.threshold;
+    if shares.iter().any(|share| {
+        share.threshold != threshold || share.public_key != first.public_key
+    }) {
+        return Err(SocialRecoveryError::InconsistentShares);
+    }
+    let mut indices = BTreeSet::new();
+    for share in shares {
+        if share.index == 0 || !indices.insert(share.index) {
+            return Err(SocialRecoveryError::DuplicateShare(share.index));
+        }
+    }
+    if shares.len() < threshold as usize {
+        return Err(SocialRecoveryError::NotEnoughShares {
+            found: shares.len(),
+            threshold,
+        });
+    }
+
+    // Lagrange interpolation at 0 of the polynomials through the shares,
+    // of which only `threshold` are needed
+    let shares = &shares[..threshold as usize];
+    let mut seed = Zeroizing::new([0; SEED_LEN]);
+    for (j, share) in shares.iter().enumerate() {
+        let basis = shares.iter().enumerate().filter(|(m, _)| *m != j).fold(
+            1,
+            |acc, (_, other)| {
+                gf_mul(acc, gf_div(other.index, other.index ^ share.index))
+            },
+        );
+        for (byte, share_byte) in seed.iter_mut().zip(share.share.iter()) {
+            *byte ^= gf_mul(basis, *share_byte);
+        }
+    }
+
+    let keypair = ed25519::SecretKey::try_from_slice(&seed[..])
+        .expect("Any 32 bytes are a valid Ed25519 secret key")
+        .try_to_sk::<common::SecretKey>()
+        .expect("Converting the secret key shouldn't fail");
+    if keypair.ref_to() != first.public_key {
+        return Err(SocialRecoveryError::PublicKeyMismatch);
+    }
+    Ok(keypair)
+}
+
+/// Multiply in GF(2^8) modulo the AES polynomial `x^8 + x^4 + x^3 + x + 1`,
+/// without branching on the operands
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    for _ in 0..8 {
+        product ^= a & 0u8.wrapping_sub(b & 1);
+        let carry = 0u8.wrapping_sub(a >> 7);
+        a = (a << 1) ^ (0x1b & carry);
+        b >>= 1;
+    }
+    product
+}
+
+/// Divide in GF(2^8) by a non-zero element, i.e. multiply by its inverse
+/// `b^254`
+fn gf_div(a: u8, b: u8) -> u8 {
+    let mut inverse = 1;
+    for _ in 0..254 {
+        inverse = gf_mul(inverse, b);
+    }
+    gf_mul(a, inverse)
+}
+
+#[cfg(test)]
+mod tests {
+    use anoma::types::key::testing::{gen_keypair, keypair_1, keypair_2};
+
+    use super::*;
+
+    fn new_keypair() -> common::SecretKey {
+        gen_keypair::<ed25519::SigScheme>().try_to_sk().unwrap()
+    }
+
+    #[test]
+    fn test_gf_arithmetic() {
+        // The example of FIPS 197
+        assert_eq!(gf_mul(0x57, 0x83), 0xc1);
+        for a in 1..=u8::MAX {
+            assert_eq!(gf_mul(gf_div(1, a), a), 1);
+        }
+    }
+
+    #[test]
+    fn test_social_recovery() {
+        let guardian_keypairs: Vec<_> = (0..5).map(|_| new_keypair()).collect();
+        let guardians: Vec<_> = guardian_keypairs
+            .iter()
+            .map(|keypair| keypair.ref_to())
+            .collect();
+        let shares =
+            setup_social_recovery(&keypair_1(), &guardians, 3).unwrap();
+        assert_eq!(shares.len(), 5);
+
+        let decrypted: Vec<_> = shares
+            .iter()
+            .zip(&guardian_keypairs)
+            .map(|(share, keypair)| share.decrypt(keypair).unwrap())
+            .collect();
+        let recovered = recover_from_shares(&[
+            decrypted[4].clone(),
+            decrypted[0].clone(),
+            decrypted[2].clone(),
+        ])
+        .unwrap();
+        assert_eq!(recovered.ref_to(), keypair_1().ref_to());
+        assert_eq!(
+            recover_from_shares(&decrypted).unwrap().ref_to(),
+            keypair_1().ref_to()
+        );
+        assert_eq!(
+            recover_from_shares(&decrypted[..2]).unwrap_err(),
+            SocialRecoveryError::NotEnoughShares {
+                found: 2,
+                threshold: 3
+            }
+        );
+        assert_eq!(
+            recover_from_shares(&[
+                decrypted[0].clone(),
+                decrypted[0].clone(),
+                decrypted[1].clone(),
+            ])
+            .unwrap_err(),
+            SocialRecoveryError::DuplicateShare(1)
+        );
+    }
+
+    #[test]
+    fn test_guardian_share_protection() {
+        let guardian = new_keypair();
+        let guardians = [guardian.ref_to(), keypair_2().ref_to()];
+        let shares =
+            setup_social_recovery(&keypair_1(), &guardians, 2).unwrap();
+
+        // Each guardian can only decrypt their own share
+        assert_eq!(
+            shares[0].decrypt(&keypair_2()).unwrap_err(),
+            SocialRecoveryError::NotGuardian
+        );
+        let mut stolen = shares[0].clone();
+        stolen.guardian = keypair_2().ref_to();
+        assert_eq!(
+            stolen.decrypt(&keypair_2()).unwrap_err(),
+            SocialRecoveryError::DecryptionFailed
+        );
+        // The metadata can't be altered
+        let mut altered = shares[0].clone();
+        altered.threshold = 1;
+        assert_eq!(
+            altered.decrypt(&guardian).unwrap_err(),
+            SocialRecoveryError::DecryptionFailed
+        );
+
+        assert_eq!(
+            setup_social_recovery(&keypair_1(), &guardians, 3).unwrap_err(),
+            SocialRecoveryError::InvalidThreshold(3, 2)
+        );
+        assert_eq!(
+            setup_social_recovery(&keypair_1(), &guardians, 0).unwrap_err(),
+            SocialRecoveryError::InvalidThreshold(0, 2)
+        );
+        assert!(matches!(
+            setup_social_recovery(
+                &keypair_1(),
+                &[guardian.ref_to(), guardian.ref_to()],
+                1
+            ),
+            Err(SocialRecoveryError::DuplicateGuardian(_))
+        ));
+    }
+
+    #[test]
+    fn test_decrypted_share_bytes_roundtrip() {
+        let guardian_keypairs: Vec<_> = (0..3).map(|_| new_keypair()).collect();
+        let guardians: Vec<_> = guardian_keypairs
+            .iter()
+            .map(|keypair| keypair.ref_to())
+            .collect();
+        let shares =
+            setup_social_recovery(&keypair_1(), &guardians, 2).unwrap();
+
+        let handed_over: Vec<Vec<u8>> = shares
+            .iter()
+            .zip(&guardian_keypairs)
+            .take(2)
+            .map(|(share, keypair)| {
+                share.decrypt(keypair).unwrap().try_to_vec().unwrap()
+            })
+            .collect();
+        let received: Vec<_> = handed_over
+            .iter()
+            .map(|bytes| DecryptedGuardianShare::try_from_slice(bytes).unwrap())
+            .collect();
+        assert_eq!(received[1].index, 2);
+        assert_eq!(received[1].threshold, 2);
+        let recovered = recover_from_shares(&received).unwrap();
+        assert_eq!(recovered.ref_to(), keypair_1().ref_to());
+
+        // A truncated or extended share is rejected
+        let bytes = &handed_over[0];
+        assert!(DecryptedGuardianShare::try_from_slice(
+            &bytes[..bytes.len() - 1]
+        )
+        .is_err());
+        let extended = [bytes.as_slice(), &[0]].concat();
+        assert!(DecryptedGuardianShare::try_from_slice(&extended).is_err());
+    }
+}
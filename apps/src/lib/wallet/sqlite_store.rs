@@ -0,0 +1,412 @@
+//! A wallet key store backed by an SQLite database, for wallets with too
+//! many keys to be kept in a single wallet file.
+//!
+//! Every key is a row of its alias, public key, implicit address and role,
+//! which are stored in the clear and indexed, so that keys can be listed and
+//! looked up without decrypting anything, and of its keypair, stored as its
+//! encoding in the wallet file, which stays encrypted. Unencrypted keypairs
+//! are rejected. The whole database can also be encrypted with SQLCipher if
+//! the wallet is built with the `sqlcipher` feature, to hide the public
+//! columns too.
+
+use std::path::Path;
+use std::str::FromStr;
+
+use anoma::types::address::{Address, ImplicitAddress};
+use anoma::types::key::*;
+use rusqlite::{params, Connection, OptionalExtension};
+use thiserror::Error;
+
+use super::alias::{Alias, KeyRole};
+use super::StoredKeypair;
+
+/// The schema of the database, created when opening it if needed
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS keys (
+        name TEXT PRIMARY KEY NOT NULL,
+        public_key TEXT,
+        address TEXT,
+        role TEXT NOT NULL,
+        encrypted_blob TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS keys_address ON keys (address);
+    CREATE INDEX IF NOT EXISTS keys_public_key ON keys (public_key);
+";
+/// The version of the [`SCHEMA`], recorded as the `user_version` of the
+/// database
+const SCHEMA_VERSION: u32 = 1;
+
+#[allow(missing_docs)]
+#[derive(Error, Debug)]
+pub enum SqliteStoreError {
+    #[error("Wallet database error: {0}")]
+    Sqlite(rusqlite::Error),
+    #[error(
+        "A database key was given, but the wallet wasn't built with SQLCipher \
+         support"
+    )]
+    NoCipherSupport,
+    #[error("The wallet database already has a key with the alias {0}")]
+    AliasExists(String),
+    #[error("The keypair {0} is not encrypted and can't be stored")]
+    Unencrypted(String),
+    #[error("Invalid key {0} in the wallet database: {1}")]
+    InvalidRow(String, String),
+}
+
+impl From<rusqlite::Error> for SqliteStoreError {
+    fn from(err: rusqlite::Error) -> Self {
+        Self::Sqlite(err)
+    }
+}
+
+/// The public columns of a key of a [`SqliteWalletStore`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct PublicKeyEntry {
+    /// The alias of the key
+    pub name: String,
+    /// The public key, if it's known without decryption
+    pub public_key: Option<common::PublicKey>,
+    /// The implicit address of the public key
+    pub address: Option<Address>,
+    /// The role of the key, derived from its alias
+    pub role: KeyRole,
+}
+
+/// A key store in an SQLite database
+#[derive(Debug)]
+pub struct SqliteWalletStore {
+    conn: Connection,
+}
+
+impl SqliteWalletStore {
+    /// Open the database at the given path, creating it if it doesn't
+    /// exist. If a database key is given, the whole database is encrypted
+    /// with it by SQLCipher, which fails if the wallet isn't built with the
+    /// `sqlcipher` feature. A wrong key fails to open an existing database.
+    pub fn open(
+        path: &Path,
+        database_key: Option<&str>,
+    ) -> Result<Self, SqliteStoreError> {
+        let conn = Connection::open(path)?;
+        if let Some(database_key) = database_key {
+            // A plain SQLite silently ignores the `key` pragma, so check that
+            // SQLCipher is there to not leave the database in the clear
+            let cipher_version: Option<String> = conn
+                .query_row("PRAGMA cipher_version", [], |row| row.get(0))
+                .optional()?;
+            if cipher_version.is_none() {
+                return Err(SqliteStoreError::NoCipherSupport);
+            }
+            conn.pragma_update(None, "key", &database_key)?;
+        }
+        conn.execute_batch(SCHEMA)?;
+        migrate(&conn)?;
+        Ok(Self { conn })
+    }
+
+    /// Insert a keypair with the given alias. Fails if the alias is taken or
+    /// if the keypair is not encrypted.
+    pub fn insert(
+        &self,
+        name: &str,
+        keypair: &StoredKeypair,
+    ) -> Result<(), SqliteStoreError> {
+        let name = Alias::from(name).normalize();
+        if let StoredKeypair::Raw(_) = keypair {
+            return Err(SqliteStoreError::Unencrypted(name));
+        }
+        let public_key = keypair.public_key();
+        let address = public_key.as_ref().map(|pk| {
+            Address::Implicit(ImplicitAddress(PublicKeyHash::from(pk))).encode()
+        });
+        let inserted = self.conn.execute(
+            "INSERT OR IGNORE INTO keys (name, public_key, address, role, \
+             encrypted_blob) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                name,
+                public_key.map(|pk| pk.to_string()),
+                address,
+                KeyRole::of_alias(&name).to_string(),
+                keypair.to_string(),
+            ],
+        )?;
+        if inserted == 0 {
+            return Err(SqliteStoreError::AliasExists(name));
+        }
+        Ok(())
+    }
+
+    /// Get the keypair with the given alias
+    pub fn get(
+        &self,
+        name: &str,
+    ) -> Result<Option<StoredKeypair>, SqliteStoreError> {
+        let name = Alias::from(name).normalize();
+        let blob: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT encrypted_blob FROM keys WHERE name = ?1",
+                params![name],
+                |row| row.get(0),
+            )
+            .optional()?;
+        blob.map(|blob| parse_keypair(&name, &blob)).transpose()
+    }
+
+    /// Find the alias and the keypair of the key with the given implicit
+    /// address. If several aliases have the same key, the first one in
+    /// alphabetical order is found.
+    pub fn find_by_address(
+        &self,
+        address: &Address,
+    ) -> Result<Option<(String, StoredKeypair)>, SqliteStoreError> {
+        let row: Option<(String, String)> = self
+            .conn
+            .query_row(
+                "SELECT name, encrypted_blob FROM keys WHERE address = ?1 \
+                 ORDER BY name LIMIT 1",
+                params![address.encode()],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        row.map(|(name, blob)| {
+            let keypair = parse_keypair(&name, &blob)?;
+            Ok((name, keypair))
+        })
+        .transpose()
+    }
+
+    /// The public columns of all the keys, ordered by alias. No keypair is
+    /// read.
+    pub fn iter_public(
+        &self,
+    ) -> Result<impl Iterator<Item = PublicKeyEntry>, SqliteStoreError> {
+        let mut statement = self.conn.prepare(
+            "SELECT name, public_key, address FROM keys ORDER BY name",
+        )?;
+        let rows = statement
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, Option<String>>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        let entries = rows
+            .into_iter()
+            .map(|(name, public_key, address)| {
+                let public_key = public_key
+                    .map(|pk| common::PublicKey::from_str(&pk))
+                    .transpose()
+                    .map_err(|err| {
+                        SqliteStoreError::InvalidRow(
+                            name.clone(),
+                            err.to_string(),
+                        )
+                    })?;
+                let address = address
+                    .map(|address| Address::decode(&address))
+                    .transpose()
+                    .map_err(|err| {
+                        SqliteStoreError::InvalidRow(
+                            name.clone(),
+                            err.to_string(),
+                        )
+                    })?;
+                let role = KeyRole::of_alias(&name);
+                Ok(PublicKeyEntry {
+                    name,
+                    public_key,
+                    address,
+                    role,
+                })
+            })
+            .collect::<Result<Vec<_>, SqliteStoreError>>()?;
+        Ok(entries.into_iter())
+    }
+}
+
+/// Migrate a database created with an older schema to the [`SCHEMA_VERSION`].
+/// The schema before the version 1 has no role column, which is added and
+/// filled in from the aliases.
+fn migrate(conn: &Connection) -> Result<(), SqliteStoreError> {
+    let version: u32 =
+        conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version >= SCHEMA_VERSION {
+        return Ok(());
+    }
+    let transaction = conn.unchecked_transaction()?;
+    let has_role: bool = transaction.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info('keys') WHERE name = \
+         'role'",
+        [],
+        |row| row.get(0),
+    )?;
+    if !has_role {
+        transaction.execute_batch(
+            "ALTER TABLE keys ADD COLUMN role TEXT NOT NULL DEFAULT ''",
+        )?;
+        let mut statement = transaction.prepare("SELECT name FROM keys")?;
+        let names = statement
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        drop(statement);
+        for name in names {
+            transaction.execute(
+                "UPDATE keys SET role = ?1 WHERE name = ?2",
+                params![KeyRole::of_alias(&name).to_string(), name],
+            )?;
+        }
+    }
+    transaction.pragma_update(None, "user_version", &SCHEMA_VERSION)?;
+    transaction.commit()?;
+    Ok(())
+}
+
+/// Parse a keypair read from the database
+fn parse_keypair(
+    name: &str,
+    blob: &str,
+) -> Result<StoredKeypair, SqliteStoreError> {
+    StoredKeypair::from_str(blob).map_err(|err| {
+        SqliteStoreError::InvalidRow(name.to_owned(), err.to_string())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use anoma::types::key::testing::{keypair_1, keypair_2};
+
+    use super::*;
+
+    #[test]
+    fn test_sqlite_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wallet.sqlite");
+        let (encrypted, _) =
+            StoredKeypair::new(keypair_1(), Some("password".to_owned()));
+        let watch_only = StoredKeypair::WatchOnly(keypair_2().ref_to());
+        {
+            let store = SqliteWalletStore::open(&path, None).unwrap();
+            store.insert("Bob", &encrypted).unwrap();
+            store.insert("alice-validator-key", &watch_only).unwrap();
+            assert!(matches!(
+                store.insert("bob", &watch_only),
+                Err(SqliteStoreError::AliasExists(_))
+            ));
+            let (raw, _) = StoredKeypair::new(keypair_1(), None);
+            assert!(matches!(
+                store.insert("carol", &raw),
+                Err(SqliteStoreError::Unencrypted(_))
+            ));
+        }
+
+        let store = SqliteWalletStore::open(&path, None).unwrap();
+        let keypair = store.get("bob").unwrap().unwrap();
+        assert_eq!(keypair.to_string(), encrypted.to_string());
+        assert!(store.get("carol").unwrap().is_none());
+
+        let address = Address::Implicit(ImplicitAddress(PublicKeyHash::from(
+            &keypair_2().ref_to(),
+        )));
+        let (name, _) = store.find_by_address(&address).unwrap().unwrap();
+        assert_eq!(name, "alice-validator-key");
+        let role: String = store
+            .conn
+            .query_row(
+                "SELECT role FROM keys WHERE name = 'alice-validator-key'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(role, "validator-account");
+
+        let entries: Vec<_> = store.iter_public().unwrap().collect();
+        assert_eq!(
+            entries,
+            vec![
+                PublicKeyEntry {
+                    name: "alice-validator-key".to_owned(),
+                    public_key: Some(keypair_2().ref_to()),
+                    address: Some(address),
+                    role: KeyRole::ValidatorAccount,
+                },
+                PublicKeyEntry {
+                    name: "bob".to_owned(),
+                    public_key: Some(keypair_1().ref_to()),
+                    address: Some(Address::Implicit(ImplicitAddress(
+                        PublicKeyHash::from(&keypair_1().ref_to())
+                    ))),
+                    role: KeyRole::Account,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_find_by_address_of_a_shared_key() {
+        let store =
+            SqliteWalletStore::open(Path::new(":memory:"), None).unwrap();
+        let watch_only = StoredKeypair::WatchOnly(keypair_1().ref_to());
+        for name in ["carol", "alice", "bob"] {
+            store.insert(name, &watch_only).unwrap();
+        }
+        let address = Address::Implicit(ImplicitAddress(PublicKeyHash::from(
+            &keypair_1().ref_to(),
+        )));
+        let (name, _) = store.find_by_address(&address).unwrap().unwrap();
+        assert_eq!(name, "alice");
+    }
+
+    #[test]
+    fn test_migrate_keys_without_role() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wallet.sqlite");
+        let watch_only = StoredKeypair::WatchOnly(keypair_2().ref_to());
+        {
+            let conn = Connection::open(&path).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE keys (
+                    name TEXT PRIMARY KEY NOT NULL,
+                    public_key TEXT,
+                    address TEXT,
+                    encrypted_blob TEXT NOT NULL
+                );",
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO keys (name, public_key, address, encrypted_blob) \
+                 VALUES ('alice-validator-key', NULL, NULL, ?1)",
+                params![watch_only.to_string()],
+            )
+            .unwrap();
+        }
+
+        let store = SqliteWalletStore::open(&path, None).unwrap();
+        let role: String = store
+            .conn
+            .query_row(
+                "SELECT role FROM keys WHERE name = 'alice-validator-key'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(role, "validator-account");
+        let version: u32 = store
+            .conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, SCHEMA_VERSION);
+        // New keys get their role after the migration too
+        store.insert("bob", &watch_only).unwrap();
+        let role: String = store
+            .conn
+            .query_row("SELECT role FROM keys WHERE name = 'bob'", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(role, "account");
+    }
+}
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::io::prelude::*;
 use std::io::{self, Write};
@@ -13,11 +13,16 @@ use anoma::types::transaction::EllipticCurve;
 use ark_std::rand::prelude::*;
 use ark_std::rand::SeedableRng;
 use file_lock::{FileLock, FileOptions};
+use orion::pwhash;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
+use zeroize::Zeroizing;
 
-use super::alias::{self, Alias};
-use super::keys::StoredKeypair;
+use super::alias::{self, Alias, KeyRole};
+#[cfg(feature = "pq")]
+use super::hybrid::{HybridPublicKey, HybridSecretKey};
+use super::import::{validate_public_key, KeyValidationError};
+use super::keys::{EncryptedKeypair, KdfParams, MachineIdFile, StoredKeypair};
 use super::pre_genesis;
 use crate::cli;
 use crate::config::genesis::genesis_config::GenesisConfig;
@@ -51,14 +56,122 @@ pub struct ValidatorData {
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct Store {
     /// Cryptographic keypairs
+    #[serde(serialize_with = "ordered_map")]
     keys: HashMap<Alias, StoredKeypair>,
     /// Anoma address book
+    #[serde(serialize_with = "ordered_map")]
     addresses: HashMap<Alias, Address>,
     /// Known mappings of public key hashes to their aliases in the `keys`
     /// field. Used for look-up by a public key.
+    #[serde(serialize_with = "ordered_map")]
     pkhs: HashMap<PublicKeyHash, Alias>,
     /// Special keys if the wallet belongs to a validator
     pub(crate) validator_data: Option<ValidatorData>,
+    /// Wallet configuration
+    #[serde(default)]
+    config: WalletConfig,
+}
+
+/// Wallet configuration persisted in the store
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct WalletConfig {
+    /// The KDF parameters used to encrypt new keys, e.g. as calibrated for
+    /// this device. The [`KdfParams::default`] are used when absent.
+    #[serde(default)]
+    pub default_kdf_params: KdfParams,
+    /// The optional duress password
+    #[serde(default)]
+    pub duress: Option<DuressConfig>,
+    /// Whether to bind new encrypted keys to this machine, so that they can
+    /// only be decrypted on it. The machine fingerprint is read from the
+    /// default [`MachineIdFile`].
+    #[serde(default)]
+    pub bind_to_machine: bool,
+}
+
+/// A newly generated encrypted keypair with the public key and the implicit
+/// address to register for a new account
+#[derive(Debug)]
+pub struct AccountBundle {
+    /// The encrypted keypair to store
+    pub stored: StoredKeypair,
+    /// The public key of the keypair
+    pub public_key: common::PublicKey,
+    /// The implicit address derived from the public key
+    pub address: Address,
+}
+
+/// Public keys of the wallet's keys pinned on their first use, to detect the
+/// substitution of a keypair in the wallet store by another one. The pins are
+/// kept in a file separate from the store, which should be integrity
+/// protected, e.g. read-only or on another medium, because an attacker that
+/// can replace both files can also replace the pins.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct KeyPins {
+    /// The pinned public keys of the keys by their alias
+    #[serde(serialize_with = "ordered_map")]
+    keys: HashMap<Alias, String>,
+}
+
+/// A duress ("panic") password configuration. When the duress password is
+/// entered in place of a decryption password, the wallet drops all of its
+/// keys instead of decrypting one, saves itself without them and fails as if
+/// the password was wrong.
+///
+/// This is meant to protect against a coercer who forces the user to unlock
+/// the wallet in front of them, not against one who has a copy of the wallet
+/// file or who can inspect the process. In particular:
+/// - the keys are only dropped from the wallet, copies of a key still held
+///   elsewhere in the process are unaffected and the memory is not guaranteed
+///   to be scrubbed
+/// - only the keys decrypted with [`super::Wallet::unlock_key`] (or the
+///   functions using it) check the duress password, not those decrypted
+///   directly with [`StoredKeypair::get`]
+/// - unless `delete_files` is set, the wallet file is overwritten in place, so
+///   the old keys may still be found on the disk
+/// - a deleted file can still be recovered from backups or by forensic analysis
+///   of the disk
+/// - the duress password hash is stored in the clear in the wallet file, so its
+///   presence isn't hidden from anyone who can read the file
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DuressConfig {
+    /// The Argon2i hash of the duress password in its encoded form
+    pub password_hash: String,
+    /// Whether to also delete the wallet file from disk
+    #[serde(default)]
+    pub delete_files: bool,
+}
+
+impl DuressConfig {
+    /// Hash the duress password with the given KDF parameters
+    pub fn new(
+        password: &str,
+        delete_files: bool,
+        kdf_params: &KdfParams,
+    ) -> Result<Self, orion::errors::UnknownCryptoError> {
+        let password = pwhash::Password::from_slice(password.as_bytes())?;
+        let hash = pwhash::hash_password(
+            &password,
+            kdf_params.iterations,
+            kdf_params.memory,
+        )?;
+        Ok(Self {
+            password_hash: hash.unprotected_as_encoded().to_owned(),
+            delete_files,
+        })
+    }
+
+    /// Check if the given password is the duress password
+    pub fn matches(&self, password: &str) -> bool {
+        let hash = match pwhash::PasswordHash::from_encoded(&self.password_hash)
+        {
+            Ok(hash) => hash,
+            Err(_) => return false,
+        };
+        pwhash::Password::from_slice(password.as_bytes())
+            .and_then(|password| pwhash::hash_password_verify(&hash, &password))
+            .is_ok()
+    }
 }
 
 #[derive(Error, Debug)]
@@ -71,6 +184,92 @@ pub enum LoadStoreError {
     StoreNewWallet(String),
 }
 
+#[derive(Error, Debug)]
+pub enum KeyPinsError {
+    #[error("Failed to read or write the pinned keys: {0}")]
+    Io(io::Error),
+    #[error("Failed decoding the pinned keys: {0}")]
+    Decode(toml::de::Error),
+    #[error("Invalid pinned public key of \"{0}\": {1}")]
+    InvalidPublicKey(Alias, ParsePublicKeyError),
+}
+
+/// The number of seeds drawn from the random number generator before giving
+/// up if each of them looks like the output of a failed generator
+const MAX_GENERATE_ATTEMPTS: usize = 3;
+
+#[derive(Error, Debug)]
+pub enum GenerateError {
+    #[error(
+        "The random number generator appears to have failed, it only produced \
+         pathological seeds"
+    )]
+    WeakRandomness,
+}
+
+#[derive(Error, Debug)]
+pub enum ProvisionError {
+    #[error("Unable to generate the keypair: {0}")]
+    Generate(GenerateError),
+    #[error("Unable to bind the keypair to this machine: {0}")]
+    MachineBinding(io::Error),
+    #[error("The encrypted keypair doesn't decrypt to the generated one")]
+    Roundtrip,
+}
+
+/// A problem found in a wallet store by [`Store::validate`]
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ValidationIssue {
+    #[error("A key has an empty alias")]
+    EmptyAlias,
+    #[error(
+        "The key \"{0}\" has the alias of a {1} key without a validator alias \
+         before its suffix"
+    )]
+    MalformedRole(Alias, KeyRole),
+    #[error("The public key of \"{0}\" is invalid: {1}")]
+    InvalidPublicKey(Alias, KeyValidationError),
+    #[error(
+        "The encrypted keypair of \"{0}\" is truncated, it can't be decrypted"
+    )]
+    MalformedKeystore(Alias),
+    #[error(
+        "The same public key {public_key} is stored under several aliases: {}",
+        .aliases.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")
+    )]
+    DuplicateKey {
+        public_key: common::PublicKey,
+        aliases: Vec<Alias>,
+    },
+    #[error(
+        "The public key hash {0} refers to the alias \"{1}\", which has no key"
+    )]
+    DanglingPublicKeyHash(PublicKeyHash, Alias),
+    #[error(
+        "The public key hash {0} refers to the alias \"{1}\", whose key has \
+         another public key"
+    )]
+    MismatchedPublicKeyHash(PublicKeyHash, Alias),
+    #[error(
+        "The public key hash of \"{0}\" is missing, it can't be found by its \
+         public key"
+    )]
+    MissingPublicKeyHash(Alias),
+}
+
+#[derive(Error, Debug)]
+pub enum TransferKeyError {
+    #[error("No key found with the alias \"{0}\"")]
+    KeyNotFound(Alias),
+    #[error(
+        "The alias \"{0}\" is already used by a key or an address in the \
+         destination"
+    )]
+    AliasCollision(Alias),
+    #[error("The key is already in the destination with the alias \"{0}\"")]
+    DuplicateKey(Alias),
+}
+
 impl Store {
     #[cfg(not(feature = "dev"))]
     fn new(genesis: GenesisConfig) -> Self {
@@ -264,7 +463,24 @@ impl Store {
     ) -> (Alias, Rc<common::SecretKey>) {
         let sk = gen_sk();
         let pkh: PublicKeyHash = PublicKeyHash::from(&sk.ref_to());
-        let (keypair_to_store, raw_keypair) = StoredKeypair::new(sk, password);
+        let (keypair_to_store, raw_keypair) = match password {
+            Some(password) => {
+                let encrypted =
+                    self.encrypt_new_key(&sk, password).unwrap_or_else(|err| {
+                        eprintln!(
+                            "Unable to bind the key to this machine: {}",
+                            err
+                        );
+                        cli::safe_exit(1)
+                    });
+                (StoredKeypair::Encrypted(encrypted), Rc::new(sk))
+            }
+            None => StoredKeypair::new_with_kdf_params(
+                sk,
+                None,
+                &self.config.default_kdf_params,
+            ),
+        };
         let address = Address::Implicit(ImplicitAddress(pkh.clone()));
         let alias: Alias = alias.unwrap_or_else(|| pkh.clone().into()).into();
         if self
@@ -281,6 +497,94 @@ impl Store {
         (alias, raw_keypair)
     }
 
+    /// Encrypt a newly generated keypair with the password, with the
+    /// configured KDF parameters and bound to this machine if configured so
+    fn encrypt_new_key(
+        &self,
+        sk: &common::SecretKey,
+        password: String,
+    ) -> io::Result<EncryptedKeypair> {
+        if self.config.bind_to_machine {
+            EncryptedKeypair::new_bound_to_machine(
+                sk,
+                password,
+                &self.config.default_kdf_params,
+                &MachineIdFile::default(),
+            )
+        } else {
+            Ok(EncryptedKeypair::new_with_kdf_params(
+                sk,
+                password,
+                &self.config.default_kdf_params,
+            ))
+        }
+    }
+
+    /// Generate a new keypair for an account, encrypted with the given
+    /// password like the keys of [`Store::gen_key`], together with its public
+    /// key and implicit address. The keypair isn't inserted into the store.
+    /// The raw secret key is neither returned nor retained, it is dropped as
+    /// soon as it is encrypted.
+    pub fn provision_account(
+        &self,
+        password: &str,
+    ) -> Result<AccountBundle, ProvisionError> {
+        let keypair = try_gen_sk().map_err(ProvisionError::Generate)?;
+        let public_key = keypair.ref_to();
+        let pkh = PublicKeyHash::from(&public_key);
+        let stored = StoredKeypair::Encrypted(
+            self.encrypt_new_key(&keypair, password.to_owned())
+                .map_err(ProvisionError::MachineBinding)?,
+        );
+        if !stored.verify_roundtrip(password, &keypair) {
+            return Err(ProvisionError::Roundtrip);
+        }
+        drop(keypair);
+        Ok(AccountBundle {
+            stored,
+            public_key,
+            address: Address::Implicit(ImplicitAddress(pkh)),
+        })
+    }
+
+    /// Generate a new hybrid Ed25519 and Dilithium3 keypair, encrypted with
+    /// the password, and insert it into the store like
+    /// [`Store::gen_key`]. The key's public key hash and implicit address are
+    /// those of its Ed25519 key. Returns the alias and the public key.
+    #[cfg(feature = "pq")]
+    pub fn gen_hybrid_key(
+        &mut self,
+        alias: Option<String>,
+        password: String,
+    ) -> (Alias, HybridPublicKey) {
+        let keypair = HybridSecretKey::generate();
+        let public_key = keypair.public_key();
+        let pkh = PublicKeyHash::from(&public_key.ed25519);
+        let encrypted = EncryptedKeypair::new_hybrid(
+            &keypair,
+            password,
+            &self.config.default_kdf_params,
+        );
+        let address = Address::Implicit(ImplicitAddress(pkh.clone()));
+        let alias: Alias = alias.unwrap_or_else(|| pkh.clone().into()).into();
+        if self
+            .insert_keypair(
+                alias.clone(),
+                StoredKeypair::Encrypted(encrypted),
+                pkh,
+            )
+            .is_none()
+        {
+            eprintln!("Action cancelled, no changes persisted.");
+            cli::safe_exit(1);
+        }
+        if self.insert_address(alias.clone(), address).is_none() {
+            eprintln!("Action cancelled, no changes persisted.");
+            cli::safe_exit(1);
+        }
+        (alias, public_key)
+    }
+
     /// Generate keypair for signing protocol txs and for the DKG
     /// A protocol keypair may be optionally provided
     ///
@@ -307,6 +611,33 @@ impl Store {
         self.validator_data = Some(ValidatorData { address, keys });
     }
 
+    /// Returns the wallet configuration
+    pub fn config(&self) -> &WalletConfig {
+        &self.config
+    }
+
+    /// Set the KDF parameters used to encrypt new keys
+    pub fn set_default_kdf_params(&mut self, kdf_params: KdfParams) {
+        self.config.default_kdf_params = kdf_params;
+    }
+
+    /// Set whether new encrypted keys are bound to this machine
+    pub fn set_bind_to_machine(&mut self, bind_to_machine: bool) {
+        self.config.bind_to_machine = bind_to_machine;
+    }
+
+    /// Set or unset the duress password
+    pub fn set_duress(&mut self, duress: Option<DuressConfig>) {
+        self.config.duress = duress;
+    }
+
+    /// Drop all the keys and the validator data from the store
+    pub fn wipe_keys(&mut self) {
+        self.keys.clear();
+        self.pkhs.clear();
+        self.validator_data = None;
+    }
+
     /// Returns the validator data, if it exists
     pub fn get_validator_data(&self) -> Option<&ValidatorData> {
         self.validator_data.as_ref()
@@ -347,6 +678,47 @@ impl Store {
         Some(alias)
     }
 
+    /// Move the key with the given alias, together with its public key hash
+    /// mapping and the address with the same alias, into another store. The
+    /// stored keypair is moved as is, without re-encrypting or serializing it.
+    /// Nothing is changed if the alias or the key is already present in the
+    /// destination.
+    pub(super) fn transfer_key(
+        &mut self,
+        alias: &Alias,
+        to: &mut Store,
+    ) -> Result<(), TransferKeyError> {
+        if !self.keys.contains_key(alias) {
+            return Err(TransferKeyError::KeyNotFound(alias.clone()));
+        }
+        if to.keys.contains_key(alias)
+            || (self.addresses.contains_key(alias)
+                && to.addresses.contains_key(alias))
+        {
+            return Err(TransferKeyError::AliasCollision(alias.clone()));
+        }
+        let pkh = self
+            .pkhs
+            .iter()
+            .find(|(_pkh, key_alias)| *key_alias == alias)
+            .map(|(pkh, _alias)| pkh.clone());
+        if let Some(existing) =
+            pkh.as_ref().and_then(|pkh| to.find_alias_by_pkh(pkh))
+        {
+            return Err(TransferKeyError::DuplicateKey(existing));
+        }
+        let keypair = self.keys.remove(alias).unwrap();
+        to.keys.insert(alias.clone(), keypair);
+        if let Some(pkh) = pkh {
+            self.pkhs.remove(&pkh);
+            to.pkhs.insert(pkh, alias.clone());
+        }
+        if let Some(address) = self.addresses.remove(alias) {
+            to.addresses.insert(alias.clone(), address);
+        }
+        Ok(())
+    }
+
     /// Insert a new address with the given alias. If the alias is already used,
     /// will prompt for overwrite/reselection confirmation, which when declined,
     /// the address won't be added. Return the selected alias if the address has
@@ -429,6 +801,87 @@ impl Store {
         });
     }
 
+    /// Check the consistency of the store, e.g. after loading it, using only
+    /// the data readable without decryption. All the issues found are
+    /// returned, in the order of the aliases.
+    pub fn validate(&self) -> Result<(), Vec<ValidationIssue>> {
+        let mut issues = Vec::new();
+        let mut keys: Vec<_> = self.keys.iter().collect();
+        keys.sort_by_key(|(alias, _)| alias.normalize());
+
+        let mut aliases_by_pk: HashMap<common::PublicKey, Vec<Alias>> =
+            HashMap::new();
+        for (alias, keypair) in &keys {
+            if alias.is_empty() {
+                issues.push(ValidationIssue::EmptyAlias);
+            }
+            let role = KeyRole::of_alias(alias.normalize());
+            if role != KeyRole::Account && alias.normalize().starts_with('-') {
+                issues.push(ValidationIssue::MalformedRole(
+                    (*alias).clone(),
+                    role,
+                ));
+            }
+            if let StoredKeypair::Encrypted(encrypted) = keypair {
+                if !encrypted.is_well_formed() {
+                    issues.push(ValidationIssue::MalformedKeystore(
+                        (*alias).clone(),
+                    ));
+                }
+            }
+            if let Some(pk) = keypair.public_key() {
+                if let Err(err) = validate_public_key(&pk) {
+                    issues.push(ValidationIssue::InvalidPublicKey(
+                        (*alias).clone(),
+                        err,
+                    ));
+                }
+                let pkh = PublicKeyHash::from(&pk);
+                if !self.pkhs.contains_key(&pkh) {
+                    issues.push(ValidationIssue::MissingPublicKeyHash(
+                        (*alias).clone(),
+                    ));
+                }
+                aliases_by_pk.entry(pk).or_default().push((*alias).clone());
+            }
+        }
+
+        let mut duplicates: Vec<_> = aliases_by_pk
+            .into_iter()
+            .filter(|(_, aliases)| aliases.len() > 1)
+            .collect();
+        duplicates.sort_by_key(|(_, aliases)| aliases[0].normalize());
+        issues.extend(duplicates.into_iter().map(|(public_key, aliases)| {
+            ValidationIssue::DuplicateKey {
+                public_key,
+                aliases,
+            }
+        }));
+
+        let mut pkhs: Vec<_> = self.pkhs.iter().collect();
+        pkhs.sort_by_key(|(_, alias)| alias.normalize());
+        for (pkh, alias) in pkhs {
+            match self.keys.get(alias).map(StoredKeypair::public_key) {
+                None => issues.push(ValidationIssue::DanglingPublicKeyHash(
+                    pkh.clone(),
+                    alias.clone(),
+                )),
+                Some(Some(pk)) if &PublicKeyHash::from(&pk) != pkh => issues
+                    .push(ValidationIssue::MismatchedPublicKeyHash(
+                        pkh.clone(),
+                        alias.clone(),
+                    )),
+                Some(_) => {}
+            }
+        }
+
+        if issues.is_empty() {
+            Ok(())
+        } else {
+            Err(issues)
+        }
+    }
+
     fn decode(data: Vec<u8>) -> Result<Self, toml::de::Error> {
         toml::from_slice(&data)
     }
@@ -438,6 +891,21 @@ impl Store {
     }
 }
 
+/// Serialize a map with its entries sorted by key, so that the same wallet is
+/// always written the same way, whatever the order its entries were added in,
+/// e.g. to keep the diffs of a wallet file tracked in version control clean
+fn ordered_map<K, V, S>(
+    map: &HashMap<K, V>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    K: Ord + Serialize,
+    V: Serialize,
+    S: serde::Serializer,
+{
+    map.iter().collect::<BTreeMap<_, _>>().serialize(serializer)
+}
+
 enum ConfirmationResponse {
     Replace,
     Reselect(Alias),
@@ -493,25 +961,137 @@ fn show_overwrite_confirmation(
 
 /// Wallet file name
 const FILE_NAME: &str = "wallet.toml";
+const PINS_FILE_NAME: &str = "pinned_keys.toml";
 
 /// Get the path to the wallet store.
 pub fn wallet_file(store_dir: impl AsRef<Path>) -> PathBuf {
     store_dir.as_ref().join(FILE_NAME)
 }
 
-/// Generate a new secret key.
+/// Get the default path to the pinned keys, next to the wallet store.
+pub fn pins_file(store_dir: impl AsRef<Path>) -> PathBuf {
+    store_dir.as_ref().join(PINS_FILE_NAME)
+}
+
+impl KeyPins {
+    /// Load the pinned keys from the given file. No keys are pinned if the
+    /// file doesn't exist.
+    pub fn load(path: &Path) -> Result<Self, KeyPinsError> {
+        match fs::read_to_string(path) {
+            Ok(data) => toml::from_str(&data).map_err(KeyPinsError::Decode),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                Ok(Self::default())
+            }
+            Err(err) => Err(KeyPinsError::Io(err)),
+        }
+    }
+
+    /// Save the pinned keys to the given file.
+    pub fn save(&self, path: &Path) -> Result<(), KeyPinsError> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(KeyPinsError::Io)?;
+        }
+        let data = toml::to_string(self)
+            .expect("Serializing the pinned keys shouldn't fail");
+        fs::write(path, data).map_err(KeyPinsError::Io)
+    }
+
+    /// Get the pinned public key of the given alias, if any
+    pub fn get(
+        &self,
+        alias: &Alias,
+    ) -> Result<Option<common::PublicKey>, KeyPinsError> {
+        self.keys
+            .get(alias)
+            .map(|pk| {
+                common::PublicKey::from_str(pk).map_err(|err| {
+                    KeyPinsError::InvalidPublicKey(alias.clone(), err)
+                })
+            })
+            .transpose()
+    }
+
+    /// Pin the public key of the given alias
+    pub fn pin(&mut self, alias: Alias, pk: &common::PublicKey) {
+        self.keys.insert(alias, pk.to_string());
+    }
+}
+
+/// Generate a new secret key. This uses the operating system's random number
+/// generator and is the only safe way to generate keys for real use.
+///
+/// Panics if the operating system's generator appears to have failed, as no
+/// key can then be safely generated. Use [`try_gen_sk`] to handle it.
 pub fn gen_sk() -> common::SecretKey {
+    try_gen_sk()
+        .expect("The operating system's random number generator has failed")
+}
+
+/// Generate a new secret key like [`gen_sk`], returning an error instead of
+/// panicking if the operating system's generator appears to have failed.
+pub fn try_gen_sk() -> Result<common::SecretKey, GenerateError> {
     use rand::rngs::OsRng;
     let mut csprng = OsRng {};
-    ed25519::SigScheme::generate(&mut csprng)
-        .try_to_sk()
-        .unwrap()
+    gen_sk_from_rng(&mut csprng)
+}
+
+/// Generate a new secret key from the given random number generator. Unless
+/// the generator is seeded from a good entropy source, the generated key is
+/// not safe to use. Prefer [`gen_sk`].
+///
+/// A seed that is obviously not random, such as one repeating a short
+/// pattern, is discarded and another one is drawn. If the generator keeps
+/// producing such seeds, it is assumed to have failed and
+/// [`GenerateError::WeakRandomness`] is returned instead of a guessable key.
+pub fn gen_sk_from_rng<R>(
+    csprng: &mut R,
+) -> Result<common::SecretKey, GenerateError>
+where
+    R: rand::CryptoRng + rand::RngCore,
+{
+    let mut seed = Zeroizing::new([0u8; 32]);
+    for _ in 0..MAX_GENERATE_ATTEMPTS {
+        csprng.fill_bytes(&mut *seed);
+        if !is_weak_seed(&*seed) {
+            let sk = ed25519::SecretKey::try_from_slice(&*seed)
+                .expect("Any 32 bytes should be a valid secret key");
+            return Ok(sk.try_to_sk().unwrap());
+        }
+        tracing::warn!("Discarding a seed that doesn't look random");
+    }
+    Err(GenerateError::WeakRandomness)
 }
 
-#[cfg(all(test, feature = "dev"))]
+/// Check if the seed repeats a pattern of at most 8 bytes, e.g. all zeros or
+/// all ones, or is an arithmetic progression, e.g. a counter. A uniformly
+/// random seed has a negligible probability of either.
+fn is_weak_seed(seed: &[u8]) -> bool {
+    let is_periodic = (1..=8)
+        .any(|period| seed.iter().zip(&seed[period..]).all(|(a, b)| a == b));
+    let step = seed[1].wrapping_sub(seed[0]);
+    let is_progression = seed
+        .windows(2)
+        .all(|pair| pair[1].wrapping_sub(pair[0]) == step);
+    is_periodic || is_progression
+}
+
+/// Generate a secret key deterministically from the given seed for
+/// reproducible tests. The key is as predictable as the seed, so this must
+/// never be used for real keys.
+#[cfg(test)]
+pub fn gen_sk_from_seed(seed: [u8; 32]) -> common::SecretKey {
+    use rand_chacha::rand_core::SeedableRng;
+    let mut rng = rand_chacha::ChaCha20Rng::from_seed(seed);
+    gen_sk_from_rng(&mut rng).unwrap()
+}
+
+#[cfg(test)]
 mod test_wallet {
+    use anoma::types::key::testing::{keypair_1, keypair_2};
+
     use super::*;
 
+    #[cfg(feature = "dev")]
     #[test]
     fn test_toml_roundtrip() {
         let mut store = Store::new();
@@ -523,4 +1103,230 @@ mod test_wallet {
         let data = store.encode();
         let _ = Store::decode(data).expect("Test failed");
     }
+
+    #[test]
+    fn test_gen_sk_from_seed_is_reproducible() {
+        let seed = [7; 32];
+        assert_eq!(
+            gen_sk_from_seed(seed).ref_to(),
+            gen_sk_from_seed(seed).ref_to()
+        );
+        assert_ne!(
+            gen_sk_from_seed(seed).ref_to(),
+            gen_sk_from_seed([8; 32]).ref_to()
+        );
+    }
+
+    /// A failed generator that only yields the same byte
+    struct ConstantRng(u8);
+
+    impl rand::RngCore for ConstantRng {
+        fn next_u32(&mut self) -> u32 {
+            u32::from_le_bytes([self.0; 4])
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            u64::from_le_bytes([self.0; 8])
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            dest.fill(self.0)
+        }
+
+        fn try_fill_bytes(
+            &mut self,
+            dest: &mut [u8],
+        ) -> Result<(), rand::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    impl rand::CryptoRng for ConstantRng {}
+
+    #[test]
+    fn test_gen_sk_rejects_weak_randomness() {
+        for byte in [0x00, 0xff] {
+            assert!(matches!(
+                gen_sk_from_rng(&mut ConstantRng(byte)),
+                Err(GenerateError::WeakRandomness)
+            ));
+        }
+        let counter: Vec<u8> = (0..32).collect();
+        assert!(is_weak_seed(&counter));
+        let pattern: Vec<u8> = [0xde, 0xad, 0xbe, 0xef].repeat(8);
+        assert!(is_weak_seed(&pattern));
+        assert!(!is_weak_seed(&rfc8032_seed()));
+    }
+
+    /// The seed of the test 1 of RFC 8032
+    fn rfc8032_seed() -> Vec<u8> {
+        hex::decode(
+            "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_provision_account() {
+        let mut store = Store::default();
+        store.config.default_kdf_params = KdfParams {
+            iterations: 3,
+            memory: 8,
+        };
+        let bundle = store.provision_account("password").unwrap();
+        assert!(bundle.stored.is_encrypted());
+        match &bundle.stored {
+            StoredKeypair::Encrypted(encrypted) => assert_eq!(
+                encrypted.header().kdf_params,
+                Some(store.config.default_kdf_params)
+            ),
+            _ => unreachable!(),
+        }
+        let keypair = bundle.stored.get(true, Some("password".into())).unwrap();
+        assert_eq!(keypair.ref_to(), bundle.public_key);
+        let pkh = PublicKeyHash::from(&bundle.public_key);
+        assert_eq!(bundle.address, Address::Implicit(ImplicitAddress(pkh)));
+    }
+
+    fn insert(store: &mut Store, alias: &str, keypair: common::SecretKey) {
+        let pkh = PublicKeyHash::from(&keypair.ref_to());
+        let (stored, _raw) = StoredKeypair::new(keypair, None);
+        store.keys.insert(alias.into(), stored);
+        store.pkhs.insert(pkh, alias.into());
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut store = Store::default();
+        insert(&mut store, "alice", keypair_1());
+        insert(&mut store, "bob", keypair_2());
+        assert_eq!(store.validate(), Ok(()));
+
+        // Store the same key again, with a malformed validator alias
+        let (stored, _raw) = StoredKeypair::new(keypair_1(), None);
+        store.keys.insert("-consensus-key".into(), stored);
+        // Point a public key hash to a missing key
+        let dangling = PublicKeyHash::from(&keypair_2().ref_to());
+        store.pkhs.insert(dangling.clone(), "carol".into());
+        store.keys.remove(&Alias::from("bob"));
+
+        let issues = store.validate().unwrap_err();
+        assert_eq!(
+            issues,
+            vec![
+                ValidationIssue::MalformedRole(
+                    "-consensus-key".into(),
+                    KeyRole::Consensus
+                ),
+                ValidationIssue::DuplicateKey {
+                    public_key: keypair_1().ref_to(),
+                    aliases: vec!["-consensus-key".into(), "alice".into()],
+                },
+                ValidationIssue::DanglingPublicKeyHash(
+                    dangling,
+                    "carol".into()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_transfer_key() {
+        let mut from = Store::default();
+        let mut to = Store::default();
+        let keypair = keypair_1();
+        let pkh = PublicKeyHash::from(&keypair.ref_to());
+        let alias: Alias = "key".into();
+        let address = Address::Implicit(ImplicitAddress(pkh.clone()));
+        let (stored, _raw) = StoredKeypair::new(keypair, None);
+        from.keys.insert(alias.clone(), stored);
+        from.pkhs.insert(pkh.clone(), alias.clone());
+        from.addresses.insert(alias.clone(), address.clone());
+
+        from.transfer_key(&alias, &mut to).unwrap();
+        assert!(from.find_key("key").is_none());
+        assert!(from.find_alias_by_pkh(&pkh).is_none());
+        assert!(from.find_address("key").is_none());
+        assert!(to.find_key_by_pkh(&pkh).is_some());
+        assert_eq!(to.find_address("key"), Some(&address));
+
+        // The address' alias must also be free in the destination
+        let mut other = Store::default();
+        other.addresses.insert(alias.clone(), address.clone());
+        assert!(matches!(
+            to.transfer_key(&alias, &mut other),
+            Err(TransferKeyError::AliasCollision(_))
+        ));
+        assert!(to.find_key("key").is_some());
+        assert_eq!(to.find_address("key"), Some(&address));
+
+        // Moving it back fails with a collision once the alias is taken
+        let (stored, _raw) = StoredKeypair::new(keypair_1(), None);
+        from.keys.insert(alias.clone(), stored);
+        assert!(matches!(
+            to.transfer_key(&alias, &mut from),
+            Err(TransferKeyError::AliasCollision(_))
+        ));
+        assert!(to.find_key("key").is_some());
+    }
+
+    /// A store with a raw key, whose encoding is deterministic, and an
+    /// address for each alias, added in the order of the given aliases
+    fn store_with_keys(aliases: &[&str]) -> Store {
+        let mut store = Store::default();
+        for alias in aliases {
+            let seed = [alias.to_lowercase().as_bytes()[0]; 32];
+            let keypair = gen_sk_from_seed(seed);
+            let pkh = PublicKeyHash::from(&keypair.ref_to());
+            let (stored, _raw) = StoredKeypair::new(keypair, None);
+            store.keys.insert((*alias).into(), stored);
+            store.pkhs.insert(pkh.clone(), (*alias).into());
+            store.addresses.insert(
+                (*alias).into(),
+                Address::Implicit(ImplicitAddress(pkh)),
+            );
+        }
+        store
+    }
+
+    #[test]
+    fn test_encoding_is_sorted() {
+        let store = store_with_keys(&["carol", "Alice", "bob", "dave"]);
+        let encoded = store.encode();
+        assert_eq!(
+            encoded,
+            store_with_keys(&["dave", "bob", "Alice", "carol"]).encode()
+        );
+        assert_eq!(encoded, store.encode());
+
+        let encoded = String::from_utf8(encoded).unwrap();
+        let position = |alias: &str| encoded.find(alias).unwrap();
+        assert!(position("alice") < position("bob"));
+        assert!(position("bob") < position("carol"));
+        assert!(position("carol") < position("dave"));
+    }
+
+    #[test]
+    fn test_duress_password_matches() {
+        let duress =
+            DuressConfig::new("duress", false, &KdfParams::default()).unwrap();
+        assert!(duress.matches("duress"));
+        assert!(!duress.matches("password"));
+        assert!(!duress.password_hash.contains("duress"));
+    }
+
+    #[test]
+    fn test_key_pins_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = pins_file(dir.path());
+        let alias: Alias = "key".into();
+        let mut pins = KeyPins::load(&path).unwrap();
+        assert_eq!(pins.get(&alias).unwrap(), None);
+
+        pins.pin(alias.clone(), &keypair_1().ref_to());
+        pins.save(&path).unwrap();
+        let pins = KeyPins::load(&path).unwrap();
+        assert_eq!(pins.get(&alias).unwrap(), Some(keypair_1().ref_to()));
+    }
 }
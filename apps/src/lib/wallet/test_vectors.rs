@@ -0,0 +1,220 @@
+//! Canonical test vectors of the wallet's key formats for cross-implementation
+//! compatibility testing.
+//!
+//! All the values are hex encoded. The public key and the signature are in
+//! their Borsh encoding, i.e. prefixed with the scheme tag byte `00` for
+//! Ed25519. The first vector is the test 1 of RFC 8032.
+//!
+//! The keystore vectors are the same keypairs encrypted in the wallet's
+//! `encrypted_v1:` format. The salt and the nonce of the AEAD are normally
+//! drawn at random, so these are encrypted with fixed ones, which is only
+//! possible in tests and with the `testing` feature.
+
+use anoma::types::address::{Address, ImplicitAddress};
+use anoma::types::key::*;
+use borsh::{BorshDeserialize, BorshSerialize};
+
+#[cfg(any(test, feature = "testing"))]
+use super::keys::{EncryptedKeypair, KdfParams, StoredKeypair};
+
+/// A test vector derived from a fixed seed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TestVector {
+    /// The Ed25519 secret key seed
+    pub seed: String,
+    /// The public key
+    pub public_key: String,
+    /// The hash of the public key
+    pub public_key_hash: String,
+    /// The implicit address derived from the public key
+    pub address: String,
+    /// The signed message
+    pub message: String,
+    /// The signature of the message
+    pub signature: String,
+}
+
+/// The fixed seeds and messages the test vectors are derived from
+const INPUTS: [([u8; 32], &[u8]); 3] = [
+    (
+        [
+            0x9d, 0x61, 0xb1, 0x9d, 0xef, 0xfd, 0x5a, 0x60, 0xba, 0x84, 0x4a,
+            0xf4, 0x92, 0xec, 0x2c, 0xc4, 0x44, 0x49, 0xc5, 0x69, 0x7b, 0x32,
+            0x69, 0x19, 0x70, 0x3b, 0xac, 0x03, 0x1c, 0xae, 0x7f, 0x60,
+        ],
+        b"",
+    ),
+    ([1; 32], b"anoma test vector"),
+    ([2; 32], b"anoma test vector"),
+];
+
+/// A test vector of a keypair encrypted with a fixed password, salt and nonce
+#[cfg(any(test, feature = "testing"))]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeystoreVector {
+    /// The Ed25519 secret key seed
+    pub seed: String,
+    /// The password
+    pub password: String,
+    /// The salt of the key derivation
+    pub salt: String,
+    /// The nonce of the AEAD
+    pub nonce: String,
+    /// The parameters of the key derivation
+    pub kdf_params: KdfParams,
+    /// The stored encrypted keypair
+    pub encrypted: String,
+}
+
+/// The password of the keystore vectors
+#[cfg(any(test, feature = "testing"))]
+const KEYSTORE_PASSWORD: &str = "password";
+/// The salt of the keystore vectors
+#[cfg(any(test, feature = "testing"))]
+const KEYSTORE_SALT: [u8; 16] = [1; 16];
+/// The nonce of the keystore vectors
+#[cfg(any(test, feature = "testing"))]
+const KEYSTORE_NONCE: [u8; 24] = [2; 24];
+/// The cheapest key derivation parameters, for the vectors to be quick to
+/// check
+#[cfg(any(test, feature = "testing"))]
+const KEYSTORE_KDF_PARAMS: KdfParams = KdfParams {
+    iterations: 3,
+    memory: 8,
+};
+
+/// The secret key of a seed
+fn secret_key(seed: &[u8; 32]) -> common::SecretKey {
+    ed25519::SecretKey::try_from_slice(seed)
+        .expect("The seed should be a valid secret key")
+        .try_to_sk()
+        .expect("Converting the secret key shouldn't fail")
+}
+
+/// Derive the test vectors with the current implementation
+pub fn test_vectors() -> Vec<TestVector> {
+    INPUTS
+        .iter()
+        .map(|(seed, message)| {
+            let sk = secret_key(seed);
+            let pk = sk.ref_to();
+            let pkh = PublicKeyHash::from(&pk);
+            let address = Address::Implicit(ImplicitAddress(pkh.clone()));
+            let signature = common::SigScheme::sign(&sk, message);
+            TestVector {
+                seed: hex::encode(seed),
+                public_key: pk.to_string(),
+                public_key_hash: pkh.to_string(),
+                address: address.encode(),
+                message: hex::encode(message),
+                signature: hex::encode(
+                    signature
+                        .try_to_vec()
+                        .expect("Encoding signature shouldn't fail"),
+                ),
+            }
+        })
+        .collect()
+}
+
+/// Derive the keystore vectors of the seeds of the [`test_vectors`] with the
+/// current implementation
+#[cfg(any(test, feature = "testing"))]
+pub fn keystore_vectors() -> Vec<KeystoreVector> {
+    INPUTS
+        .iter()
+        .map(|(seed, _message)| {
+            let encrypted = EncryptedKeypair::new_with_salt(
+                &secret_key(seed),
+                KEYSTORE_PASSWORD.to_owned(),
+                KEYSTORE_SALT,
+                KEYSTORE_NONCE,
+                &KEYSTORE_KDF_PARAMS,
+            );
+            KeystoreVector {
+                seed: hex::encode(seed),
+                password: KEYSTORE_PASSWORD.to_owned(),
+                salt: hex::encode(KEYSTORE_SALT),
+                nonce: hex::encode(KEYSTORE_NONCE),
+                kdf_params: KEYSTORE_KDF_PARAMS,
+                encrypted: StoredKeypair::Encrypted(encrypted).to_string(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The test vectors must never change, as other implementations rely on
+    /// them.
+    #[test]
+    fn test_vectors_golden() {
+        let expected = vec![
+            TestVector {
+                seed: "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60".into(),
+                public_key: "00d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a".into(),
+                public_key_hash: "5C6A8BE64D810B2CF2FCE43583FEFF53F8054064".into(),
+                address: "atest1d9khqw36x4pnvsfcgfznvdzy8qcnqs3jgdrry3jrg56rxdfcxdry23jxx5e5vwpsx56rqd35d7yepg".into(),
+                message: "".into(),
+                signature: "00e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b".into(),
+            },
+            TestVector {
+                seed: "0101010101010101010101010101010101010101010101010101010101010101".into(),
+                public_key: "008a88e3dd7409f195fd52db2d3cba5d72ca6709bf1d94121bf3748801b40f6f5c".into(),
+                public_key_hash: "C5F42D4C9E0DE03550F6AD9D4FD8028B6F2B500C".into(),
+                address: "atest1d9khqw36gv65vdpjgs6yxw29xpzy2vpnx56nq33kg9zrj3p5gezrsvpj8pprv33jgg6nqvzra0pqkp".into(),
+                message: "616e6f6d61207465737420766563746f72".into(),
+                signature: "006985341c21e981fc15a056288d13a2ecd0b466db29b131417cc2d4f13fee131035e06b473059f4f92a7d63834f7ce8a88751268129209914d60c9e9cd333e80e".into(),
+            },
+            TestVector {
+                seed: "0202020202020202020202020202020202020202020202020202020202020202".into(),
+                public_key: "008139770ea87d175f56a35466c34c7ecccb8d8a91b4ee37a25df60f5b8fc9b394".into(),
+                public_key_hash: "1D067EA12D430B1E3E1AB05708E62E73FB593A25".into(),
+                address: "atest1d9khqw36x9zrqd3hg4qnzvjyxsenqs33g5e52v2pggcr2des8pznvvj9xue5vs348ye5zv34t437ez".into(),
+                message: "616e6f6d61207465737420766563746f72".into(),
+                signature: "00f647f6ae07a99143902d1d5519e44a4243a1248ce7865c203eb4d07a0e5ff7f79e641a2551e85aa298ccec5ec22e5274d5745bc02bd6614cb787145ee1317602".into(),
+            },
+        ];
+        assert_eq!(test_vectors(), expected);
+    }
+
+    /// The keystore vectors must never change either, as keystores written
+    /// by this version must stay readable by other implementations and by
+    /// later versions.
+    #[test]
+    fn test_keystore_vectors_golden() {
+        let encrypted = [
+            "encrypted_v1:0103000000022100000000d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a040800000003000000080000000c04000000100000005900000001010101010101010101010101010101020202020202020202020202020202020202020202020202e0ca3abab98fbfea67f33dc21d5291f918d0fb3c2ecf54d0e221fd3059b4ebfc0fa0cc3cc3320626a97b062b9ebc336a8a",
+            "encrypted_v1:01030000000221000000008a88e3dd7409f195fd52db2d3cba5d72ca6709bf1d94121bf3748801b40f6f5c040800000003000000080000000c04000000100000005900000001010101010101010101010101010101020202020202020202020202020202020202020202020202e0565a0a256143b10648b889e8c17cd4dd95b3f846b567b8fa50c79d5ba944826e75ffe972d10739fa85a5cc8927f5dedb",
+            "encrypted_v1:01030000000221000000008139770ea87d175f56a35466c34c7ecccb8d8a91b4ee37a25df60f5b8fc9b394040800000003000000080000000c04000000100000005900000001010101010101010101010101010101020202020202020202020202020202020202020202020202e0555909266240b2054bbb8aebc27fd7de96b0fb45b664bbf953c49e58aa47816da1b48ac4219a9b73e1137a6a79ef580b",
+        ];
+        let vectors = keystore_vectors();
+        assert_eq!(
+            vectors
+                .iter()
+                .map(|vector| vector.encrypted.as_str())
+                .collect::<Vec<_>>(),
+            encrypted
+        );
+        for (vector, expected) in vectors.iter().zip(test_vectors()) {
+            let stored: StoredKeypair = vector.encrypted.parse().unwrap();
+            let sk = stored.get(true, Some(vector.password.clone())).unwrap();
+            assert_eq!(sk.ref_to().to_string(), expected.public_key);
+        }
+    }
+
+    /// A keypair encrypted by the versions of the wallet that predate the
+    /// keystore header, which must stay readable
+    #[test]
+    fn test_legacy_keystore_vector() {
+        let legacy = "encrypted:01010101010101010101010101010101020202020202020202020202020202020202020202020202d5016b000fc03504f84908d34c124c9d2bc04c3300bc51e71d14914a390fb252662bf106750364c9ce765af5f9a1dadd10";
+        let stored: StoredKeypair = legacy.parse().unwrap();
+        assert_eq!(stored.created_by_version(), None);
+        let sk = stored
+            .get(true, Some(KEYSTORE_PASSWORD.to_owned()))
+            .unwrap();
+        assert_eq!(sk.ref_to().to_string(), test_vectors()[0].public_key);
+    }
+}
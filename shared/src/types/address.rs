@@ -153,6 +153,24 @@ impl Address {
             .map_err(Error::InvalidAddressEncoding)
     }
 
+    /// Encode an address in a checksummed form for display. The Bech32m
+    /// encoding used by [`Address::encode`] already includes a 6 character
+    /// checksum (BIP-350) that detects any single character error.
+    pub fn to_checksummed_string(&self) -> String {
+        self.encode()
+    }
+
+    /// Check that the given string is Bech32m encoded with the address
+    /// human-readable part and a valid checksum, e.g. to catch transcription
+    /// errors in a user-typed address. This doesn't check that the decoded
+    /// bytes are a valid address, for that use [`Address::decode`].
+    pub fn validate_checksum(string: impl AsRef<str>) -> bool {
+        matches!(
+            bech32::decode(string.as_ref()),
+            Ok((prefix, _, ADDRESS_BECH32_VARIANT)) if prefix == ADDRESS_HRP
+        )
+    }
+
     /// Try to get a raw hash of an address, only defined for established and
     /// implicit addresses.
     pub fn raw_hash(&self) -> Option<&str> {
@@ -588,6 +606,35 @@ pub mod tests {
             assert_eq!(address, decoded);
         }
 
+        #[test]
+        /// Check that the checksummed string roundtrips and that changing any
+        /// single character of it is detected.
+        fn test_address_checksum(
+            address in testing::arb_address(),
+            position in 0..ADDRESS_LEN - ADDRESS_HRP.len() - 1,
+            replacement in 0..31_usize,
+        ) {
+            const CHARSET: &str = "qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+            let checksummed = address.to_checksummed_string();
+            assert!(Address::validate_checksum(&checksummed));
+            assert_eq!(Address::decode(&checksummed).unwrap(), address);
+
+            // Replace a character in the data part with a different one
+            let index = ADDRESS_HRP.len() + 1 + position;
+            let original = checksummed.as_bytes()[index] as char;
+            let replacement = CHARSET
+                .chars()
+                .filter(|c| *c != original)
+                .nth(replacement)
+                .unwrap();
+            let mut mistyped = checksummed.clone();
+            mistyped.replace_range(
+                index..index + 1,
+                &replacement.to_string(),
+            );
+            assert!(!Address::validate_checksum(&mistyped));
+        }
+
         #[test]
         fn test_established_address_bytes_length(address in testing::arb_established_address()) {
             let address = Address::Established(address);